@@ -0,0 +1,175 @@
+use crate::classification::classify_statement;
+use crate::classification::first_keyword;
+use crate::classification::StatementKind;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+use rusqlite::Params;
+
+// Every write executed with an apply index records it in this table, in the
+// same transaction as the write. Replaying a log entry at or below the
+// recorded index is then a no-op, so a node can reopen its files after a
+// crash and apply the whole log again.
+pub const META_TABLE: &str = "_erldb_meta";
+
+pub(crate) fn stored_index(conn: &Connection) -> rusqlite::Result<u64> {
+    // the table is created by the first stamped write
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [META_TABLE],
+            |_| Ok(()),
+        )
+        .optional()?;
+    if exists.is_none() {
+        return Ok(0);
+    }
+    let index = conn
+        .query_row(
+            &format!("SELECT value FROM {} WHERE key = 'apply_index'", META_TABLE),
+            [],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?;
+    Ok(index.unwrap_or(0) as u64)
+}
+
+fn stamp(conn: &Connection, index: u64) -> rusqlite::Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value INTEGER NOT NULL)",
+        META_TABLE
+    ))?;
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO {} (key, value) VALUES ('apply_index', ?1)",
+            META_TABLE
+        ),
+        [index as i64],
+    )?;
+    Ok(())
+}
+
+pub(crate) fn in_savepoint<T>(conn: &Connection, f: impl FnOnce() -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+    conn.execute_batch("SAVEPOINT _erldb_apply")?;
+    let rv = f().and_then(|v| conn.execute_batch("RELEASE _erldb_apply").map(|_| v));
+    if rv.is_err() {
+        let _ = conn.execute_batch("ROLLBACK TO _erldb_apply; RELEASE _erldb_apply");
+    }
+    rv
+}
+
+// run f and record index atomically with it, None if index was already applied
+pub(crate) fn stamped<T>(
+    conn: &Connection,
+    index: u64,
+    f: impl FnOnce() -> rusqlite::Result<T>,
+) -> rusqlite::Result<Option<T>> {
+    if index <= stored_index(conn)? {
+        return Ok(None);
+    }
+    in_savepoint(conn, || {
+        let rv = f()?;
+        stamp(conn, index)?;
+        Ok(rv)
+    })
+    .map(Some)
+}
+
+// for a write that is already in a transaction, it commits or rolls back with it
+pub(crate) fn stamp_pending(conn: &Connection, index: u64) -> rusqlite::Result<()> {
+    if index > stored_index(conn)? {
+        stamp(conn, index)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn execute_stamped<P: Params + Copy>(
+    conn: &Connection,
+    query: &str,
+    params: P,
+    index: u64,
+) -> rusqlite::Result<usize> {
+    let mut stmt = match conn.prepare(query) {
+        Ok(stmt) => stmt,
+        // a replayed entry may no longer prepare, e.g. a CREATE TABLE of a
+        // table that exists now
+        Err(_) if index <= stored_index(conn)? => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let classification = classify_statement(&stmt, query);
+    match classification.kind {
+        // transaction control, attach and pragmas change the connection, they
+        // are never skipped. The pragmas that change the file set a value, so
+        // running them again in log order ends with the same settings.
+        _ if classification.read_only => stmt.execute(params),
+        StatementKind::Transaction | StatementKind::Attach | StatementKind::Pragma => {
+            stmt.execute(params)
+        }
+        // VACUUM cannot run in a transaction, it is not stamped: it leaves
+        // the content as it was, so running it again after a crash only
+        // costs time
+        StatementKind::Maintenance if first_keyword(query) == "VACUUM" => {
+            if index <= stored_index(conn)? {
+                return Ok(0);
+            }
+            stmt.execute(params)
+        }
+        _ => Ok(stamped(conn, index, || stmt.execute(params))?.unwrap_or(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(conn: &Connection) -> i64 {
+        conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn writes_at_or_below_the_index_are_skipped() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(stored_index(&conn).unwrap(), 0);
+        execute_stamped(&conn, "CREATE TABLE t(x)", [], 1).unwrap();
+        assert_eq!(execute_stamped(&conn, "INSERT INTO t VALUES (1)", [], 2).unwrap(), 1);
+        assert_eq!(stored_index(&conn).unwrap(), 2);
+        // replayed entries
+        assert_eq!(execute_stamped(&conn, "INSERT INTO t VALUES (1)", [], 2).unwrap(), 0);
+        assert_eq!(execute_stamped(&conn, "INSERT INTO t VALUES (1)", [], 1).unwrap(), 0);
+        assert_eq!(execute_stamped(&conn, "CREATE TABLE t(x)", [], 1).unwrap(), 0);
+        assert_eq!(count(&conn), 1);
+        assert_eq!(execute_stamped(&conn, "INSERT INTO t VALUES (2)", [], 3).unwrap(), 1);
+        assert_eq!(count(&conn), 2);
+        assert_eq!(stored_index(&conn).unwrap(), 3);
+    }
+
+    #[test]
+    fn pragmas_run_again() {
+        let conn = Connection::open_in_memory().unwrap();
+        execute_stamped(&conn, "CREATE TABLE t(x)", [], 2).unwrap();
+        execute_stamped(&conn, "PRAGMA user_version = 7", [], 1).unwrap();
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, 7);
+        assert_eq!(stored_index(&conn).unwrap(), 2);
+    }
+
+    #[test]
+    fn failed_write_keeps_the_index() {
+        let conn = Connection::open_in_memory().unwrap();
+        execute_stamped(&conn, "CREATE TABLE t(x UNIQUE)", [], 1).unwrap();
+        execute_stamped(&conn, "INSERT INTO t VALUES (1)", [], 2).unwrap();
+        assert!(execute_stamped(&conn, "INSERT INTO t VALUES (1)", [], 3).is_err());
+        assert_eq!(stored_index(&conn).unwrap(), 2);
+    }
+
+    #[test]
+    fn stamped_in_an_open_transaction() {
+        let conn = Connection::open_in_memory().unwrap();
+        execute_stamped(&conn, "CREATE TABLE t(x)", [], 1).unwrap();
+        conn.execute_batch("BEGIN").unwrap();
+        assert_eq!(stamped(&conn, 2, || conn.execute("INSERT INTO t VALUES (1)", [])).unwrap(), Some(1));
+        conn.execute_batch("ROLLBACK").unwrap();
+        // rolled back with the write
+        assert_eq!(stored_index(&conn).unwrap(), 1);
+        assert_eq!(count(&conn), 0);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::connection::RusqliteError;
+use rusqlite::Connection;
+use std::cell::Cell;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+// the progress handler runs every STEP_GRANULARITY virtual machine
+// instructions, so a budget is enforced with this precision
+pub const STEP_GRANULARITY: u64 = 1000;
+
+#[derive(Default)]
+struct State {
+    // 0 means no budget
+    limit: AtomicU64,
+    used: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+// A budget counts virtual machine steps instead of time, replicas running
+// the same call on the same data stop at the same instruction and report the
+// same error. Like the determinism overrides, the handler is installed on the
+// first call that carries a budget.
+pub(crate) struct Budget {
+    installed: Cell<bool>,
+    state: Arc<State>,
+}
+
+impl Budget {
+    pub(crate) fn new() -> Self {
+        Budget {
+            installed: Cell::new(false),
+            state: Arc::new(State::default()),
+        }
+    }
+
+    // every call that evaluates sql sets it, None removes the limit
+    pub(crate) fn set(&self, conn: &Connection, budget: Option<u64>) {
+        if budget.is_some() && !self.installed.get() {
+            let state = Arc::clone(&self.state);
+            conn.progress_handler(
+                STEP_GRANULARITY as i32,
+                Some(move || {
+                    let limit = state.limit.load(Ordering::Relaxed);
+                    if limit == 0 {
+                        return false;
+                    }
+                    let used = state.used.fetch_add(STEP_GRANULARITY, Ordering::Relaxed);
+                    if used + STEP_GRANULARITY > limit {
+                        state.exceeded.store(true, Ordering::Relaxed);
+                        return true;
+                    }
+                    false
+                }),
+            );
+            self.installed.set(true);
+        }
+        self.state.limit.store(budget.unwrap_or(0), Ordering::Relaxed);
+        self.state.used.store(0, Ordering::Relaxed);
+        self.state.exceeded.store(false, Ordering::Relaxed);
+    }
+
+    // the handler stops the statement with SQLITE_INTERRUPT, tell it apart
+    // from an interrupt coming from outside
+    pub(crate) fn exceeded(&self) -> Option<u64> {
+        if self.state.exceeded.load(Ordering::Relaxed) {
+            Some(self.state.limit.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn error(&self, err: rusqlite::Error) -> RusqliteError {
+        match self.exceeded() {
+            Some(limit) => RusqliteError::BudgetExceeded(limit),
+            None => err.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNT: &str = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 100000) \
+                         SELECT count(*) FROM c";
+
+    fn count(conn: &Connection, budget: &Budget) -> Result<i64, RusqliteError> {
+        conn.query_row(COUNT, [], |row| row.get(0))
+            .map_err(|err| budget.error(err))
+    }
+
+    #[test]
+    fn budget_exceeded() {
+        let conn = Connection::open_in_memory().unwrap();
+        let budget = Budget::new();
+        budget.set(&conn, Some(10 * STEP_GRANULARITY));
+        let Err(RusqliteError::BudgetExceeded(limit)) = count(&conn, &budget) else {
+            panic!("the query takes more steps than the budget");
+        };
+        assert_eq!(limit, 10 * STEP_GRANULARITY);
+    }
+
+    #[test]
+    fn every_call_starts_a_new_budget() {
+        let conn = Connection::open_in_memory().unwrap();
+        let budget = Budget::new();
+        budget.set(&conn, Some(10 * STEP_GRANULARITY));
+        assert!(count(&conn, &budget).is_err());
+        budget.set(&conn, None);
+        assert_eq!(count(&conn, &budget).unwrap(), 100000);
+        assert_eq!(budget.exceeded(), None);
+    }
+
+    #[test]
+    fn same_budget_same_outcome() {
+        let steps = 50 * STEP_GRANULARITY;
+        let outcome = || {
+            let conn = Connection::open_in_memory().unwrap();
+            let budget = Budget::new();
+            budget.set(&conn, Some(steps));
+            count(&conn, &budget).map_err(|err| err.to_string())
+        };
+        assert_eq!(outcome(), outcome());
+    }
+}
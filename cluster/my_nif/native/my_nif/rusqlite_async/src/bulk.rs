@@ -0,0 +1,38 @@
+use crate::budget::Budget;
+use crate::connection::Result;
+use crate::connection::RusqliteError;
+use crate::connection::SQLiteValue;
+use rusqlite::Connection;
+
+// The query is prepared once and run with every parameter set in the same
+// savepoint, a transaction of its own when none is open. One failing set
+// rolls back the others. Returns the changes of every set.
+pub(crate) fn execute_many(
+    conn: &Connection,
+    query: &str,
+    rows: &[Box<[SQLiteValue]>],
+    apply_index: Option<u64>,
+    budget: &Budget,
+) -> Result<Vec<usize>> {
+    let mut failed = None;
+    let run = || {
+        let mut stmt = conn.prepare(query)?;
+        let mut changes = Vec::with_capacity(rows.len());
+        for (row, params) in rows.iter().enumerate() {
+            failed = Some(row);
+            changes.push(stmt.execute(rusqlite::params_from_iter(params.iter()))?);
+        }
+        failed = None;
+        Ok(changes)
+    };
+    let rv = match apply_index {
+        // already applied, nothing changed
+        Some(index) => crate::apply_index::stamped(conn, index, run)
+            .map(|changes| changes.unwrap_or_else(|| vec![0; rows.len()])),
+        None => crate::apply_index::in_savepoint(conn, run),
+    };
+    rv.map_err(|err| match (budget.exceeded(), failed) {
+        (None, Some(row)) => RusqliteError::Row(row, err),
+        _ => budget.error(err),
+    })
+}
@@ -0,0 +1,86 @@
+use rusqlite::Statement;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatementKind {
+    Query,
+    Dml,
+    Ddl,
+    Transaction,
+    Pragma,
+    Attach,
+    Maintenance,
+    Explain,
+    Other,
+}
+
+impl StatementKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatementKind::Query => "query",
+            StatementKind::Dml => "dml",
+            StatementKind::Ddl => "ddl",
+            StatementKind::Transaction => "transaction",
+            StatementKind::Pragma => "pragma",
+            StatementKind::Attach => "attach",
+            StatementKind::Maintenance => "maintenance",
+            StatementKind::Explain => "explain",
+            StatementKind::Other => "other",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Classification {
+    pub kind: StatementKind,
+    // as reported by sqlite3_stmt_readonly, note that transaction control
+    // statements are read only even if they change the connection state
+    pub read_only: bool,
+}
+
+impl Classification {
+    // can be served by a single replica without changing any state
+    pub fn is_stateless_read(&self) -> bool {
+        self.read_only && matches!(self.kind, StatementKind::Query | StatementKind::Explain)
+    }
+}
+
+// skip whitespaces and comments, then return the first keyword
+pub(crate) fn first_keyword(sql: &str) -> String {
+    let mut rest = sql;
+    loop {
+        rest = rest.trim_start();
+        if let Some(tail) = rest.strip_prefix("--") {
+            rest = tail.split_once('\n').map_or("", |(_, tail)| tail);
+        } else if let Some(tail) = rest.strip_prefix("/*") {
+            rest = tail.split_once("*/").map_or("", |(_, tail)| tail);
+        } else {
+            break;
+        }
+    }
+    rest.chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect::<String>()
+        .to_ascii_uppercase()
+}
+
+pub(crate) fn classify_statement(stmt: &Statement<'_>, sql: &str) -> Classification {
+    let read_only = stmt.readonly();
+    let kind = if stmt.is_explain() > 0 {
+        StatementKind::Explain
+    } else {
+        match first_keyword(sql).as_str() {
+            "SELECT" | "VALUES" => StatementKind::Query,
+            "WITH" if read_only => StatementKind::Query,
+            "WITH" | "INSERT" | "UPDATE" | "DELETE" | "REPLACE" => StatementKind::Dml,
+            "CREATE" | "DROP" | "ALTER" => StatementKind::Ddl,
+            "BEGIN" | "COMMIT" | "END" | "ROLLBACK" | "SAVEPOINT" | "RELEASE" => {
+                StatementKind::Transaction
+            }
+            "PRAGMA" => StatementKind::Pragma,
+            "ATTACH" | "DETACH" => StatementKind::Attach,
+            "VACUUM" | "ANALYZE" | "REINDEX" => StatementKind::Maintenance,
+            _ => StatementKind::Other,
+        }
+    };
+    Classification { kind, read_only }
+}
@@ -0,0 +1,210 @@
+use crate::connection::Result;
+use rusqlite::Connection;
+use rusqlite::Statement;
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+pub struct ColumnMetadata {
+    pub name: Box<str>,
+    // as written in the create table, None for an expression
+    pub decl_type: Option<Box<str>>,
+    // where the value comes from, None for an expression or without the
+    // column_metadata feature
+    pub database: Option<Box<str>>,
+    pub table: Option<Box<str>>,
+    pub column: Option<Box<str>>,
+    // None when the column has no origin
+    pub not_null: Option<bool>,
+    pub primary_key: Option<bool>,
+    pub autoincrement: Option<bool>,
+}
+
+#[derive(Default)]
+struct Origin {
+    database: Option<Box<str>>,
+    table: Option<Box<str>>,
+    column: Option<Box<str>>,
+    // not null, primary key, autoincrement
+    flags: Option<(bool, bool, bool)>,
+}
+
+#[cfg(feature = "column_metadata")]
+mod sqlite {
+    use super::Origin;
+    use crate::connection::Result;
+    use rusqlite::ffi;
+    use rusqlite::Connection;
+    use std::ffi::CStr;
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+    use std::ptr;
+
+    struct Finalize(*mut ffi::sqlite3_stmt);
+
+    impl Drop for Finalize {
+        fn drop(&mut self) {
+            unsafe {
+                ffi::sqlite3_finalize(self.0);
+            }
+        }
+    }
+
+    unsafe fn text(s: *const c_char) -> Option<Box<str>> {
+        if s.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(s).to_string_lossy().into())
+        }
+    }
+
+    fn error(db: *mut ffi::sqlite3, code: i32) -> rusqlite::Error {
+        let message = unsafe { text(ffi::sqlite3_errmsg(db)) };
+        rusqlite::Error::SqliteFailure(ffi::Error::new(code), message.map(String::from))
+    }
+
+    // (not null, primary key, autoincrement) of a column of a table
+    unsafe fn flags(
+        db: *mut ffi::sqlite3,
+        database: &str,
+        table: &str,
+        column: &str,
+    ) -> Result<(bool, bool, bool)> {
+        let database = CString::new(database).map_err(rusqlite::Error::from)?;
+        let table = CString::new(table).map_err(rusqlite::Error::from)?;
+        let column = CString::new(column).map_err(rusqlite::Error::from)?;
+        let (mut not_null, mut primary_key, mut autoincrement) = (0, 0, 0);
+        let rc = ffi::sqlite3_table_column_metadata(
+            db,
+            database.as_ptr(),
+            table.as_ptr(),
+            column.as_ptr(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut not_null,
+            &mut primary_key,
+            &mut autoincrement,
+        );
+        if rc != ffi::SQLITE_OK {
+            return Err(error(db, rc).into());
+        }
+        Ok((not_null != 0, primary_key != 0, autoincrement != 0))
+    }
+
+    // The query is prepared once more on its own, rusqlite does not hand out the
+    // sqlite3_stmt of a statement. The origin functions are only in a sqlite
+    // built with SQLITE_ENABLE_COLUMN_METADATA.
+    pub(super) fn origins(conn: &Connection, query: &str) -> Result<Vec<Origin>> {
+        let sql = CString::new(query).map_err(rusqlite::Error::from)?;
+        unsafe {
+            let db = conn.handle();
+            let mut stmt = ptr::null_mut();
+            let rc = ffi::sqlite3_prepare_v2(db, sql.as_ptr(), -1, &mut stmt, ptr::null_mut());
+            if rc != ffi::SQLITE_OK {
+                return Err(error(db, rc).into());
+            }
+            let stmt = Finalize(stmt);
+            let mut origins = Vec::new();
+            for i in 0..ffi::sqlite3_column_count(stmt.0) {
+                let database = text(ffi::sqlite3_column_database_name(stmt.0, i));
+                let table = text(ffi::sqlite3_column_table_name(stmt.0, i));
+                let column = text(ffi::sqlite3_column_origin_name(stmt.0, i));
+                let flags = match (&database, &table, &column) {
+                    (Some(database), Some(table), Some(column)) => {
+                        Some(flags(db, database, table, column)?)
+                    }
+                    _ => None,
+                };
+                origins.push(Origin {
+                    database,
+                    table,
+                    column,
+                    flags,
+                });
+            }
+            Ok(origins)
+        }
+    }
+}
+
+#[cfg(feature = "column_metadata")]
+use sqlite::origins;
+
+// without the feature only the name and the declared type are known
+#[cfg(not(feature = "column_metadata"))]
+fn origins(_conn: &Connection, _query: &str) -> Result<Vec<Origin>> {
+    Ok(Vec::new())
+}
+
+// once when the statement is prepared
+pub(crate) fn column_metadata(
+    conn: &Connection,
+    stmt: &Statement<'_>,
+    query: &str,
+) -> Result<Arc<[ColumnMetadata]>> {
+    let mut origins = origins(conn, query)?.into_iter();
+    let columns = stmt
+        .columns()
+        .into_iter()
+        .map(|column| {
+            let origin = origins.next().unwrap_or_default();
+            ColumnMetadata {
+                name: column.name().into(),
+                decl_type: column.decl_type().map(Box::from),
+                database: origin.database,
+                table: origin.table,
+                column: origin.column,
+                not_null: origin.flags.map(|(not_null, _, _)| not_null),
+                primary_key: origin.flags.map(|(_, primary_key, _)| primary_key),
+                autoincrement: origin.flags.map(|(_, _, autoincrement)| autoincrement),
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(columns.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(query: &str) -> Arc<[ColumnMetadata]> {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL)")
+            .unwrap();
+        let stmt = conn.prepare(query).unwrap();
+        column_metadata(&conn, &stmt, query).unwrap()
+    }
+
+    #[test]
+    fn declared_types() {
+        let columns = metadata("SELECT id, name AS label, 1 + 1 FROM t");
+        let names = columns.iter().map(|column| &*column.name).collect::<Vec<_>>();
+        assert_eq!(names, ["id", "label", "1 + 1"]);
+        assert_eq!(columns[0].decl_type.as_deref(), Some("INTEGER"));
+        assert_eq!(columns[1].decl_type.as_deref(), Some("TEXT"));
+        assert_eq!(columns[2].decl_type, None);
+        assert_eq!(columns[2].table, None);
+        assert_eq!(columns[2].not_null, None);
+    }
+
+    #[cfg(not(feature = "column_metadata"))]
+    #[test]
+    fn no_origin_without_the_feature() {
+        let columns = metadata("SELECT id, name FROM t");
+        for column in columns.iter() {
+            assert!(column.database.is_none() && column.table.is_none() && column.column.is_none());
+            assert_eq!(column.primary_key, None);
+        }
+    }
+
+    #[cfg(feature = "column_metadata")]
+    #[test]
+    fn origins() {
+        let columns = metadata("SELECT id, name AS label FROM t");
+        assert_eq!(columns[1].database.as_deref(), Some("main"));
+        assert_eq!(columns[1].table.as_deref(), Some("t"));
+        assert_eq!(columns[1].column.as_deref(), Some("name"));
+        assert_eq!(columns[0].primary_key, Some(true));
+        assert_eq!(columns[0].autoincrement, Some(true));
+        assert_eq!(columns[1].not_null, Some(true));
+    }
+}
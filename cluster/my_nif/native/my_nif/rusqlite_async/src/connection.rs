@@ -1,21 +1,53 @@
 use std::borrow::Cow;
+use std::cell::RefCell;
 use async_channel::{unbounded, Receiver, Sender};
+pub use crate::classification::Classification;
+pub use crate::classification::StatementKind;
+pub use crate::column_metadata::ColumnMetadata;
+pub use crate::crash::Health;
+pub use crate::determinism::Determinism;
+pub use crate::digest::FileDigest;
+pub use crate::digest::StateDigest;
+pub use crate::digest::TableDigest;
+pub use crate::recovery::FileReport;
+pub use crate::recovery::IntegrityCheck;
+pub use crate::recovery::Inventory;
+pub use crate::recovery::OpenOptions;
+pub use crate::registry::ConnectionInfo;
+pub use crate::registry::StatementInfo;
+pub use crate::transaction::TransactionMode;
+pub use crate::transaction::TransactionOp;
+use crate::budget::Budget;
+use crate::crash::contain;
+use crate::crash::contain_task;
+use crate::deadline::recv_answer;
+use crate::determinism::Overrides;
+use crate::registry::Registry;
+use crate::registry::Tracker;
 use base64::Engine as _;
 use rusqlite::Connection;
+use rusqlite::DatabaseName;
+use rusqlite::InterruptHandle;
+use rusqlite::OpenFlags;
+use rusqlite::Params;
 use rusqlite::Statement;
 use rusqlite::ToSql;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fmt::Display;
-use std::fmt::Formatter;
-use std::fs::DirEntry;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::ops::FromResidual;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 use tokio::task;
 use tokio::task::LocalSet;
 use uuid::Uuid;
@@ -58,18 +90,55 @@ pub type Result<T> = std::result::Result<T, RusqliteError>;
 
 pub enum RusqliteError {
     RusqliteError(rusqlite::Error),
+    // binding the parameter at the index failed
+    Bind(usize, rusqlite::Error),
+    // index and byte offset of the statement of a script that failed
+    Script(usize, usize, rusqlite::Error),
+    // index of the parameter set of an execute_many that failed
+    Row(usize, rusqlite::Error),
     CommunicationError(String),
     IoError(std::io::Error),
     CustomError(String),
+    // the call was stopped by interrupt or cancel
+    Interrupted,
+    // the call ran more virtual machine steps than its budget
+    BudgetExceeded(u64),
+    // the deadline of the call passed, the work was interrupted
+    Timeout,
+    // the statement or the connection was idle for too long and was closed
+    Expired,
+    // the transaction outlived its lease, it was rolled back and the
+    // connection closed
+    LeaseExpired,
+    // the worker panicked, with the panic message
+    WorkerCrashed(String),
 }
 
 impl Display for RusqliteError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RusqliteError::RusqliteError(e) => std::fmt::Debug::fmt(e, f)?,
+            RusqliteError::Bind(n, e) => {
+                write!(f, "parameter {}: ", n)?;
+                std::fmt::Debug::fmt(e, f)?
+            }
+            RusqliteError::Script(index, offset, e) => {
+                write!(f, "statement {} at {}: ", index, offset)?;
+                std::fmt::Debug::fmt(e, f)?
+            }
+            RusqliteError::Row(row, e) => {
+                write!(f, "row {}: ", row)?;
+                std::fmt::Debug::fmt(e, f)?
+            }
             RusqliteError::CommunicationError(s) => std::fmt::Debug::fmt(s, f)?,
             RusqliteError::CustomError(s) => std::fmt::Debug::fmt(s, f)?,
             RusqliteError::IoError(e) => std::fmt::Debug::fmt(e, f)?,
+            RusqliteError::Interrupted => f.write_str("interrupted")?,
+            RusqliteError::BudgetExceeded(limit) => write!(f, "budget of {} steps exceeded", limit)?,
+            RusqliteError::Timeout => f.write_str("timeout")?,
+            RusqliteError::Expired => f.write_str("expired")?,
+            RusqliteError::LeaseExpired => f.write_str("transaction lease expired, rolled back")?,
+            RusqliteError::WorkerCrashed(message) => write!(f, "worker crashed: {}", message)?,
         }
         Ok(())
     }
@@ -77,26 +146,38 @@ impl Display for RusqliteError {
 
 impl Debug for RusqliteError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            RusqliteError::RusqliteError(e) => std::fmt::Debug::fmt(e, f)?,
-            RusqliteError::CommunicationError(s) => std::fmt::Debug::fmt(s, f)?,
-            RusqliteError::CustomError(s) => std::fmt::Debug::fmt(s, f)?,
-            RusqliteError::IoError(e) => std::fmt::Debug::fmt(e, f)?,
-        }
-        Ok(())
+        Display::fmt(self, f)
     }
 }
 
 impl From<rusqlite::Error> for RusqliteError {
     fn from(e: rusqlite::Error) -> Self {
-        RusqliteError::RusqliteError(e)
+        match e {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ffi::ErrorCode::OperationInterrupted,
+                    ..
+                },
+                _,
+            ) => RusqliteError::Interrupted,
+            e => RusqliteError::RusqliteError(e),
+        }
     }
 }
 
 impl From<RusqliteError> for rusqlite::Error {
     fn from(e: RusqliteError) -> Self {
         match e {
-            RusqliteError::RusqliteError(e) => e,
+            RusqliteError::RusqliteError(e)
+            | RusqliteError::Bind(_, e)
+            | RusqliteError::Script(_, _, e)
+            | RusqliteError::Row(_, e) => e,
+            RusqliteError::Interrupted | RusqliteError::BudgetExceeded(_) => {
+                rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_INTERRUPT),
+                    None,
+                )
+            }
             _ => rusqlite::Error::SqliteFailure(
                 rusqlite::ffi::Error {
                     code: rusqlite::ffi::ErrorCode::Unknown,
@@ -110,10 +191,7 @@ impl From<RusqliteError> for rusqlite::Error {
 
 impl<T> From<async_channel::SendError<T>> for RusqliteError {
     fn from(e: async_channel::SendError<T>) -> Self {
-        let mut s = String::new();
-        let mut fmt = Formatter::new(&mut s);
-        std::fmt::Debug::fmt(&e, &mut fmt).unwrap();
-        RusqliteError::CommunicationError(s)
+        RusqliteError::CommunicationError(format!("{:?}", e))
     }
 }
 
@@ -131,122 +209,476 @@ impl FromResidual<std::io::Error> for RusqliteError {
 
 impl<T> FromResidual<async_channel::SendError<T>> for RusqliteError {
     fn from_residual(residual: async_channel::SendError<T>) -> Self {
-        let mut s = String::new();
-        let mut fmt = Formatter::new(&mut s);
-        std::fmt::Debug::fmt(&residual, &mut fmt).unwrap();
-        RusqliteError::CommunicationError(s)
+        RusqliteError::CommunicationError(format!("{:?}", residual))
     }
 }
 
 impl From<async_channel::RecvError> for RusqliteError {
     fn from(e: async_channel::RecvError) -> Self {
-        let mut s = String::new();
-        let mut fmt = Formatter::new(&mut s);
-        std::fmt::Debug::fmt(&e, &mut fmt).unwrap();
-        RusqliteError::CommunicationError(s)
+        RusqliteError::CommunicationError(format!("{:?}", e))
     }
 }
 
 impl FromResidual<async_channel::RecvError> for RusqliteError {
     fn from_residual(residual: async_channel::RecvError) -> Self {
-        let mut s = String::new();
-        let mut fmt = Formatter::new(&mut s);
-        std::fmt::Debug::fmt(&residual, &mut fmt).unwrap();
-        RusqliteError::CommunicationError(s)
+        RusqliteError::CommunicationError(format!("{:?}", residual))
+    }
+}
+
+// the answer to a request
+pub type Answer<T> = tokio::sync::oneshot::Receiver<Result<T>>;
+
+// where the answer to a request goes, the caller may have stopped waiting
+pub struct Reply<T> {
+    sink: Option<Sink<T>>,
+    // the request of a connection worker, its id and the one it interrupted
+    running: Option<(Arc<Running>, u64, u64)>,
+}
+
+enum Sink<T> {
+    Channel(tokio::sync::oneshot::Sender<Result<T>>),
+    // called on the worker thread, e.g. to send the answer to an erlang process
+    Callback(Box<dyn FnOnce(Result<T>) + Send>),
+}
+
+impl<T> Sink<T> {
+    fn send(self, rv: Result<T>) {
+        match self {
+            Sink::Channel(sender) => {
+                let _ = sender.send(rv);
+            }
+            Sink::Callback(f) => f(rv),
+        }
+    }
+}
+
+impl<T> Reply<T> {
+    fn new(sink: Sink<T>) -> Self {
+        Reply {
+            sink: Some(sink),
+            running: None,
+        }
+    }
+
+    pub fn send(mut self, output: T) {
+        self.finish();
+        if let Some(sink) = self.sink.take() {
+            sink.send(Ok(output));
+        }
+    }
+
+    fn fail(mut self, err: RusqliteError) {
+        self.finish();
+        if let Some(sink) = self.sink.take() {
+            sink.send(Err(err));
+        }
+    }
+
+    // the caller may interrupt the connection only until it is answered
+    fn finish(&mut self) {
+        if let Some((running, id, previous)) = self.running.take() {
+            running.stop(id, previous);
+        }
+    }
+}
+
+// a worker that panics while handling a request still answers it, a callback
+// is always called
+impl<T> Drop for Reply<T> {
+    fn drop(&mut self) {
+        self.finish();
+        let Some(sink) = self.sink.take() else {
+            return;
+        };
+        if std::thread::panicking() {
+            let message = "panicked while handling the call".to_owned();
+            sink.send(Err(RusqliteError::WorkerCrashed(message)));
+        } else if let Sink::Callback(_) = sink {
+            sink.send(Err(RusqliteError::CommunicationError("channel closed".to_owned())));
+        }
+    }
+}
+
+// 0 is no request
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(1);
+
+// Every input carries the reply for its output, so an answer cannot be taken
+// by another call, e.g. one that timed out or runs on another process.
+pub struct Request<I, O> {
+    input: I,
+    reply: Reply<O>,
+    // the caller stops waiting then, a worker that did not start the request
+    // yet drops it
+    deadline: Option<Instant>,
+    id: u64,
+}
+
+impl<I, O> Request<I, O> {
+    pub fn new(input: I) -> (Self, Answer<O>) {
+        Self::with_deadline(input, None)
+    }
+
+    pub fn with_deadline(input: I, deadline: Option<Instant>) -> (Self, Answer<O>) {
+        let (sender, answer) = tokio::sync::oneshot::channel();
+        let request = Request {
+            input,
+            reply: Reply::new(Sink::Channel(sender)),
+            deadline,
+            id: NEXT_REQUEST.fetch_add(1, Ordering::Relaxed),
+        };
+        (request, answer)
+    }
+
+    pub fn with_callback(input: I, f: impl FnOnce(Result<O>) + Send + 'static) -> Self {
+        Request {
+            input,
+            reply: Reply::new(Sink::Callback(Box::new(f))),
+            deadline: None,
+            id: NEXT_REQUEST.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    // None when the deadline passed before the worker got to the request, the
+    // caller has Timeout already and nothing runs. A request of a connection
+    // is its running one until it is answered.
+    fn start(self, running: Option<&Arc<Running>>) -> Option<(I, Reply<O>)> {
+        let Request {
+            input,
+            mut reply,
+            deadline,
+            id,
+        } = self;
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            reply.fail(RusqliteError::Timeout);
+            return None;
+        }
+        if let Some(running) = running {
+            let previous = running.start(id);
+            reply.running = Some((Arc::clone(running), id, previous));
+        }
+        Some((input, reply))
+    }
+}
+
+// The request a connection worker is handling. A caller that timed out
+// interrupts the connection only while its own request runs, not one that
+// was queued after it. A request that waits on another connection, e.g. for
+// its lease, gets the connection back when the other one is answered.
+#[derive(Debug, Default)]
+pub(crate) struct Running(Mutex<u64>);
+
+impl Running {
+    fn start(&self, id: u64) -> u64 {
+        std::mem::replace(&mut *self.0.lock().unwrap(), id)
+    }
+
+    fn stop(&self, id: u64, previous: u64) {
+        let mut current = self.0.lock().unwrap();
+        if *current == id {
+            *current = previous;
+        }
+    }
+
+    // the lock is held so that the request cannot be answered meanwhile
+    fn interrupt(&self, id: u64, handle: &InterruptHandle) {
+        let current = self.0.lock().unwrap();
+        if *current == id {
+            handle.interrupt();
+        }
     }
 }
 
+pub type ContextRequest = Request<ContextInput, ContextOutput>;
+pub type ConnectionRequest = Request<ConnectionInput, ConnectionOutput>;
+pub type StmtRequest = Request<StmtInput, StmtOutput>;
+
 pub enum ContextInput {
-    Create(Box<str>),
+    // path, what the registry shows about the connection
+    Create(Box<str>, ConnectionInfo),
     Close,
 }
 
 pub enum ContextOutput {
-    Create(Sender<ConnectionInput>, Receiver<ConnectionOutput>),
+    Create(Sender<ConnectionRequest>, Arc<ConnectionState>),
     Done,
     Error(RusqliteError),
 }
 
+// the values of the parameters by position or by name, a name keeps its
+// prefix, e.g. ":id"
+#[derive(Debug)]
+pub enum Parameters {
+    Positional(Box<[SQLiteValue]>),
+    Named(Box<[(Box<str>, SQLiteValue)]>),
+}
+
+// how a call that may come from the log runs: the values of the
+// nondeterministic functions, the index of its entry and the steps it may take
+#[derive(Debug, Default)]
+pub struct CallOptions {
+    pub determinism: Option<Determinism>,
+    pub apply_index: Option<u64>,
+    pub budget: Option<u64>,
+}
+
 pub enum ConnectionInput {
-    Execute(Box<str>, Box<[SQLiteValue]>),
-    Prepare(Box<str>),
+    Execute(Box<str>, Parameters, CallOptions),
+    // query, default budget of the steps
+    Prepare(Box<str>, Option<u64>),
     LastInsertRowId,
     Changes,
     BusyTimeout(Duration),
+    Classify(Box<str>),
+    ExecuteScript(Box<str>, CallOptions),
+    // query, parameter sets
+    ExecuteMany(Box<str>, Box<[Box<[SQLiteValue]>]>, CallOptions),
+    // op, apply index
+    Transaction(TransactionOp, Option<u64>),
+    InTransaction,
+    // roll back, finalize every statement and close
+    Release,
+    // release when the lease of the transaction ran out by the apply index
+    Lease(u64),
     Close,
 }
 
+impl ConnectionInput {
+    fn apply_index(&self) -> Option<u64> {
+        match self {
+            ConnectionInput::Execute(_, _, options)
+            | ConnectionInput::ExecuteMany(_, _, options)
+            | ConnectionInput::ExecuteScript(_, options) => options.apply_index,
+            ConnectionInput::Transaction(_, index) => *index,
+            _ => None,
+        }
+    }
+}
+
 pub enum ConnectionOutput {
-    Prepare(Result<(Sender<StmtInput>, Receiver<StmtOutput>)>),
+    Prepare(Result<(Sender<StmtRequest>, Arc<StatementState>)>),
     Execute(Result<usize>),
+    ExecuteScript(Result<Vec<u64>>),
+    ExecuteMany(Result<Vec<usize>>),
     Changes(Result<u64>),
     BusyTimeout(Result<()>),
     LastInsertRowid(i64),
+    Classify(Result<Classification>),
+    Transaction(Result<()>),
+    InTransaction(bool),
+    Release(Result<()>),
     Done,
-    Error(RusqliteError),
 }
 
 #[derive(Debug)]
 pub enum StmtInput {
-    StepBy(usize),
+    StepBy(usize, CallOptions),
     ClearBindings,
     CloneAndReset,
     Bind(usize, SQLiteValue),
+    BindNamed(Box<str>, SQLiteValue),
+    BindAll(Parameters),
     BindParameterCount,
+    BindParameterName(usize),
+    BindParameterIndex(Box<str>),
     ColumnNames,
     ColumnName(usize),
     ColumnCount,
+    ColumnMetadata,
     Close,
 }
 
 pub enum StmtOutput {
     Rows(Result<Vec<Vec<SQLiteValue>>>),
     ClearBindings(Result<bool>),
-    CloneAndReset(Result<(Sender<StmtInput>, Receiver<StmtOutput>)>),
+    CloneAndReset(Result<(Sender<StmtRequest>, Arc<StatementState>)>),
     Bind(Result<()>),
     BindParameterCount(Result<usize>),
+    BindParameterName(Result<Option<Box<str>>>),
+    BindParameterIndex(Result<Option<usize>>),
     ColumnNames(Result<Arc<[Box<str>]>>),
     ColumnName(Result<Box<str>>),
     ColumnCount(Result<usize>),
+    ColumnMetadata(Result<Arc<[ColumnMetadata]>>),
     Done,
+    // the statement could not be prepared
     Error(RusqliteError),
 }
 
+type ContextWorker = std::thread::JoinHandle<Result<()>>;
+
 #[derive(Debug)]
 pub struct Context {
     home: PathBuf,
-    sender: Sender<ContextInput>,
-    receiver: Receiver<ContextOutput>,
+    // replaced by restart_context
+    sender: Mutex<Sender<ContextRequest>>,
     uuid: Uuid,
-    join_handle: Option<std::thread::JoinHandle<Result<()>>>,
+    join_handle: Mutex<Option<ContextWorker>>,
+    // used by the calls without a deadline
+    default_timeout: Mutex<Option<Duration>>,
+    registry: Arc<Registry>,
+}
+
+fn stop_context_worker(sender: &Sender<ContextRequest>, join_handle: Option<ContextWorker>) {
+    let (request, _) = Request::new(ContextInput::Close);
+    let _ = sender.send_blocking(request);
+    let Some(join_handle) = join_handle else {
+        return;
+    };
+    let _ = join_handle.join();
 }
 
 impl Drop for Context {
     fn drop(&mut self) {
-        let _ = self.sender.send_blocking(ContextInput::Close);
-        let join_handle = self.join_handle.take();
-        let Some(join_handle) = join_handle else {
-            return;
+        let join_handle = self.join_handle.get_mut().unwrap().take();
+        stop_context_worker(self.sender.get_mut().unwrap(), join_handle);
+    }
+}
+
+type OnClose = Box<dyn FnOnce() + Send>;
+
+// run once the task of a connection or a statement is over, at once for the
+// ones registered after that
+#[derive(Default)]
+struct Closed(Mutex<(bool, Vec<OnClose>)>);
+
+impl Closed {
+    fn push(&self, then: OnClose) {
+        let mut closed = self.0.lock().unwrap();
+        if closed.0 {
+            drop(closed);
+            then();
+        } else {
+            closed.1.push(then);
+        }
+    }
+
+    fn close(&self) {
+        let pending = {
+            let mut closed = self.0.lock().unwrap();
+            closed.0 = true;
+            std::mem::take(&mut closed.1)
         };
-        let _ = join_handle.join();
+        for then in pending {
+            then();
+        }
+    }
+}
+
+impl Debug for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Closed").field(&self.0.lock().unwrap().0).finish()
+    }
+}
+
+// shared by a connection worker and the handle
+pub struct ConnectionState {
+    // sqlite3_interrupt is safe to call from any thread
+    interrupt: InterruptHandle,
+    running: Arc<Running>,
+    // closed after being idle
+    expired: AtomicBool,
+    // rolled back and closed when the transaction lease ran out
+    aborted: AtomicBool,
+    // the panic message, the connection task is gone
+    crashed: Mutex<Option<String>>,
+    closed: Closed,
+}
+
+impl ConnectionState {
+    // why a call cannot get an answer from the worker, None if it can
+    fn gone(&self) -> Option<RusqliteError> {
+        if self.expired.load(Ordering::SeqCst) {
+            Some(RusqliteError::Expired)
+        } else if self.aborted.load(Ordering::SeqCst) {
+            Some(RusqliteError::LeaseExpired)
+        } else {
+            self.crashed.lock().unwrap().clone().map(RusqliteError::WorkerCrashed)
+        }
+    }
+}
+
+impl Debug for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionState")
+            .field("running", &self.running)
+            .field("expired", &self.expired)
+            .field("aborted", &self.aborted)
+            .field("crashed", &self.crashed)
+            .field("closed", &self.closed)
+            .finish()
     }
 }
 
+// shared by a statement task and the handle
+#[derive(Debug, Default)]
+pub struct StatementState {
+    // set by the worker while the statement is being stepped
+    running: AtomicBool,
+    // finalized after being idle
+    expired: AtomicBool,
+    // the panic message, the statement task is gone
+    crashed: Mutex<Option<String>>,
+    closed: Closed,
+}
+
+impl StatementState {
+    // the statements are finalized with their connection
+    fn gone(&self, conn: &ConnectionState) -> Option<RusqliteError> {
+        if self.expired.load(Ordering::SeqCst) {
+            return Some(RusqliteError::Expired);
+        }
+        match conn.gone() {
+            Some(RusqliteError::WorkerCrashed(_)) | None => {
+                self.crashed.lock().unwrap().clone().map(RusqliteError::WorkerCrashed)
+            }
+            err => err,
+        }
+    }
+}
+
+// the handles are shared by the processes holding the resource, every call
+// waits on its own answer
 #[derive(Debug)]
 pub struct VirtualConnection {
-    sender: Sender<ConnectionInput>,
-    receiver: Receiver<ConnectionOutput>,
+    sender: Sender<ConnectionRequest>,
     // keep track of the uuid to the context to check consistency,
     uuid: Uuid,
     context: Uuid,
+    state: Arc<ConnectionState>,
+    // false for a weak handle
+    owned: bool,
+}
+
+// a handle dropped without close, e.g. a nif resource collected by the
+// garbage collector, stops its task without waiting for the answer
+impl Drop for VirtualConnection {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        let (request, _) = Request::new(ConnectionInput::Close);
+        let _ = self.sender.try_send(request);
+    }
 }
 
 #[derive(Debug)]
 pub struct VirtualStatement {
-    sender: Sender<StmtInput>,
-    receiver: Receiver<StmtOutput>,
+    sender: Sender<StmtRequest>,
     connection: Uuid,
     context: Uuid,
+    state: Arc<StatementState>,
+    // false for a weak handle
+    owned: bool,
+}
+
+impl Drop for VirtualStatement {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        let (request, _) = Request::new(StmtInput::Close);
+        let _ = self.sender.try_send(request);
+    }
 }
 
 fn row_to_vec(ncols: usize, row: &rusqlite::Row) -> Vec<SQLiteValue> {
@@ -269,200 +701,359 @@ fn row_to_vec(ncols: usize, row: &rusqlite::Row) -> Vec<SQLiteValue> {
     vec
 }
 
-async fn handle_bind_parameter_count(
-    sender: &Sender<StmtOutput>,
-    statement_meta: &StatementMeta,
-) -> Result<()> {
-    sender
-        .send(StmtOutput::BindParameterCount(Ok(
-            statement_meta.parameter_count
-        )))
-        .await?;
-    Ok(())
+fn handle_bind_parameter_count(reply: Reply<StmtOutput>, statement_meta: &StatementMeta) {
+    reply.send(StmtOutput::BindParameterCount(Ok(
+        statement_meta.parameter_count,
+    )));
 }
 
-async fn handle_column_count(
-    sender: &Sender<StmtOutput>,
-    statement_meta: &StatementMeta,
-) -> Result<()> {
-    sender
-        .send(StmtOutput::ColumnCount(Ok(statement_meta
-            .column_names
-            .len())))
-        .await?;
-    Ok(())
+fn parameter_index(parameter_names: &[Option<Box<str>>], name: &str) -> Option<usize> {
+    parameter_names
+        .iter()
+        .position(|parameter| parameter.as_deref() == Some(name))
+        .map(|i| i + 1)
 }
 
-async fn handle_clone_and_reset(
-    sender: &Sender<StmtOutput>,
-    statement_meta: &StatementMeta,
-) -> Result<()> {
-    let (stmt_sender, other_thread_receiver) = unbounded();
-    let (other_thread_sender, stmt_receiver) = unbounded();
-    let connection = Rc::clone(&statement_meta.connection);
-    let query = Rc::clone(&statement_meta.query);
-    let parameters_to_bind = Some(statement_meta.bound_values.clone());
-    task::spawn_local(async move {
-        statement(
-            stmt_sender,
-            stmt_receiver,
-            connection,
-            query,
-            parameters_to_bind,
-        )
-        .await
-    });
-    sender
-        .send(StmtOutput::CloneAndReset(Ok((
-            other_thread_sender,
-            other_thread_receiver,
-        ))))
-        .await?;
-    Ok(())
+fn handle_bind_parameter_name(reply: Reply<StmtOutput>, n: usize, statement_meta: &StatementMeta) {
+    let name = n
+        .checked_sub(1)
+        .and_then(|i| statement_meta.parameter_names.get(i))
+        .cloned()
+        .flatten();
+    reply.send(StmtOutput::BindParameterName(Ok(name)));
 }
 
-async fn handle_column_name(
-    sender: &Sender<StmtOutput>,
-    index: usize,
-    statement_meta: &StatementMeta,
-) -> Result<()> {
+fn handle_bind_parameter_index(reply: Reply<StmtOutput>, name: &str, statement_meta: &StatementMeta) {
+    reply.send(StmtOutput::BindParameterIndex(Ok(parameter_index(
+        &statement_meta.parameter_names,
+        name,
+    ))));
+}
+
+fn handle_column_count(reply: Reply<StmtOutput>, statement_meta: &StatementMeta) {
+    reply.send(StmtOutput::ColumnCount(Ok(statement_meta.column_names.len())));
+}
+
+fn handle_clone_and_reset(reply: Reply<StmtOutput>, statement_meta: &StatementMeta) {
+    let seed = StatementSeed {
+        connection: Rc::clone(&statement_meta.connection),
+        overrides: Rc::clone(&statement_meta.overrides),
+        budget: Rc::clone(&statement_meta.budget),
+        statements: Rc::clone(&statement_meta.statements),
+        query: Rc::clone(&statement_meta.query),
+        parameters_to_bind: Some(statement_meta.bound_values.clone()),
+        default_budget: statement_meta.default_budget,
+        running: Arc::clone(&statement_meta.running),
+        tracker: statement_meta.tracker.clone(),
+    };
+    reply.send(StmtOutput::CloneAndReset(Ok(spawn_statement(seed))));
+}
+
+fn handle_column_name(reply: Reply<StmtOutput>, index: usize, statement_meta: &StatementMeta) {
     let col = statement_meta.column_names.get(index);
 
     if let Some(col) = col {
-        sender
-            .send(StmtOutput::ColumnName(Ok(col.to_owned())))
-            .await?;
+        reply.send(StmtOutput::ColumnName(Ok(col.to_owned())));
     } else {
-        sender
-            .send(StmtOutput::ColumnName(Ok(Default::default())))
-            .await?;
+        reply.send(StmtOutput::ColumnName(Ok(Default::default())));
     }
-    Ok(())
 }
 
-async fn handle_column_names(
-    sender: &Sender<StmtOutput>,
-    statement_meta: &StatementMeta,
-) -> Result<()> {
-    sender
-        .send(StmtOutput::ColumnNames(Ok(Arc::clone(
-            &statement_meta.column_names,
-        ))))
-        .await?;
-    Ok(())
+fn handle_column_names(reply: Reply<StmtOutput>, statement_meta: &StatementMeta) {
+    reply.send(StmtOutput::ColumnNames(Ok(Arc::clone(
+        &statement_meta.column_names,
+    ))));
+}
+
+fn handle_column_metadata(reply: Reply<StmtOutput>, statement_meta: &StatementMeta) {
+    reply.send(StmtOutput::ColumnMetadata(Ok(Arc::clone(
+        &statement_meta.column_metadata,
+    ))));
 }
 
-async fn handle_bind(
+fn handle_bind(
     stmt: &mut Statement<'_>,
     statement_meta: &mut StatementMeta,
-    sender: &Sender<StmtOutput>,
+    reply: Reply<StmtOutput>,
     n: usize,
     value: SQLiteValue,
-) -> Result<()> {
+) {
     let value = Rc::new(value);
-    stmt.raw_bind_parameter(n, Rc::clone(&value))?;
-    statement_meta.bound_values.insert(n, value);
-    sender.send(StmtOutput::Bind(Ok(()))).await?;
+    let rv = stmt
+        .raw_bind_parameter(n, Rc::clone(&value))
+        .map_err(|e| RusqliteError::Bind(n, e));
+    if rv.is_ok() {
+        statement_meta.bound_values.insert(n, value);
+        statement_meta.bound();
+    }
+    reply.send(StmtOutput::Bind(rv));
+}
+
+// the index of every value, one per parameter of the statement
+fn resolve_parameters(
+    parameter_names: &[Option<Box<str>>],
+    params: Parameters,
+) -> Result<HashMap<usize, Rc<SQLiteValue>>> {
+    let given = match &params {
+        Parameters::Positional(values) => values.len(),
+        Parameters::Named(values) => values.len(),
+    };
+    if given != parameter_names.len() {
+        return Err(RusqliteError::RusqliteError(rusqlite::Error::InvalidParameterCount(
+            given,
+            parameter_names.len(),
+        )));
+    }
+    let mut resolved = HashMap::new();
+    match params {
+        Parameters::Positional(values) => {
+            for (i, value) in values.into_vec().into_iter().enumerate() {
+                resolved.insert(i + 1, Rc::new(value));
+            }
+        }
+        Parameters::Named(values) => {
+            for (name, value) in values.into_vec() {
+                let Some(n) = parameter_index(parameter_names, &name) else {
+                    return Err(RusqliteError::RusqliteError(rusqlite::Error::InvalidParameterName(
+                        name.into(),
+                    )));
+                };
+                resolved.insert(n, Rc::new(value));
+            }
+            // the same name twice leaves a parameter out
+            if resolved.len() != given {
+                return Err(RusqliteError::CustomError("Parameter named twice".to_owned()));
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+// all the values or none of them, the previous ones stay bound on error
+fn handle_bind_all(
+    stmt: &mut Statement<'_>,
+    statement_meta: &mut StatementMeta,
+    reply: Reply<StmtOutput>,
+    params: Parameters,
+) {
+    let values = match resolve_parameters(&statement_meta.parameter_names, params) {
+        Ok(values) => values,
+        Err(err) => return reply.send(StmtOutput::Bind(Err(err))),
+    };
+    for (&n, value) in &values {
+        if let Err(e) = stmt.raw_bind_parameter(n, Rc::clone(value)) {
+            stmt.clear_bindings();
+            for (&n, value) in &statement_meta.bound_values {
+                let _ = stmt.raw_bind_parameter(n, Rc::clone(value));
+            }
+            return reply.send(StmtOutput::Bind(Err(RusqliteError::Bind(n, e))));
+        }
+    }
+    statement_meta.bound_values = values;
+    statement_meta.bound();
+    reply.send(StmtOutput::Bind(Ok(())));
+}
+
+fn handle_bind_named(
+    stmt: &mut Statement<'_>,
+    statement_meta: &mut StatementMeta,
+    reply: Reply<StmtOutput>,
+    name: Box<str>,
+    value: SQLiteValue,
+) {
+    match parameter_index(&statement_meta.parameter_names, &name) {
+        Some(n) => handle_bind(stmt, statement_meta, reply, n, value),
+        None => reply.send(StmtOutput::Bind(Err(RusqliteError::RusqliteError(
+            rusqlite::Error::InvalidParameterName(name.into()),
+        )))),
+    }
+}
+
+// set up the connection for the steps of a step_by
+fn prepare_step(
+    statement_meta: &StatementMeta,
+    determinism: Option<&Determinism>,
+    budget: Option<u64>,
+) -> Result<()> {
+    statement_meta
+        .overrides
+        .set(&statement_meta.connection, determinism)?;
+    let budget = budget.or(statement_meta.default_budget);
+    statement_meta.budget.set(&statement_meta.connection, budget);
     Ok(())
 }
 
-// 0 is not allowed, so use it as special value to signal close
+// a replicated step_by renews the lease, false when the lease of the
+// connection ran out by index: it was rolled back with its connection and the
+// caller gets LeaseExpired
+async fn apply_step(statement_meta: &StatementMeta, index: u64) -> bool {
+    if expire_leases(&statement_meta.tracker, index, false).await {
+        return false;
+    }
+    statement_meta.tracker.applied(index);
+    true
+}
+
+// between two batches of rows, None when the statement is closed. The rows of
+// a write were buffered when it ran, a later step_by of the log only records
+// its index.
 async fn handle_stmt_inside_step(
-    receiver: &Receiver<StmtInput>,
-    sender: &Sender<StmtOutput>,
+    receiver: &Receiver<StmtRequest>,
     statement_meta: &mut StatementMeta,
-) -> Result<usize> {
+    writes: bool,
+) -> Option<(usize, Reply<StmtOutput>)> {
     loop {
-        let input = receiver.recv().await?;
+        let request = match next_input(receiver, statement_meta.tracker.statement_idle()).await {
+            Idle::Input(request) => request,
+            // every handle is gone, same as close
+            Idle::Closed => return None,
+            Idle::Expired => {
+                statement_meta.expire(receiver);
+                return None;
+            }
+        };
+        let Some((input, reply)) = request.start(Some(&statement_meta.running)) else {
+            continue;
+        };
+        statement_meta.tracker.touch();
         match input {
-            StmtInput::StepBy(n) => {
-                if n == 0 {
-                    return Err(RusqliteError::CustomError("Cannot step by 0".to_owned()));
+            StmtInput::StepBy(n, CallOptions { determinism, apply_index, budget }) => {
+                if let Some(index) = apply_index {
+                    if !apply_step(statement_meta, index).await {
+                        return None;
+                    }
+                }
+                let rv = if n == 0 {
+                    Err(RusqliteError::CustomError("Cannot step by 0".to_owned()))
+                } else {
+                    prepare_step(statement_meta, determinism.as_ref(), budget)
+                };
+                let rv = match apply_index {
+                    Some(index) if writes => rv.and_then(|_| {
+                        crate::apply_index::stamped(&statement_meta.connection, index, || Ok(()))
+                            .map(|_| ())
+                            .map_err(RusqliteError::from)
+                    }),
+                    _ => rv,
+                };
+                statement_meta
+                    .tracker
+                    .in_transaction(crate::transaction::active(&statement_meta.connection));
+                match rv {
+                    Ok(()) => return Some((n, reply)),
+                    Err(err) => reply.send(StmtOutput::Rows(Err(err))),
                 }
-                return Ok(n);
-            }
-            StmtInput::ColumnName(index) => {
-                handle_column_name(sender, index, statement_meta).await?
             }
-            StmtInput::ColumnNames => handle_column_names(sender, statement_meta).await?,
-            StmtInput::ColumnCount => handle_column_count(sender, statement_meta).await?,
-            StmtInput::CloneAndReset => handle_clone_and_reset(sender, statement_meta).await?,
-            StmtInput::ClearBindings => sender.send(StmtOutput::ClearBindings(Ok(false))).await?,
-            StmtInput::BindParameterCount => {
-                handle_bind_parameter_count(sender, statement_meta).await?
+            StmtInput::ColumnName(index) => handle_column_name(reply, index, statement_meta),
+            StmtInput::ColumnNames => handle_column_names(reply, statement_meta),
+            StmtInput::ColumnCount => handle_column_count(reply, statement_meta),
+            StmtInput::ColumnMetadata => handle_column_metadata(reply, statement_meta),
+            StmtInput::CloneAndReset => handle_clone_and_reset(reply, statement_meta),
+            StmtInput::ClearBindings => reply.send(StmtOutput::ClearBindings(Ok(false))),
+            StmtInput::BindParameterCount => handle_bind_parameter_count(reply, statement_meta),
+            StmtInput::BindParameterName(n) => handle_bind_parameter_name(reply, n, statement_meta),
+            StmtInput::BindParameterIndex(name) => {
+                handle_bind_parameter_index(reply, &name, statement_meta)
             }
 
-            StmtInput::Bind(_, _) => {
-                sender
-                    .send(StmtOutput::Bind(Err(RusqliteError::CustomError(
-                        "Cannot bind when stepping".to_owned(),
-                    ))))
-                    .await?
+            StmtInput::Bind(_, _) | StmtInput::BindNamed(_, _) | StmtInput::BindAll(_) => {
+                reply.send(StmtOutput::Bind(Err(RusqliteError::CustomError(
+                    "Cannot bind when stepping".to_owned(),
+                ))))
             }
             StmtInput::Close => {
-                sender.send(StmtOutput::Done).await?;
-                return Ok(0);
+                // nobody waits for the answer when the handle was dropped
+                reply.send(StmtOutput::Done);
+                return None;
             }
         }
     }
 }
 
+// only a read only step can be cancelled, it is flagged while sqlite works on
+// it and not while the rows wait for the next step_by, when the connection may
+// run other statements
+fn step<'a>(
+    rows: &'a mut rusqlite::Rows<'_>,
+    state: &StatementState,
+    readonly: bool,
+) -> rusqlite::Result<Option<&'a rusqlite::Row<'a>>> {
+    state.running.store(readonly, Ordering::SeqCst);
+    let next = rows.next();
+    state.running.store(false, Ordering::SeqCst);
+    next
+}
+
+// true when the statement was closed while stepping
 async fn handle_step_by(
     stmt: &mut Statement<'_>,
-    sender: &Sender<StmtOutput>,
-    receiver: &Receiver<StmtInput>,
+    mut reply: Reply<StmtOutput>,
+    receiver: &Receiver<StmtRequest>,
     mut n: usize,
     statement_meta: &mut StatementMeta,
-) -> Result<bool> {
+) -> bool {
+    if n == 0 {
+        reply.send(StmtOutput::Rows(Err(RusqliteError::CustomError(
+            "Cannot step by 0".to_owned(),
+        ))));
+        return false;
+    }
+
+    let readonly = stmt.readonly();
     let mut rows = stmt.raw_query();
     let mut tmp: Vec<Vec<SQLiteValue>> = Vec::new();
     let mut i = 0;
-    let cols = rows
-        .as_ref()
-        .ok_or(RusqliteError::CustomError(
+    let Some(cols) = rows.as_ref().map(|stmt| {
+        stmt.column_names()
+            .into_iter()
+            .map(|s| s.to_owned())
+            .collect::<Vec<_>>()
+    }) else {
+        reply.send(StmtOutput::Rows(Err(RusqliteError::CustomError(
             "This shouldn't happen".to_owned(),
-        ))?
-        .column_names()
-        .into_iter()
-        .map(|s| s.to_owned())
-        .collect::<Vec<_>>();
-
-    if n == 0 {
-        return Err(RusqliteError::CustomError("Cannot step by 0".to_owned()));
-    }
+        ))));
+        return false;
+    };
 
     let mut first_step_done = false;
 
     loop {
         if i == n {
-            if i == 0 {
-                sender.send(StmtOutput::Done).await?;
-                return Ok(false);
-            }
             let tmp = std::mem::take(&mut tmp);
-            sender.send(StmtOutput::Rows(Ok(tmp))).await?;
-            n = handle_stmt_inside_step(receiver, &sender, statement_meta).await?;
-            if n == 0 {
-                return Ok(true);
+            statement_meta.stepped(tmp.len());
+            reply.send(StmtOutput::Rows(Ok(tmp)));
+            match handle_stmt_inside_step(receiver, statement_meta, false).await {
+                Some((next, next_reply)) => (n, reply) = (next, next_reply),
+                None => return true,
             }
             i = 0;
         }
 
         {
-            if let Ok(Some(row)) = rows.next() {
+            let next = match step(&mut rows, &statement_meta.state, readonly) {
+                Ok(next) => next,
+                // e.g. interrupted, dropping rows resets the statement
+                Err(err) => {
+                    let err = statement_meta.budget.error(err);
+                    reply.send(StmtOutput::Rows(Err(err)));
+                    return false;
+                }
+            };
+            if let Some(row) = next {
                 let row = row_to_vec(cols.len(), row);
                 tmp.push(row);
                 i += 1;
             } else {
                 if i == 0 {
-                    sender.send(StmtOutput::Done).await?;
-                    return Ok(false);
+                    reply.send(StmtOutput::Done);
+                    return false;
                 }
                 let tmp = std::mem::take(&mut tmp);
-                sender.send(StmtOutput::Rows(Ok(tmp))).await?;
-                n = handle_stmt_inside_step(receiver, &sender, statement_meta).await?;
-                if n == 0 {
-                    return Ok(true);
+                statement_meta.stepped(tmp.len());
+                reply.send(StmtOutput::Rows(Ok(tmp)));
+                match handle_stmt_inside_step(receiver, statement_meta, false).await {
+                    Some((next, next_reply)) => (n, reply) = (next, next_reply),
+                    None => return true,
                 }
                 i = 0;
             };
@@ -485,75 +1076,290 @@ async fn handle_step_by(
     }
 }
 
+// the statement writes, run it to completion in the same savepoint as the
+// apply index and serve the buffered rows (e.g. from RETURNING) afterwards
+async fn handle_stamped_step_by(
+    stmt: &mut Statement<'_>,
+    mut reply: Reply<StmtOutput>,
+    receiver: &Receiver<StmtRequest>,
+    mut n: usize,
+    index: u64,
+    statement_meta: &mut StatementMeta,
+) -> bool {
+    if n == 0 {
+        reply.send(StmtOutput::Rows(Err(RusqliteError::CustomError(
+            "Cannot step by 0".to_owned(),
+        ))));
+        return false;
+    }
+    let connection = Rc::clone(&statement_meta.connection);
+    let ncols = stmt.column_count();
+    let rv = crate::apply_index::stamped(&connection, index, || {
+        let mut rows = stmt.raw_query();
+        let mut all = Vec::new();
+        while let Some(row) = rows.next()? {
+            all.push(row_to_vec(ncols, row));
+        }
+        Ok(all)
+    });
+    let rows = match rv {
+        Ok(rows) => rows.unwrap_or_default(),
+        Err(err) => {
+            let err = statement_meta.budget.error(err);
+            reply.send(StmtOutput::Rows(Err(err)));
+            return false;
+        }
+    };
+    let mut rows = rows.into_iter();
+    loop {
+        let batch = rows.by_ref().take(n).collect::<Vec<_>>();
+        if batch.is_empty() {
+            reply.send(StmtOutput::Done);
+            return false;
+        }
+        statement_meta.stepped(batch.len());
+        reply.send(StmtOutput::Rows(Ok(batch)));
+        match handle_stmt_inside_step(receiver, statement_meta, true).await {
+            Some((next, next_reply)) => (n, reply) = (next, next_reply),
+            None => return true,
+        }
+    }
+}
+
 struct StatementMeta {
     connection: Rc<Connection>,
+    overrides: Rc<Overrides>,
+    budget: Rc<Budget>,
+    statements: Rc<Statements>,
     query: Rc<str>,
     column_names: Arc<[Box<str>]>,
+    column_metadata: Arc<[ColumnMetadata]>,
     parameter_count: usize,
+    // of the parameters 1 to parameter_count, None for a plain ?
+    parameter_names: Box<[Option<Box<str>>]>,
     bound_values: HashMap<usize, Rc<SQLiteValue>>,
+    default_budget: Option<u64>,
+    state: Arc<StatementState>,
+    running: Arc<Running>,
+    id: Uuid,
+    tracker: Tracker,
 }
 
-async fn handle_statement(
-    sender: &Sender<StmtOutput>,
-    receiver: &Receiver<StmtInput>,
-    conn: Rc<Connection>,
-    query: Rc<str>,
-    parameters_to_bind: Option<HashMap<usize, Rc<SQLiteValue>>>,
-) -> Result<()> {
-    let mut stmt = conn
-        .prepare(&*query)
-        .map_err(RusqliteError::RusqliteError)?;
+impl StatementMeta {
+    fn bound(&self) {
+        let bound_values = bound_values(&self.bound_values);
+        self.tracker.statement(self.id, |info| info.bound_values = bound_values);
+    }
 
-    let column_names: Arc<[Box<str>]> = stmt
-        .column_names()
-        .into_iter()
-        .map(ToString::to_string)
-        .map(String::into_boxed_str)
-        .collect::<Vec<_>>()
-        .into_boxed_slice()
+    fn stepped(&self, rows: usize) {
+        self.tracker.statement(self.id, |info| {
+            info.rows_stepped += rows as u64;
+            info.mid_step = true;
+        });
+    }
+
+    fn reset(&self) {
+        self.tracker.statement(self.id, |info| info.mid_step = false);
+    }
+
+    // later calls on the handle fail with Expired
+    fn expire(&self, receiver: &Receiver<StmtRequest>) {
+        self.state.expired.store(true, Ordering::SeqCst);
+        receiver.close();
+    }
+}
+
+enum Idle<T> {
+    Input(T),
+    Closed,
+    Expired,
+}
+
+// The handles keep the channel alive after the task is gone, the requests
+// sent in the meantime are dropped so that their callers stop waiting.
+fn drop_pending<T>(receiver: &Receiver<T>) {
+    receiver.close();
+    while receiver.try_recv().is_ok() {}
+}
+
+async fn next_input<T>(receiver: &Receiver<T>, idle: Option<Duration>) -> Idle<T> {
+    let input = match idle {
+        Some(idle) => match tokio::time::timeout(idle, receiver.recv()).await {
+            Ok(input) => input,
+            Err(_) => return Idle::Expired,
+        },
+        None => receiver.recv().await,
+    };
+    input.map_or(Idle::Closed, Idle::Input)
+}
+
+fn bound_values(values: &HashMap<usize, Rc<SQLiteValue>>) -> Vec<(usize, SQLiteValue)> {
+    let mut bound_values = values
+        .iter()
+        .map(|(&n, value)| (n, SQLiteValue::clone(value)))
+        .collect::<Vec<_>>();
+    bound_values.sort_by_key(|&(n, _)| n);
+    bound_values
+}
+
+// what a statement task is started from, by prepare and clone_and_reset
+struct StatementSeed {
+    connection: Rc<Connection>,
+    overrides: Rc<Overrides>,
+    budget: Rc<Budget>,
+    statements: Rc<Statements>,
+    query: Rc<str>,
+    parameters_to_bind: Option<HashMap<usize, Rc<SQLiteValue>>>,
+    default_budget: Option<u64>,
+    running: Arc<Running>,
+    tracker: Tracker,
+}
+
+// the inputs of the statements of a connection, to close them on release
+type Statements = RefCell<Vec<Sender<StmtRequest>>>;
+
+fn spawn_statement(seed: StatementSeed) -> (Sender<StmtRequest>, Arc<StatementState>) {
+    let (sender, stmt_receiver) = unbounded();
+    {
+        let mut statements = seed.statements.borrow_mut();
+        // a finished task dropped its receiver
+        statements.retain(|statement| !statement.is_closed());
+        statements.push(sender.clone());
+    }
+    let id = Uuid::new_v4();
+    let parameters = seed.parameters_to_bind.as_ref().map(bound_values).unwrap_or_default();
+    seed.tracker.statement_opened(id, &seed.query, parameters);
+    let state = Arc::new(StatementState::default());
+    let stmt_state = Arc::clone(&state);
+    task::spawn_local(async move { statement(stmt_receiver, seed, stmt_state, id).await });
+    (sender, state)
+}
+
+// fails only when the statement cannot be prepared
+async fn handle_statement(
+    receiver: &Receiver<StmtRequest>,
+    seed: StatementSeed,
+    state: Arc<StatementState>,
+    id: Uuid,
+) -> Result<()> {
+    let StatementSeed {
+        connection: conn,
+        overrides,
+        budget,
+        statements,
+        query,
+        parameters_to_bind,
+        default_budget,
+        running,
+        tracker,
+    } = seed;
+    let mut stmt = conn
+        .prepare(&query)
+        .map_err(RusqliteError::RusqliteError)?;
+
+    let column_names: Arc<[Box<str>]> = stmt
+        .column_names()
+        .into_iter()
+        .map(ToString::to_string)
+        .map(String::into_boxed_str)
+        .collect::<Vec<_>>()
+        .into_boxed_slice()
         .into();
+    let column_metadata = crate::column_metadata::column_metadata(&conn, &stmt, &query)?;
 
     if let Some(ref parameters_to_bind) = parameters_to_bind {
         for (&n, value) in parameters_to_bind {
-            stmt.raw_bind_parameter(n, value)?;
+            stmt.raw_bind_parameter(n, value).map_err(|e| RusqliteError::Bind(n, e))?;
         }
     }
 
     let parameter_count = stmt.parameter_count();
+    let parameter_names = (1..=parameter_count)
+        .map(|n| stmt.parameter_name(n).map(Box::from))
+        .collect();
     let mut statement_meta = StatementMeta {
         connection: Rc::clone(&conn),
+        overrides,
+        budget,
+        statements,
         query,
         column_names,
+        column_metadata,
         parameter_count,
+        parameter_names,
         bound_values: parameters_to_bind.unwrap_or_default(),
+        default_budget,
+        state,
+        running,
+        id,
+        tracker,
     };
 
     loop {
-        let input = receiver.recv().await?;
+        let request = match next_input(receiver, statement_meta.tracker.statement_idle()).await {
+            Idle::Input(request) => request,
+            Idle::Closed => break,
+            Idle::Expired => {
+                statement_meta.expire(receiver);
+                break;
+            }
+        };
+        let Some((input, reply)) = request.start(Some(&statement_meta.running)) else {
+            continue;
+        };
+        statement_meta.tracker.touch();
         match input {
-            StmtInput::StepBy(n) => {
-                if handle_step_by(&mut stmt, &sender, &receiver, n, &mut statement_meta).await? {
+            StmtInput::StepBy(n, CallOptions { determinism, apply_index, budget }) => {
+                if let Some(index) = apply_index {
+                    if !apply_step(&statement_meta, index).await {
+                        return Ok(());
+                    }
+                }
+                if let Err(err) = prepare_step(&statement_meta, determinism.as_ref(), budget) {
+                    reply.send(StmtOutput::Rows(Err(err)));
+                    continue;
+                }
+                let closed = match apply_index {
+                    Some(index) if !stmt.readonly() => {
+                        handle_stamped_step_by(&mut stmt, reply, receiver, n, index, &mut statement_meta)
+                            .await
+                    }
+                    _ => handle_step_by(&mut stmt, reply, receiver, n, &mut statement_meta).await,
+                };
+                if closed {
                     return Ok(());
                 }
+                statement_meta.reset();
+                statement_meta
+                    .tracker
+                    .in_transaction(crate::transaction::active(&statement_meta.connection));
+            }
+            StmtInput::Bind(n, value) => handle_bind(&mut stmt, &mut statement_meta, reply, n, value),
+            StmtInput::BindNamed(name, value) => {
+                handle_bind_named(&mut stmt, &mut statement_meta, reply, name, value)
             }
-            StmtInput::Bind(n, value) => {
-                handle_bind(&mut stmt, &mut statement_meta, &sender, n, value).await?
+            StmtInput::BindAll(params) => {
+                handle_bind_all(&mut stmt, &mut statement_meta, reply, params)
             }
-            StmtInput::BindParameterCount => {
-                handle_bind_parameter_count(&sender, &statement_meta).await?
+            StmtInput::BindParameterCount => handle_bind_parameter_count(reply, &statement_meta),
+            StmtInput::BindParameterName(n) => handle_bind_parameter_name(reply, n, &statement_meta),
+            StmtInput::BindParameterIndex(name) => {
+                handle_bind_parameter_index(reply, &name, &statement_meta)
             }
             StmtInput::ClearBindings => {
                 stmt.clear_bindings();
-                sender.send(StmtOutput::ClearBindings(Ok(true))).await?
+                // clone_and_reset must not bind them again
+                statement_meta.bound_values.clear();
+                statement_meta.bound();
+                reply.send(StmtOutput::ClearBindings(Ok(true)))
             }
-            StmtInput::ColumnName(index) => {
-                handle_column_name(&sender, index, &statement_meta).await?
-            }
-            StmtInput::CloneAndReset => handle_clone_and_reset(&sender, &statement_meta).await?,
-            StmtInput::ColumnNames => handle_column_names(&sender, &statement_meta).await?,
-            StmtInput::ColumnCount => handle_column_count(&sender, &statement_meta).await?,
+            StmtInput::ColumnName(index) => handle_column_name(reply, index, &statement_meta),
+            StmtInput::CloneAndReset => handle_clone_and_reset(reply, &statement_meta),
+            StmtInput::ColumnNames => handle_column_names(reply, &statement_meta),
+            StmtInput::ColumnCount => handle_column_count(reply, &statement_meta),
+            StmtInput::ColumnMetadata => handle_column_metadata(reply, &statement_meta),
             StmtInput::Close => {
-                sender.send(StmtOutput::Done).await?;
+                reply.send(StmtOutput::Done);
                 break;
             }
         }
@@ -562,91 +1368,321 @@ async fn handle_statement(
 }
 
 async fn statement(
-    sender: Sender<StmtOutput>,
-    receiver: Receiver<StmtInput>,
-    conn: Rc<Connection>,
-    query: Rc<str>,
-    parameters_to_bind: Option<HashMap<usize, Rc<SQLiteValue>>>,
+    receiver: Receiver<StmtRequest>,
+    seed: StatementSeed,
+    state: Arc<StatementState>,
+    id: Uuid,
 ) {
-    if let Err(err) = handle_statement(&sender, &receiver, conn, query, parameters_to_bind).await {
-        let _ = sender.send(StmtOutput::Error(err)).await;
+    let tracker = seed.tracker.clone();
+    match contain_task(handle_statement(&receiver, seed, Arc::clone(&state), id)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => {
+            // prepare already answered, the first call on the statement gets the error
+            if let Ok(Request { reply, .. }) = receiver.recv().await {
+                reply.send(StmtOutput::Error(err));
+            }
+        }
+        Err(message) => {
+            tracker.crashed(&message);
+            *state.crashed.lock().unwrap() = Some(message);
+        }
+    }
+    drop_pending(&receiver);
+    tracker.statement_closed(id);
+    state.closed.close();
+}
+
+fn release_connection(connection: &Connection, statements: &Statements) -> Result<()> {
+    // the statement tasks see the closed channel and finalize
+    for statement in statements.borrow_mut().drain(..) {
+        statement.close();
+    }
+    crate::transaction::rollback(connection)
+}
+
+fn execute_with<P: Params + Copy>(
+    connection: &Connection,
+    query: &str,
+    params: P,
+    apply_index: Option<u64>,
+) -> rusqlite::Result<usize> {
+    match apply_index {
+        Some(index) => crate::apply_index::execute_stamped(connection, query, params, index),
+        None => connection.execute(query, params),
+    }
+}
+
+// the transactions whose lease ran out by index are rolled back before the
+// call at index runs, tell whether the one of tracker is among them. A worker
+// does not wait for itself, it rolls its own back.
+async fn expire_leases(tracker: &Tracker, index: u64, worker: bool) -> bool {
+    let mut own = false;
+    for (this, sender) in tracker.outrun(index) {
+        own |= this;
+        if this && worker {
+            continue;
+        }
+        let (request, answer) = Request::new(ConnectionInput::Lease(index));
+        if sender.send(request).await.is_ok() {
+            let _ = answer.await;
+        }
     }
+    own
+}
+
+// nobody renewed the lease, the write lock goes back to the other connections
+// on the file and the next calls fail with LeaseExpired
+fn abort(
+    receiver: &Receiver<ConnectionRequest>,
+    connection: &Connection,
+    statements: &Statements,
+    state: &ConnectionState,
+) {
+    state.aborted.store(true, Ordering::SeqCst);
+    receiver.close();
+    let _ = release_connection(connection, statements);
 }
 
 async fn handle_connection(
-    conn_sender: &Sender<ConnectionOutput>,
-    receiver: &Receiver<ConnectionInput>,
+    receiver: &Receiver<ConnectionRequest>,
     connection: Rc<Connection>,
-) -> Result<()> {
+    tracker: &Tracker,
+    state: &ConnectionState,
+) {
+    let overrides = Rc::new(Overrides::new());
+    let budget = Rc::new(Budget::new());
+    let statements = Rc::new(Statements::default());
     loop {
-        let op = receiver.recv().await?;
+        // the activity of the statements counts too
+        let idle = tracker.connection_idle();
+        let lease = if crate::transaction::active(&connection) {
+            tracker.lease_wait()
+        } else {
+            None
+        };
+        let wait = idle.map(|idle| idle.saturating_sub(tracker.idle_for()));
+        let wait = [wait, lease].into_iter().flatten().min();
+        let request = match next_input(receiver, wait).await {
+            Idle::Input(request) => request,
+            // the statements keep the connection open until they are closed too
+            Idle::Closed => {
+                let _ = crate::transaction::rollback(&connection);
+                return;
+            }
+            Idle::Expired => {
+                if lease.is_some() && tracker.lease_expired() {
+                    abort(receiver, &connection, &statements, state);
+                    return;
+                }
+                if idle.is_none_or(|idle| tracker.idle_for() < idle) {
+                    continue;
+                }
+                state.expired.store(true, Ordering::SeqCst);
+                receiver.close();
+                let _ = release_connection(&connection, &statements);
+                return;
+            }
+        };
+        let Some((op, reply)) = request.start(Some(&state.running)) else {
+            continue;
+        };
+        tracker.touch();
+        if let Some(index) = op.apply_index() {
+            // the caller sees the connection gone, the answer is LeaseExpired
+            if expire_leases(tracker, index, true).await {
+                abort(receiver, &connection, &statements, state);
+                return;
+            }
+        }
         match op {
-            ConnectionInput::Prepare(query) => {
-                let (stmt_sender, receiver) = unbounded();
-                let (sender, stmt_receiver) = unbounded();
-                let connection = Rc::clone(&connection);
-                let query = Rc::from(query);
-                task::spawn_local(async move {
-                    statement(stmt_sender, stmt_receiver, connection, query, None).await
-                });
-                conn_sender
-                    .send(ConnectionOutput::Prepare(Ok((sender, receiver))))
-                    .await?;
+            ConnectionInput::Prepare(query, default_budget) => {
+                let seed = StatementSeed {
+                    connection: Rc::clone(&connection),
+                    overrides: Rc::clone(&overrides),
+                    budget: Rc::clone(&budget),
+                    statements: Rc::clone(&statements),
+                    query: Rc::from(query),
+                    parameters_to_bind: None,
+                    default_budget,
+                    running: Arc::clone(&state.running),
+                    tracker: tracker.clone(),
+                };
+                reply.send(ConnectionOutput::Prepare(Ok(spawn_statement(seed))));
             }
 
             ConnectionInput::Changes => {
-                conn_sender
-                    .send(ConnectionOutput::Changes(Ok(connection.changes())))
-                    .await?;
+                reply.send(ConnectionOutput::Changes(Ok(connection.changes())));
             }
 
-            ConnectionInput::Execute(query, params) => {
-                let params = params.iter().map(|v| v as &dyn ToSql).collect::<Vec<_>>();
-                let rv = connection.execute(&*query, params.as_slice());
-                conn_sender
-                    .send(ConnectionOutput::Execute(rv.map_err(RusqliteError::from)))
-                    .await?;
+            ConnectionInput::Execute(query, params, CallOptions { determinism, apply_index, budget: steps }) => {
+                if let Some(index) = apply_index {
+                    tracker.applied(index);
+                }
+                budget.set(&connection, steps);
+                let rv = overrides
+                    .set(&connection, determinism.as_ref())
+                    .and_then(|_| match &params {
+                        Parameters::Positional(values) => {
+                            let values = values.iter().map(|v| v as &dyn ToSql).collect::<Vec<_>>();
+                            execute_with(&connection, &query, values.as_slice(), apply_index)
+                        }
+                        Parameters::Named(values) => {
+                            let values = values
+                                .iter()
+                                .map(|(name, v)| (&**name, v as &dyn ToSql))
+                                .collect::<Vec<_>>();
+                            execute_with(&connection, &query, values.as_slice(), apply_index)
+                        }
+                    });
+                reply.send(ConnectionOutput::Execute(rv.map_err(|err| budget.error(err))));
+            }
+
+            ConnectionInput::ExecuteScript(sql, CallOptions { determinism, apply_index, budget: steps }) => {
+                if let Some(index) = apply_index {
+                    tracker.applied(index);
+                }
+                budget.set(&connection, steps);
+                let rv = overrides
+                    .set(&connection, determinism.as_ref())
+                    .map_err(RusqliteError::from)
+                    .and_then(|_| crate::script::execute_script(&connection, &sql, apply_index, &budget));
+                reply.send(ConnectionOutput::ExecuteScript(rv));
+            }
+
+            ConnectionInput::ExecuteMany(query, rows, CallOptions { determinism, apply_index, budget: steps }) => {
+                if let Some(index) = apply_index {
+                    tracker.applied(index);
+                }
+                budget.set(&connection, steps);
+                let rv = overrides
+                    .set(&connection, determinism.as_ref())
+                    .map_err(RusqliteError::from)
+                    .and_then(|_| {
+                        crate::bulk::execute_many(&connection, &query, &rows, apply_index, &budget)
+                    });
+                reply.send(ConnectionOutput::ExecuteMany(rv));
             }
 
             ConnectionInput::LastInsertRowId => {
-                conn_sender
-                    .send(ConnectionOutput::LastInsertRowid(
-                        connection.last_insert_rowid(),
-                    ))
-                    .await?
+                reply.send(ConnectionOutput::LastInsertRowid(
+                    connection.last_insert_rowid(),
+                ))
             }
 
             ConnectionInput::BusyTimeout(timeout) => {
-                conn_sender
-                    .send(ConnectionOutput::BusyTimeout(
-                        connection
-                            .busy_timeout(timeout)
-                            .map_err(RusqliteError::from),
-                    ))
-                    .await?
+                reply.send(ConnectionOutput::BusyTimeout(
+                    connection
+                        .busy_timeout(timeout)
+                        .map_err(RusqliteError::from),
+                ))
+            }
+
+            ConnectionInput::Classify(query) => {
+                let rv = connection
+                    .prepare(&query)
+                    .map(|stmt| crate::classification::classify_statement(&stmt, &query))
+                    .map_err(RusqliteError::from);
+                reply.send(ConnectionOutput::Classify(rv))
+            }
+
+            ConnectionInput::Transaction(op, apply_index) => {
+                let rv = match apply_index {
+                    Some(index) => {
+                        tracker.applied(index);
+                        crate::transaction::apply_stamped(&connection, &op, index)
+                    }
+                    None => crate::transaction::apply(&connection, &op),
+                };
+                reply.send(ConnectionOutput::Transaction(rv))
+            }
+
+            ConnectionInput::InTransaction => {
+                reply.send(ConnectionOutput::InTransaction(crate::transaction::active(
+                    &connection,
+                )))
+            }
+
+            ConnectionInput::Release => {
+                let rv = release_connection(&connection, &statements);
+                reply.send(ConnectionOutput::Release(rv));
+                return;
+            }
+
+            ConnectionInput::Lease(index) => {
+                if tracker.outrun(index).iter().any(|(this, _)| *this) {
+                    abort(receiver, &connection, &statements, state);
+                    reply.send(ConnectionOutput::Done);
+                    return;
+                }
+                reply.send(ConnectionOutput::Done);
             }
 
             ConnectionInput::Close => {
-                conn_sender.send(ConnectionOutput::Done).await?;
-                return Ok(());
+                let _ = crate::transaction::rollback(&connection);
+                reply.send(ConnectionOutput::Done);
+                return;
             }
         }
+        // a long call, e.g. waiting for a lock, isn't idle time
+        tracker.touch();
+        tracker.in_transaction(crate::transaction::active(&connection));
     }
 }
 
 async fn connection(
-    sender: Sender<ConnectionOutput>,
-    receiver: Receiver<ConnectionInput>,
+    receiver: Receiver<ConnectionRequest>,
     connection: Rc<Connection>,
+    tracker: Tracker,
+    state: Arc<ConnectionState>,
 ) {
-    if let Err(err) = handle_connection(&sender, &receiver, connection).await {
-        let _ = sender.send(ConnectionOutput::Error(err)).await;
+    if let Err(message) = contain_task(handle_connection(&receiver, connection, &tracker, &state)).await {
+        tracker.crashed(&message);
+        *state.crashed.lock().unwrap() = Some(message);
     }
+    drop_pending(&receiver);
+    tracker.closed();
+    state.closed.close();
+}
+
+// every connection runs with its statements on its own thread, so that a busy
+// wait or a long step only blocks that connection and not the lock holder
+fn spawn_connection_worker(
+    conn: Connection,
+    registry: &Arc<Registry>,
+    info: ConnectionInfo,
+) -> Result<(Sender<ConnectionRequest>, Arc<ConnectionState>)> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .map_err(|_| RusqliteError::CustomError("Can't start runtime".to_owned()))?;
+    let (sender, conn_receiver) = unbounded();
+    let state = Arc::new(ConnectionState {
+        interrupt: conn.get_interrupt_handle(),
+        running: Arc::default(),
+        expired: AtomicBool::new(false),
+        aborted: AtomicBool::new(false),
+        crashed: Mutex::new(None),
+        closed: Closed::default(),
+    });
+    let conn_state = Arc::clone(&state);
+    let tracker = registry.open(info, sender.clone());
+    std::thread::spawn(move || {
+        let ls = LocalSet::new();
+        let connection_tracker = tracker.clone();
+        ls.spawn_local(async move {
+            connection(conn_receiver, Rc::new(conn), connection_tracker, conn_state).await
+        });
+        // statements may outlive the connection task, wait for all of them
+        rt.block_on(ls);
+        tracker.gone();
+    });
+    Ok((sender, state))
 }
 
 fn do_create_context(
-    context_sender: Sender<ContextOutput>,
-    context_receiver: Receiver<ContextInput>,
+    context_receiver: Receiver<ContextRequest>,
+    registry: Arc<Registry>,
 ) -> Result<()> {
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_io()
@@ -657,19 +1693,28 @@ fn do_create_context(
     rt.block_on(async move {
         ls.run_until(async {
             loop {
-                let op = context_receiver.recv().await?;
+                let Some((op, reply)) = context_receiver.recv().await?.start(None) else {
+                    continue;
+                };
                 match op {
-                    ContextInput::Create(file) => {
-                        let conn = Connection::open(&*file)?;
-                        let (conn_sender, receiver) = unbounded();
-                        let (sender, conn_receiver) = unbounded();
-                        task::spawn_local(async move {
-                            connection(conn_sender, conn_receiver, Rc::new(conn)).await
+                    ContextInput::Create(file, info) => {
+                        // the context thread outlives a panic while opening
+                        let rv = contain(|| {
+                            Connection::open(&*file)
+                                .map_err(RusqliteError::from)
+                                .and_then(|conn| spawn_connection_worker(conn, &registry, info))
                         });
-                        context_sender.send_blocking(ContextOutput::Create(sender, receiver))?;
+                        if let Err(RusqliteError::WorkerCrashed(message)) = &rv {
+                            registry.crashed(message);
+                        }
+                        let output = match rv {
+                            Ok((sender, state)) => ContextOutput::Create(sender, state),
+                            Err(err) => ContextOutput::Error(err),
+                        };
+                        reply.send(output);
                     }
                     ContextInput::Close => {
-                        context_sender.send_blocking(ContextOutput::Done)?;
+                        reply.send(ContextOutput::Done);
                         return Ok(());
                     }
                 }
@@ -679,23 +1724,81 @@ fn do_create_context(
     })
 }
 
-pub fn create_context(home: &str) -> Result<Context> {
-    let (conn_th_sender, receiver) = unbounded();
+fn start_context_worker(registry: &Arc<Registry>) -> (Sender<ContextRequest>, ContextWorker) {
     let (sender, conn_th_receiver) = unbounded();
+    let worker_registry = Arc::clone(registry);
+    let join_handle = std::thread::spawn(move || {
+        do_create_context(conn_th_receiver, worker_registry)
+    });
+    (sender, join_handle)
+}
+
+pub fn create_context(home: &str) -> Result<Context> {
     let uuid = Uuid::new_v4();
-    let join_handle =
-        std::thread::spawn(move || do_create_context(conn_th_sender, conn_th_receiver));
-    let join_handle = Some(join_handle);
+    let registry = Arc::new(Registry::default());
+    let (sender, join_handle) = start_context_worker(&registry);
     let home = PathBuf::from(home);
     Ok(Context {
         home,
-        sender,
-        receiver,
+        sender: Mutex::new(sender),
         uuid,
-        join_handle,
+        join_handle: Mutex::new(Some(join_handle)),
+        default_timeout: Mutex::new(None),
+        registry,
     })
 }
 
+// the thread that opens the connections is replaced by a new one on the same
+// home, the open connections run on their own threads and keep working
+pub fn restart_context(ctx: &Context) {
+    let (sender, join_handle) = start_context_worker(&ctx.registry);
+    let sender = std::mem::replace(&mut *ctx.sender.lock().unwrap(), sender);
+    let join_handle = ctx.join_handle.lock().unwrap().replace(join_handle);
+    stop_context_worker(&sender, join_handle);
+}
+
+fn context_alive(ctx: &Context) -> bool {
+    let join_handle = ctx.join_handle.lock().unwrap();
+    join_handle.as_ref().is_some_and(|join_handle| !join_handle.is_finished())
+}
+
+pub fn health(ctx: &Context) -> Health {
+    let (connections, statements) = ctx.registry.counts();
+    let (crashes, last_crash) = ctx.registry.crashes();
+    Health {
+        context_alive: context_alive(ctx),
+        connections,
+        statements,
+        crashes,
+        last_crash,
+    }
+}
+
+// start a context on a home that may hold the files of a previous run, every
+// file is opened once so that sqlite recovers it and is checked
+pub fn open_context(home: &str, options: &OpenOptions) -> Result<(Context, Inventory)> {
+    let home_path = PathBuf::from(home);
+    if !home_path.is_dir() {
+        if !options.create {
+            return Err(RusqliteError::CustomError(
+                "Context home doesn't exist".to_owned(),
+            ));
+        }
+        std::fs::create_dir_all(&home_path)?;
+    }
+    let ctx = create_context(home)?;
+    let mut buckets = list_buckets(&ctx)?;
+    buckets.sort();
+    let mut inventory = Inventory::default();
+    for bucket in buckets {
+        for (file, path) in database_files(&ctx.home, &bucket)? {
+            let report = crate::recovery::check_file(&bucket, &file, &path, options);
+            inventory.files.push(report);
+        }
+    }
+    Ok((ctx, inventory))
+}
+
 fn check_connection_consistency(ctx: &Context, conn: &VirtualConnection) -> Result<()> {
     if conn.context != ctx.uuid {
         return Err(RusqliteError::CustomError(
@@ -725,8 +1828,7 @@ fn check_statement_consistency(
 }
 
 fn mangle_bucket(bucket: &str) -> String {
-    let bucket = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bucket);
-    bucket
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bucket)
 }
 
 fn mangle_filename(filename: &str) -> String {
@@ -734,18 +1836,70 @@ fn mangle_filename(filename: &str) -> String {
     format!("{}.db", filename)
 }
 
-fn bucket_target_path(home: &PathBuf, bucket: &str) -> PathBuf {
+fn bucket_target_path(home: &Path, bucket: &str) -> PathBuf {
     let mangled_bucket = mangle_bucket(bucket);
     home.join(mangled_bucket)
 }
 
-fn file_target_path(home: &PathBuf, bucket: &str, filename: &str) -> PathBuf {
+fn file_target_path(home: &Path, bucket: &str, filename: &str) -> PathBuf {
     let mangled_filename = mangle_filename(filename);
     bucket_target_path(home, bucket).join(mangled_filename)
 }
 
-pub fn create_connection(ctx: &Context, bucket: &str, filename: &str) -> Result<VirtualConnection> {
-    let (sender, receiver) = (&ctx.sender, &ctx.receiver);
+pub fn create_connection(
+    ctx: &Context,
+    bucket: &str,
+    filename: &str,
+    deadline: Option<Instant>,
+) -> Result<VirtualConnection> {
+    let deadline = resolve_deadline(ctx, deadline);
+    let (file, info) = connection_input(ctx, bucket, filename)?;
+    let uuid = info.id;
+    let (request, answer) = Request::with_deadline(ContextInput::Create(file, info), deadline);
+    let context_sender = ctx.sender.lock().unwrap().clone();
+    // a context thread that is gone drops the request
+    let _ = context_sender.send_blocking(request);
+    // opening a file cannot be interrupted, a late answer is dropped
+    let output = recv_answer(answer, deadline, || ()).map_err(|err| match err {
+        RusqliteError::CommunicationError(_) if !context_alive(ctx) => {
+            RusqliteError::WorkerCrashed("the context thread is not running".to_owned())
+        }
+        err => err,
+    })?;
+    created(ctx.uuid, uuid, output)
+}
+
+pub fn create_connection_then(
+    ctx: &Context,
+    bucket: &str,
+    filename: &str,
+    then: impl FnOnce(Result<VirtualConnection>) + Send + 'static,
+) {
+    let (file, info) = match connection_input(ctx, bucket, filename) {
+        Ok(input) => input,
+        Err(err) => return then(Err(err)),
+    };
+    let (context, uuid) = (ctx.uuid, info.id);
+    let request = Request::with_callback(ContextInput::Create(file, info), move |rv| {
+        let rv = rv.map_err(|err| match err {
+            // dropped by a context thread that is gone
+            RusqliteError::CommunicationError(_) => {
+                RusqliteError::WorkerCrashed("the context thread is not running".to_owned())
+            }
+            err => err,
+        });
+        then(rv.and_then(|output| created(context, uuid, output)))
+    });
+    let context_sender = ctx.sender.lock().unwrap().clone();
+    let _ = context_sender.send_blocking(request);
+}
+
+// the path of the file, created with its bucket, and what the registry shows
+fn connection_input(
+    ctx: &Context,
+    bucket: &str,
+    filename: &str,
+) -> Result<(Box<str>, ConnectionInfo)> {
     let directory_target = bucket_target_path(&ctx.home, bucket);
     if !directory_target.exists() {
         std::fs::create_dir_all(&directory_target)?;
@@ -758,19 +1912,32 @@ pub fn create_connection(ctx: &Context, bucket: &str, filename: &str) -> Result<
         ))?
         .to_string()
         .into_boxed_str();
-    sender.send_blocking(ContextInput::Create(file))?;
-    let ContextOutput::Create(sender, receiver) = receiver.recv_blocking()? else {
-        unreachable!()
+    let now = SystemTime::now();
+    let info = ConnectionInfo {
+        id: Uuid::new_v4(),
+        bucket: bucket.to_owned(),
+        file: filename.to_owned(),
+        opened: now,
+        last_activity: now,
+        closed: false,
     };
-    Ok(VirtualConnection {
-        sender,
-        receiver,
-        uuid: Uuid::new_v4(),
-        context: ctx.uuid,
-    })
+    Ok((file, info))
+}
+
+fn created(context: Uuid, uuid: Uuid, output: ContextOutput) -> Result<VirtualConnection> {
+    match output {
+        ContextOutput::Create(sender, state) => Ok(VirtualConnection {
+            sender,
+            uuid,
+            context,
+            state,
+            owned: true,
+        }),
+        ContextOutput::Error(err) => Err(err),
+        _ => Err(unexpected_answer()),
+    }
 }
 
-
 pub fn list_buckets(ctx: &Context) -> Result<Vec<String>> {
     let read_dir = std::fs::read_dir(&ctx.home)?;
     let mut buckets = Vec::new();
@@ -782,7 +1949,7 @@ pub fn list_buckets(ctx: &Context) -> Result<Vec<String>> {
         if !path.is_dir() {
             continue;
         }
-        get_filename(&path).map(|x| buckets.push(x));
+        buckets.extend(get_filename(&path));
     }
     Ok(buckets)
 }
@@ -799,15 +1966,12 @@ pub fn list_files(ctx: &Context, bucket: &str) -> Result<Vec<String>> {
         if !path.is_file() {
             continue;
         }
-        get_filename(&path).map(|x| files.push(x));
+        files.extend(get_filename(&path));
     }
     Ok(files)
 }
 
-
-
-
-fn get_filename(path: &PathBuf) -> Option<String> {
+fn get_filename(path: &Path) -> Option<String> {
     let file_name = path
         .file_stem()?
         .to_str()?;
@@ -834,138 +1998,846 @@ pub fn delete_bucket(ctx: &Context, bucket: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn libversion(ctx: &Context) -> String {
-    rusqlite::version().to_owned()
+// what is open in ctx, the connections that were closed are listed until
+// their last statement is finalized
+pub fn list_connections(ctx: &Context) -> Result<Vec<ConnectionInfo>> {
+    Ok(ctx.registry.connections())
 }
 
-fn do_conn<T>(
-    ctx: &Context,
-    conn: &VirtualConnection,
-    input: ConnectionInput,
-    f: impl Fn(ConnectionOutput) -> Result<T>,
-) -> Result<T> {
+pub fn list_statements(ctx: &Context, conn: &VirtualConnection) -> Result<Vec<StatementInfo>> {
     check_connection_consistency(ctx, conn)?;
-    let (sender, receiver) = (&conn.sender, &conn.receiver);
-    // do not unwrap or return error, but wait for error received by receiver
-    let _ = sender.send_blocking(input);
-    let tmp = receiver.recv_blocking()?;
-    if let ConnectionOutput::Error(err) = tmp {
-        return Err(err);
+    Ok(ctx.registry.statements(conn.uuid))
+}
+
+// only the "<mangled>.db" files, journals and wal files are skipped
+fn database_files(home: &Path, bucket: &str) -> Result<Vec<(String, PathBuf)>> {
+    let directory_target = bucket_target_path(home, bucket);
+    let read_dir = std::fs::read_dir(directory_target)?;
+    let mut files = Vec::new();
+    for entry in read_dir {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let path = entry.path();
+        if !path.is_file() || path.extension().and_then(|x| x.to_str()) != Some("db") {
+            continue;
+        }
+        if let Some(file) = get_filename(&path) {
+            files.push((file, path));
+        }
     }
-    f(tmp)
+    files.sort();
+    Ok(files)
 }
 
-pub fn set_busy_timeout(ctx: &Context, conn: &VirtualConnection, timeout: Duration) -> Result<()> {
-    do_conn(
-        ctx,
-        conn,
-        ConnectionInput::BusyTimeout(timeout),
-        |tmp| match tmp {
-            ConnectionOutput::BusyTimeout(res) => res,
-            _ => unreachable!(),
-        },
-    )
+const MANIFEST: &str = "manifest.db";
+
+fn is_empty_dir(path: &Path) -> Result<bool> {
+    if !path.exists() {
+        return Ok(true);
+    }
+    Ok(std::fs::read_dir(path)?.next().is_none())
 }
 
-pub fn prepare(ctx: &Context, conn: &VirtualConnection, query: &str) -> Result<VirtualStatement> {
-    do_conn(
-        ctx,
-        conn,
-        ConnectionInput::Prepare(query.to_owned().into_boxed_str()),
-        |tmp| match tmp {
-            ConnectionOutput::Prepare(res) => {
-                let (sender, receiver) = res?;
-                Ok(VirtualStatement {
-                    sender,
-                    receiver,
-                    connection: conn.uuid,
-                    context: ctx.uuid,
-                })
-            }
-            _ => unreachable!(),
-        },
-    )
+// copy every file of the context into dest using the online backup api, the
+// logical names are stored in dest/manifest.db so that the archive can be
+// restored without relying on the mangling scheme
+pub fn snapshot_context(ctx: &Context, dest: &str) -> Result<Vec<(String, String)>> {
+    let dest = PathBuf::from(dest);
+    if !is_empty_dir(&dest)? {
+        return Err(RusqliteError::CustomError(
+            "Snapshot destination isn't empty".to_owned(),
+        ));
+    }
+    std::fs::create_dir_all(&dest)?;
+    let manifest = Connection::open(dest.join(MANIFEST))?;
+    manifest.execute_batch(
+        "CREATE TABLE files (bucket TEXT NOT NULL, file TEXT NOT NULL, PRIMARY KEY (bucket, file));
+         BEGIN;",
+    )?;
+    let mut buckets = list_buckets(ctx)?;
+    buckets.sort();
+    let mut archived = Vec::new();
+    for bucket in buckets {
+        std::fs::create_dir_all(bucket_target_path(&dest, &bucket))?;
+        for (file, path) in database_files(&ctx.home, &bucket)? {
+            let src = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            src.backup(DatabaseName::Main, file_target_path(&dest, &bucket, &file), None)?;
+            manifest.execute(
+                "INSERT INTO files (bucket, file) VALUES (?1, ?2)",
+                (&bucket, &file),
+            )?;
+            archived.push((bucket.clone(), file));
+        }
+    }
+    manifest.execute_batch("COMMIT;")?;
+    Ok(archived)
 }
 
-pub fn execute(
-    ctx: &Context,
-    conn: &VirtualConnection,
-    query: &str,
-    params: Vec<SQLiteValue>,
-) -> Result<usize> {
-    do_conn(
-        ctx,
-        conn,
-        ConnectionInput::Execute(
-            query.to_string().into_boxed_str(),
-            params.into_boxed_slice(),
-        ),
-        |tmp| match tmp {
-            ConnectionOutput::Execute(rv) => rv,
-            _ => unreachable!(),
-        },
-    )
+// rebuild a home from an archive made by snapshot_context and start a context on it
+pub fn restore_context(home: &str, archive: &str) -> Result<Context> {
+    restore_files(&PathBuf::from(home), &PathBuf::from(archive))?;
+    create_context(home)
 }
 
-pub fn close(ctx: &Context, conn: &VirtualConnection) -> Result<()> {
-    do_conn(ctx, conn, ConnectionInput::Close, |tmp| match tmp {
-        ConnectionOutput::Done => Ok(()),
-        _ => unreachable!(),
-    })
+// same as restore_context, into the home of a context that is already running.
+// The connections it has open keep the files they had.
+pub fn restore_home(ctx: &Context, archive: &str) -> Result<()> {
+    restore_files(&ctx.home, &PathBuf::from(archive))
 }
 
-pub fn last_insert_rowid(ctx: &Context, conn: &VirtualConnection) -> Result<i64> {
-    do_conn(
-        ctx,
-        conn,
-        ConnectionInput::LastInsertRowId,
-        |tmp| match tmp {
-            ConnectionOutput::LastInsertRowid(id) => Ok(id),
-            _ => unreachable!(),
-        },
-    )
+fn restore_files(home: &Path, archive: &Path) -> Result<()> {
+    if !is_empty_dir(home)? {
+        return Err(RusqliteError::CustomError(
+            "Restore target isn't empty".to_owned(),
+        ));
+    }
+    let manifest =
+        Connection::open_with_flags(archive.join(MANIFEST), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = manifest.prepare("SELECT bucket, file FROM files ORDER BY bucket, file")?;
+    let entries = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    std::fs::create_dir_all(home)?;
+    for (bucket, file) in entries {
+        std::fs::create_dir_all(bucket_target_path(home, &bucket))?;
+        let mut dst = Connection::open(file_target_path(home, &bucket, &file))?;
+        dst.restore(
+            DatabaseName::Main,
+            file_target_path(archive, &bucket, &file),
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+    }
+    Ok(())
 }
 
-pub fn changes(ctx: &Context, conn: &VirtualConnection) -> Result<u64> {
-    do_conn(ctx, conn, ConnectionInput::Changes, |tmp| match tmp {
-        ConnectionOutput::Changes(changes) => changes,
-        _ => unreachable!(),
-    })
+// hash of every bucket, file, schema and table content, replicas that applied
+// the same log entries have the same digest
+pub fn state_digest(ctx: &Context) -> Result<StateDigest> {
+    let mut buckets = list_buckets(ctx)?;
+    buckets.sort();
+    let mut files = Vec::new();
+    for bucket in buckets {
+        for (file, path) in database_files(&ctx.home, &bucket)? {
+            files.push(crate::digest::file_digest(&bucket, &file, &path)?);
+        }
+    }
+    Ok(crate::digest::state_digest(files))
 }
 
-fn do_stmt<T>(
+pub fn libversion(_ctx: &Context) -> String {
+    rusqlite::version().to_owned()
+}
+
+// the timeout applies to the calls that do not carry a deadline, None lets
+// them wait for the answer however long it takes. Leave it unset on a context
+// whose writes are replicated, the replicas would not time out at the same
+// point, pass a deadline to the calls that run on one node instead.
+pub fn set_default_timeout(ctx: &Context, timeout: Option<Duration>) {
+    *ctx.default_timeout.lock().unwrap() = timeout;
+}
+
+// a statement or a connection, with its statements, that gets no call for
+// that long is finalized or closed, later calls on it fail with Expired.
+// It applies to the waits that start after the call. The clock is the one of
+// the worker, leave it unset on a context whose calls are replicated.
+pub fn set_idle_timeouts(ctx: &Context, statement: Option<Duration>, connection: Option<Duration>) {
+    ctx.registry.set_idle_timeouts(statement, connection);
+}
+
+// An open transaction is rolled back when that many raft entries were
+// applied in the context since the last replicated call on its connection or
+// statements, every replica at the same entry. The duration applies only to
+// connections that never got a replicated call, on the clock of the worker.
+// None for both means no lease.
+pub fn set_transaction_lease(ctx: &Context, duration: Option<Duration>, entries: Option<u64>) {
+    ctx.registry.set_transaction_lease(duration, entries);
+}
+
+fn resolve_deadline(ctx: &Context, deadline: Option<Instant>) -> Option<Instant> {
+    deadline.or_else(|| ctx.default_timeout.lock().unwrap().map(|timeout| Instant::now() + timeout))
+}
+
+// the answer always belongs to the request, this is a bug in the worker
+fn unexpected_answer() -> RusqliteError {
+    RusqliteError::CommunicationError("unexpected answer".to_owned())
+}
+
+fn do_conn<T>(
     ctx: &Context,
     conn: &VirtualConnection,
-    stmt: &VirtualStatement,
-    input: StmtInput,
-    f: impl Fn(StmtOutput) -> Result<T>,
+    input: ConnectionInput,
+    deadline: Option<Instant>,
+    f: impl FnOnce(ConnectionOutput) -> Result<T>,
 ) -> Result<T> {
-    check_statement_consistency(ctx, conn, stmt)?;
-    let (sender, receiver) = (&stmt.sender, &stmt.receiver);
-    // do not unwrap or return error, but wait for error received by receiver
-    let _ = sender.send_blocking(input);
-    let tmp = receiver.recv_blocking()?;
-    if let StmtOutput::Error(err) = tmp {
+    check_connection_consistency(ctx, conn)?;
+    if let Some(err) = conn.state.gone() {
         return Err(err);
     }
+    let deadline = resolve_deadline(ctx, deadline);
+    let (request, answer) = Request::with_deadline(input, deadline);
+    let id = request.id;
+    // do not unwrap or return error, a worker that is gone drops the answer
+    let _ = conn.sender.send_blocking(request);
+    let tmp = recv_answer(answer, deadline, || conn.state.running.interrupt(id, &conn.state.interrupt))
+        .map_err(|err| conn.state.gone().unwrap_or(err))?;
     f(tmp)
 }
 
-pub fn bind(
+// the same call without waiting, then gets the answer on the worker thread.
+// There is no deadline, the caller decides how long it waits.
+fn do_conn_then<T>(
     ctx: &Context,
     conn: &VirtualConnection,
-    stmt: &VirtualStatement,
-    n: usize,
-    value: SQLiteValue,
+    input: ConnectionInput,
+    f: impl FnOnce(ConnectionOutput) -> Result<T> + Send + 'static,
+    then: impl FnOnce(Result<T>) + Send + 'static,
+) {
+    if let Err(err) = check_connection_consistency(ctx, conn) {
+        return then(Err(err));
+    }
+    if let Some(err) = conn.state.gone() {
+        return then(Err(err));
+    }
+    let state = conn.state.clone();
+    let request = Request::with_callback(input, move |rv| {
+        then(rv.map_err(|err| state.gone().unwrap_or(err)).and_then(f))
+    });
+    // a worker that is gone drops the request, which calls then
+    let _ = conn.sender.send_blocking(request);
+}
+
+pub fn set_busy_timeout(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    timeout: Duration,
+    deadline: Option<Instant>,
 ) -> Result<()> {
-    do_stmt(
-        ctx,
-        conn,
+    let input = ConnectionInput::BusyTimeout(timeout);
+    do_conn(ctx, conn, input, deadline, busy_timeout_output)
+}
+
+pub fn set_busy_timeout_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    timeout: Duration,
+    then: impl FnOnce(Result<()>) + Send + 'static,
+) {
+    let input = ConnectionInput::BusyTimeout(timeout);
+    do_conn_then(ctx, conn, input, busy_timeout_output, then)
+}
+
+fn busy_timeout_output(output: ConnectionOutput) -> Result<()> {
+    match output {
+        ConnectionOutput::BusyTimeout(res) => res,
+        _ => Err(unexpected_answer()),
+    }
+}
+
+// budget is the default of every step_by on the statement that has none
+pub fn prepare(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    budget: Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<VirtualStatement> {
+    let input = ConnectionInput::Prepare(query.to_owned().into_boxed_str(), budget);
+    let (context, connection) = (ctx.uuid, conn.uuid);
+    do_conn(ctx, conn, input, deadline, move |tmp| prepared(context, connection, tmp))
+}
+
+pub fn prepare_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    budget: Option<u64>,
+    then: impl FnOnce(Result<VirtualStatement>) + Send + 'static,
+) {
+    let input = ConnectionInput::Prepare(query.to_owned().into_boxed_str(), budget);
+    let (context, connection) = (ctx.uuid, conn.uuid);
+    do_conn_then(ctx, conn, input, move |tmp| prepared(context, connection, tmp), then)
+}
+
+fn prepared(context: Uuid, connection: Uuid, output: ConnectionOutput) -> Result<VirtualStatement> {
+    match output {
+        ConnectionOutput::Prepare(res) => {
+            let (sender, state) = res?;
+            Ok(VirtualStatement {
+                sender,
+                connection,
+                context,
+                state,
+                owned: true,
+            })
+        }
+        _ => Err(unexpected_answer()),
+    }
+}
+
+pub fn execute(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    params: Vec<SQLiteValue>,
+    options: CallOptions,
+    deadline: Option<Instant>,
+) -> Result<usize> {
+    let input = ConnectionInput::Execute(
+        query.to_string().into_boxed_str(),
+        Parameters::Positional(params.into_boxed_slice()),
+        options,
+    );
+    do_conn(ctx, conn, input, deadline, execute_output)
+}
+
+pub fn execute_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    params: Vec<SQLiteValue>,
+    options: CallOptions,
+    then: impl FnOnce(Result<usize>) + Send + 'static,
+) {
+    let input = ConnectionInput::Execute(
+        query.to_string().into_boxed_str(),
+        Parameters::Positional(params.into_boxed_slice()),
+        options,
+    );
+    do_conn_then(ctx, conn, input, execute_output, then)
+}
+
+fn execute_output(output: ConnectionOutput) -> Result<usize> {
+    match output {
+        ConnectionOutput::Execute(rv) => rv,
+        _ => Err(unexpected_answer()),
+    }
+}
+
+// same as execute with the values by parameter name, e.g. (":id", 1)
+pub fn execute_named(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    params: Vec<(String, SQLiteValue)>,
+    options: CallOptions,
+    deadline: Option<Instant>,
+) -> Result<usize> {
+    let params = named(params);
+    let input = ConnectionInput::Execute(query.into(), params, options);
+    do_conn(ctx, conn, input, deadline, execute_output)
+}
+
+pub fn execute_named_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    params: Vec<(String, SQLiteValue)>,
+    options: CallOptions,
+    then: impl FnOnce(Result<usize>) + Send + 'static,
+) {
+    let params = named(params);
+    let input = ConnectionInput::Execute(query.into(), params, options);
+    do_conn_then(ctx, conn, input, execute_output, then)
+}
+
+fn named(params: Vec<(String, SQLiteValue)>) -> Parameters {
+    let params = params
+        .into_iter()
+        .map(|(name, value)| (name.into_boxed_str(), value))
+        .collect();
+    Parameters::Named(params)
+}
+
+// one query with many parameter sets in a single call, the total changes and
+// the changes of every set
+pub fn execute_many(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    rows: Vec<Vec<SQLiteValue>>,
+    options: CallOptions,
+    deadline: Option<Instant>,
+) -> Result<(usize, Vec<usize>)> {
+    let rows = rows.into_iter().map(Vec::into_boxed_slice).collect();
+    let input = ConnectionInput::ExecuteMany(query.into(), rows, options);
+    do_conn(ctx, conn, input, deadline, execute_many_output)
+}
+
+pub fn execute_many_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    rows: Vec<Vec<SQLiteValue>>,
+    options: CallOptions,
+    then: impl FnOnce(Result<(usize, Vec<usize>)>) + Send + 'static,
+) {
+    let rows = rows.into_iter().map(Vec::into_boxed_slice).collect();
+    let input = ConnectionInput::ExecuteMany(query.into(), rows, options);
+    do_conn_then(ctx, conn, input, execute_many_output, then)
+}
+
+fn execute_many_output(output: ConnectionOutput) -> Result<(usize, Vec<usize>)> {
+    match output {
+        ConnectionOutput::ExecuteMany(rv) => rv.map(|changes| (changes.iter().sum(), changes)),
+        _ => Err(unexpected_answer()),
+    }
+}
+
+// every statement of sql in order, with the changes of each
+pub fn execute_script(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    sql: &str,
+    options: CallOptions,
+    deadline: Option<Instant>,
+) -> Result<Vec<u64>> {
+    let input = ConnectionInput::ExecuteScript(sql.into(), options);
+    do_conn(ctx, conn, input, deadline, execute_script_output)
+}
+
+pub fn execute_script_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    sql: &str,
+    options: CallOptions,
+    then: impl FnOnce(Result<Vec<u64>>) + Send + 'static,
+) {
+    let input = ConnectionInput::ExecuteScript(sql.into(), options);
+    do_conn_then(ctx, conn, input, execute_script_output, then)
+}
+
+fn execute_script_output(output: ConnectionOutput) -> Result<Vec<u64>> {
+    match output {
+        ConnectionOutput::ExecuteScript(rv) => rv,
+        _ => Err(unexpected_answer()),
+    }
+}
+
+// tell whether the first statement of query writes and which kind it is,
+// nothing is executed
+pub fn classify(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    deadline: Option<Instant>,
+) -> Result<Classification> {
+    let input = ConnectionInput::Classify(query.to_owned().into_boxed_str());
+    do_conn(ctx, conn, input, deadline, classify_output)
+}
+
+pub fn classify_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    query: &str,
+    then: impl FnOnce(Result<Classification>) + Send + 'static,
+) {
+    let input = ConnectionInput::Classify(query.to_owned().into_boxed_str());
+    do_conn_then(ctx, conn, input, classify_output, then)
+}
+
+fn classify_output(output: ConnectionOutput) -> Result<Classification> {
+    match output {
+        ConnectionOutput::Classify(res) => res,
+        _ => Err(unexpected_answer()),
+    }
+}
+
+// stop whatever runs on conn, the interrupted call fails with Interrupted
+pub fn interrupt(ctx: &Context, conn: &VirtualConnection) -> Result<()> {
+    check_connection_consistency(ctx, conn)?;
+    conn.state.interrupt.interrupt();
+    Ok(())
+}
+
+// interrupt conn only while the read only stmt is being stepped, the statement
+// is reset and the next step_by starts over, tell whether it was running. Only
+// the replica of the caller is interrupted, see erlang_project_api:cancel_on_leader
+pub fn cancel(ctx: &Context, conn: &VirtualConnection, stmt: &VirtualStatement) -> Result<bool> {
+    check_statement_consistency(ctx, conn, stmt)?;
+    if !stmt.state.running.load(Ordering::SeqCst) {
+        return Ok(false);
+    }
+    conn.state.interrupt.interrupt();
+    Ok(true)
+}
+
+// a handle to the same connection that leaves it open when dropped, e.g. for
+// whoever closes it when its owner is gone without keeping it alive
+pub fn weak_connection(ctx: &Context, conn: &VirtualConnection) -> Result<VirtualConnection> {
+    check_connection_consistency(ctx, conn)?;
+    Ok(VirtualConnection {
+        sender: conn.sender.clone(),
+        uuid: conn.uuid,
+        context: conn.context,
+        state: Arc::clone(&conn.state),
+        owned: false,
+    })
+}
+
+pub fn weak_statement(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+) -> Result<VirtualStatement> {
+    check_statement_consistency(ctx, conn, stmt)?;
+    Ok(VirtualStatement {
+        sender: stmt.sender.clone(),
+        connection: stmt.connection,
+        context: stmt.context,
+        state: Arc::clone(&stmt.state),
+        owned: false,
+    })
+}
+
+// then gets Ok on the worker once the connection is closed, however that
+// happens, or at once if it already is
+pub fn closed_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    then: impl FnOnce(Result<()>) + Send + 'static,
+) {
+    match check_connection_consistency(ctx, conn) {
+        Ok(()) => conn.state.closed.push(Box::new(move || then(Ok(())))),
+        Err(err) => then(Err(err)),
+    }
+}
+
+// same as closed_then, once the statement is finalized
+pub fn finalized_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    then: impl FnOnce(Result<()>) + Send + 'static,
+) {
+    match check_statement_consistency(ctx, conn, stmt) {
+        Ok(()) => stmt.state.closed.push(Box::new(move || then(Ok(())))),
+        Err(err) => then(Err(err)),
+    }
+}
+
+pub fn close(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    do_conn(ctx, conn, ConnectionInput::Close, deadline, close_output)
+}
+
+pub fn close_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    then: impl FnOnce(Result<()>) + Send + 'static,
+) {
+    do_conn_then(ctx, conn, ConnectionInput::Close, close_output, then)
+}
+
+fn close_output(output: ConnectionOutput) -> Result<()> {
+    match output {
+        ConnectionOutput::Done => Ok(()),
+        _ => Err(unexpected_answer()),
+    }
+}
+
+// what is done when the owner of conn goes away: the open transaction is
+// rolled back, the statements are finalized and conn is closed
+pub fn release(ctx: &Context, conn: &VirtualConnection, deadline: Option<Instant>) -> Result<()> {
+    do_conn(ctx, conn, ConnectionInput::Release, deadline, |tmp| match tmp {
+        ConnectionOutput::Release(rv) => rv,
+        _ => Err(unexpected_answer()),
+    })
+}
+
+fn transaction(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    op: TransactionOp,
+    apply_index: Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    do_conn(ctx, conn, ConnectionInput::Transaction(op, apply_index), deadline, |tmp| match tmp {
+        ConnectionOutput::Transaction(rv) => rv,
+        _ => Err(unexpected_answer()),
+    })
+}
+
+pub fn begin(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    mode: TransactionMode,
+    apply_index: Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    transaction(ctx, conn, TransactionOp::Begin(mode), apply_index, deadline)
+}
+
+pub fn commit(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    apply_index: Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    transaction(ctx, conn, TransactionOp::Commit, apply_index, deadline)
+}
+
+pub fn rollback(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    apply_index: Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    transaction(ctx, conn, TransactionOp::Rollback, apply_index, deadline)
+}
+
+pub fn savepoint(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    name: &str,
+    apply_index: Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    transaction(ctx, conn, TransactionOp::Savepoint(name.into()), apply_index, deadline)
+}
+
+// release is taken by the connection
+pub fn release_savepoint(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    name: &str,
+    apply_index: Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    transaction(ctx, conn, TransactionOp::Release(name.into()), apply_index, deadline)
+}
+
+pub fn rollback_to(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    name: &str,
+    apply_index: Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    transaction(ctx, conn, TransactionOp::RollbackTo(name.into()), apply_index, deadline)
+}
+
+pub fn in_transaction(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    deadline: Option<Instant>,
+) -> Result<bool> {
+    do_conn(ctx, conn, ConnectionInput::InTransaction, deadline, |tmp| match tmp {
+        ConnectionOutput::InTransaction(active) => Ok(active),
+        _ => Err(unexpected_answer()),
+    })
+}
+
+pub fn last_insert_rowid(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    deadline: Option<Instant>,
+) -> Result<i64> {
+    do_conn(ctx, conn, ConnectionInput::LastInsertRowId, deadline, last_insert_rowid_output)
+}
+
+pub fn last_insert_rowid_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    then: impl FnOnce(Result<i64>) + Send + 'static,
+) {
+    do_conn_then(ctx, conn, ConnectionInput::LastInsertRowId, last_insert_rowid_output, then)
+}
+
+fn last_insert_rowid_output(output: ConnectionOutput) -> Result<i64> {
+    match output {
+        ConnectionOutput::LastInsertRowid(id) => Ok(id),
+        _ => Err(unexpected_answer()),
+    }
+}
+
+pub fn changes(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    deadline: Option<Instant>,
+) -> Result<u64> {
+    do_conn(ctx, conn, ConnectionInput::Changes, deadline, changes_output)
+}
+
+pub fn changes_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    then: impl FnOnce(Result<u64>) + Send + 'static,
+) {
+    do_conn_then(ctx, conn, ConnectionInput::Changes, changes_output, then)
+}
+
+fn changes_output(output: ConnectionOutput) -> Result<u64> {
+    match output {
+        ConnectionOutput::Changes(changes) => changes,
+        _ => Err(unexpected_answer()),
+    }
+}
+
+fn do_stmt<T>(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    input: StmtInput,
+    deadline: Option<Instant>,
+    f: impl FnOnce(StmtOutput) -> Result<T>,
+) -> Result<T> {
+    check_statement_consistency(ctx, conn, stmt)?;
+    if let Some(err) = stmt.state.gone(&conn.state) {
+        return Err(err);
+    }
+    let deadline = resolve_deadline(ctx, deadline);
+    let (request, answer) = Request::with_deadline(input, deadline);
+    let id = request.id;
+    // do not unwrap or return error, a task that is gone drops the answer
+    let _ = stmt.sender.send_blocking(request);
+    let tmp = recv_answer(answer, deadline, || conn.state.running.interrupt(id, &conn.state.interrupt))
+        .map_err(|err| stmt.state.gone(&conn.state).unwrap_or(err))?;
+    statement_output(tmp).and_then(f)
+}
+
+fn do_stmt_then<T>(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    input: StmtInput,
+    f: impl FnOnce(StmtOutput) -> Result<T> + Send + 'static,
+    then: impl FnOnce(Result<T>) + Send + 'static,
+) {
+    if let Err(err) = check_statement_consistency(ctx, conn, stmt) {
+        return then(Err(err));
+    }
+    if let Some(err) = stmt.state.gone(&conn.state) {
+        return then(Err(err));
+    }
+    let (conn_state, stmt_state) = (conn.state.clone(), stmt.state.clone());
+    let request = Request::with_callback(input, move |rv| {
+        let rv = rv.map_err(|err| stmt_state.gone(&conn_state).unwrap_or(err));
+        then(rv.and_then(statement_output).and_then(f))
+    });
+    // a task that is gone drops the request, which calls then
+    let _ = stmt.sender.send_blocking(request);
+}
+
+fn statement_output(output: StmtOutput) -> Result<StmtOutput> {
+    match output {
+        StmtOutput::Error(err) => Err(err),
+        output => Ok(output),
+    }
+}
+
+pub fn bind(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    n: usize,
+    value: SQLiteValue,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    do_stmt(ctx, conn, stmt, StmtInput::Bind(n, value), deadline, bind_output)
+}
+
+pub fn bind_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    n: usize,
+    value: SQLiteValue,
+    then: impl FnOnce(Result<()>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::Bind(n, value), bind_output, then)
+}
+
+fn bind_output(output: StmtOutput) -> Result<()> {
+    match output {
+        StmtOutput::Bind(res) => res,
+        _ => Err(unexpected_answer()),
+    }
+}
+
+pub fn bind_named(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    name: &str,
+    value: SQLiteValue,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    do_stmt(ctx, conn, stmt, StmtInput::BindNamed(name.into(), value), deadline, bind_output)
+}
+
+pub fn bind_named_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    name: &str,
+    value: SQLiteValue,
+    then: impl FnOnce(Result<()>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::BindNamed(name.into(), value), bind_output, then)
+}
+
+// a value for every parameter of the statement, by position or by name
+pub fn bind_all(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    params: Parameters,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    do_stmt(ctx, conn, stmt, StmtInput::BindAll(params), deadline, bind_output)
+}
+
+pub fn bind_all_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    params: Parameters,
+    then: impl FnOnce(Result<()>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::BindAll(params), bind_output, then)
+}
+
+// with its prefix, None for a plain ? or an index out of range
+pub fn bind_parameter_name(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    n: usize,
+    deadline: Option<Instant>,
+) -> Result<Option<Box<str>>> {
+    do_stmt(
+        ctx,
+        conn,
         stmt,
-        StmtInput::Bind(n, value),
+        StmtInput::BindParameterName(n),
+        deadline,
         |tmp| match tmp {
-            StmtOutput::Bind(res) => res,
-            _ => unreachable!(),
+            StmtOutput::BindParameterName(res) => res,
+            _ => Err(unexpected_answer()),
+        },
+    )
+}
+
+pub fn bind_parameter_index(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    name: &str,
+    deadline: Option<Instant>,
+) -> Result<Option<usize>> {
+    do_stmt(
+        ctx,
+        conn,
+        stmt,
+        StmtInput::BindParameterIndex(name.into()),
+        deadline,
+        |tmp| match tmp {
+            StmtOutput::BindParameterIndex(res) => res,
+            _ => Err(unexpected_answer()),
         },
     )
 }
@@ -974,76 +2846,189 @@ pub fn column_names(
     ctx: &Context,
     conn: &VirtualConnection,
     stmt: &VirtualStatement,
+    deadline: Option<Instant>,
 ) -> Result<Arc<[Box<str>]>> {
-    do_stmt(ctx, conn, stmt, StmtInput::ColumnNames, |tmp| match tmp {
+    do_stmt(ctx, conn, stmt, StmtInput::ColumnNames, deadline, column_names_output)
+}
+
+pub fn column_names_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    then: impl FnOnce(Result<Arc<[Box<str>]>>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::ColumnNames, column_names_output, then)
+}
+
+fn column_names_output(output: StmtOutput) -> Result<Arc<[Box<str>]>> {
+    match output {
         StmtOutput::ColumnNames(res) => res,
-        _ => unreachable!(),
-    })
+        _ => Err(unexpected_answer()),
+    }
 }
 
 pub fn column_count(
     ctx: &Context,
     conn: &VirtualConnection,
     stmt: &VirtualStatement,
+    deadline: Option<Instant>,
 ) -> Result<usize> {
-    do_stmt(ctx, conn, stmt, StmtInput::ColumnCount, |tmp| match tmp {
+    do_stmt(ctx, conn, stmt, StmtInput::ColumnCount, deadline, column_count_output)
+}
+
+pub fn column_count_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    then: impl FnOnce(Result<usize>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::ColumnCount, column_count_output, then)
+}
+
+fn column_count_output(output: StmtOutput) -> Result<usize> {
+    match output {
         StmtOutput::ColumnCount(res) => res,
-        _ => unreachable!(),
-    })
+        _ => Err(unexpected_answer()),
+    }
+}
+
+pub fn column_metadata(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    deadline: Option<Instant>,
+) -> Result<Arc<[ColumnMetadata]>> {
+    do_stmt(ctx, conn, stmt, StmtInput::ColumnMetadata, deadline, column_metadata_output)
+}
+
+pub fn column_metadata_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    then: impl FnOnce(Result<Arc<[ColumnMetadata]>>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::ColumnMetadata, column_metadata_output, then)
+}
+
+fn column_metadata_output(output: StmtOutput) -> Result<Arc<[ColumnMetadata]>> {
+    match output {
+        StmtOutput::ColumnMetadata(res) => res,
+        _ => Err(unexpected_answer()),
+    }
 }
 
 pub fn clear_bindings(
     ctx: &Context,
     conn: &VirtualConnection,
     stmt: &VirtualStatement,
+    deadline: Option<Instant>,
 ) -> Result<bool> {
-    do_stmt(ctx, conn, stmt, StmtInput::ClearBindings, |tmp| match tmp {
+    do_stmt(ctx, conn, stmt, StmtInput::ClearBindings, deadline, clear_bindings_output)
+}
+
+pub fn clear_bindings_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    then: impl FnOnce(Result<bool>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::ClearBindings, clear_bindings_output, then)
+}
+
+fn clear_bindings_output(output: StmtOutput) -> Result<bool> {
+    match output {
         StmtOutput::ClearBindings(res) => res,
-        _ => unreachable!(),
-    })
+        _ => Err(unexpected_answer()),
+    }
 }
 
 pub fn clone_and_reset(
     ctx: &Context,
     conn: &VirtualConnection,
     stmt: &VirtualStatement,
+    deadline: Option<Instant>,
 ) -> Result<VirtualStatement> {
-    do_stmt(ctx, conn, stmt, StmtInput::CloneAndReset, |tmp| match tmp {
+    let (context, connection) = (ctx.uuid, conn.uuid);
+    do_stmt(ctx, conn, stmt, StmtInput::CloneAndReset, deadline, move |tmp| {
+        cloned(context, connection, tmp)
+    })
+}
+
+pub fn clone_and_reset_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    then: impl FnOnce(Result<VirtualStatement>) + Send + 'static,
+) {
+    let (context, connection) = (ctx.uuid, conn.uuid);
+    let f = move |tmp| cloned(context, connection, tmp);
+    do_stmt_then(ctx, conn, stmt, StmtInput::CloneAndReset, f, then)
+}
+
+fn cloned(context: Uuid, connection: Uuid, output: StmtOutput) -> Result<VirtualStatement> {
+    match output {
         StmtOutput::CloneAndReset(res) => {
-            let (sender, receiver) = res?;
+            let (sender, state) = res?;
             Ok(VirtualStatement {
                 sender,
-                receiver,
-                connection: conn.uuid,
-                context: ctx.uuid,
+                connection,
+                context,
+                state,
+                owned: true,
             })
         }
-        _ => unreachable!(),
-    })
+        _ => Err(unexpected_answer()),
+    }
 }
 
 pub fn bind_parameter_count(
     ctx: &Context,
     conn: &VirtualConnection,
     stmt: &VirtualStatement,
+    deadline: Option<Instant>,
 ) -> Result<usize> {
-    do_stmt(
-        ctx,
-        conn,
-        stmt,
-        StmtInput::BindParameterCount,
-        |tmp| match tmp {
-            StmtOutput::BindParameterCount(res) => res,
-            _ => unreachable!(),
-        },
-    )
+    do_stmt(ctx, conn, stmt, StmtInput::BindParameterCount, deadline, bind_parameter_count_output)
 }
 
-pub fn finalize(ctx: &Context, conn: &VirtualConnection, stmt: &VirtualStatement) -> Result<()> {
-    do_stmt(ctx, conn, stmt, StmtInput::Close, |tmp| match tmp {
+pub fn bind_parameter_count_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    then: impl FnOnce(Result<usize>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::BindParameterCount, bind_parameter_count_output, then)
+}
+
+fn bind_parameter_count_output(output: StmtOutput) -> Result<usize> {
+    match output {
+        StmtOutput::BindParameterCount(res) => res,
+        _ => Err(unexpected_answer()),
+    }
+}
+
+pub fn finalize(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    do_stmt(ctx, conn, stmt, StmtInput::Close, deadline, finalize_output)
+}
+
+pub fn finalize_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    then: impl FnOnce(Result<()>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::Close, finalize_output, then)
+}
+
+fn finalize_output(output: StmtOutput) -> Result<()> {
+    match output {
         StmtOutput::Done => Ok(()),
-        _ => unreachable!(),
-    })
+        _ => Err(unexpected_answer()),
+    }
 }
 
 pub fn step_by(
@@ -1051,58 +3036,89 @@ pub fn step_by(
     conn: &VirtualConnection,
     stmt: &VirtualStatement,
     n: usize,
+    options: CallOptions,
+    deadline: Option<Instant>,
 ) -> Result<Option<Vec<Vec<SQLiteValue>>>> {
-    do_stmt(ctx, conn, stmt, StmtInput::StepBy(n), |tmp| match tmp {
+    let input = StmtInput::StepBy(n, options);
+    do_stmt(ctx, conn, stmt, input, deadline, step_by_output)
+}
+
+pub fn step_by_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    n: usize,
+    options: CallOptions,
+    then: impl FnOnce(Result<Option<Vec<Vec<SQLiteValue>>>>) + Send + 'static,
+) {
+    let input = StmtInput::StepBy(n, options);
+    do_stmt_then(ctx, conn, stmt, input, step_by_output, then)
+}
+
+fn step_by_output(output: StmtOutput) -> Result<Option<Vec<Vec<SQLiteValue>>>> {
+    match output {
         StmtOutput::Done => Ok(None),
-        StmtOutput::Rows(rows) => rows.map(|x| Some(x.into())),
-        _ => unreachable!(),
-    })
+        StmtOutput::Rows(rows) => rows.map(Some),
+        _ => Err(unexpected_answer()),
+    }
 }
 
 pub fn database_list(
     ctx: &Context,
     connection: &VirtualConnection,
+    deadline: Option<Instant>,
 ) -> Result<Option<Vec<(usize, String, String)>>> {
-    let stmt = prepare(ctx, connection, "PRAGMA database_list")?;
-    Ok(step_all(ctx, connection, &stmt)?.map(|mut rows| {
-        rows.iter_mut()
-            .map(|row| {
-                let rusqlite::types::Value::Integer(seq) = row[0].0 else {
-                    panic!()
-                };
-                let rusqlite::types::Value::Text(ref mut db_name) = row[1].0 else {
-                    panic!()
-                };
-                let db_name = std::mem::take(db_name);
-                let rusqlite::types::Value::Text(ref mut path) = row[2].0 else {
-                    panic!()
-                };
-                let path = std::mem::take(path);
-                // convert path to PathBuf
-                let path = std::path::PathBuf::from(path);
-                let file_name = path.file_name().unwrap().to_str().unwrap();
-                let file_name = &file_name[..file_name.len() - 3];
-                let file_name = base64::engine::general_purpose::URL_SAFE_NO_PAD
-                    .decode(file_name.as_bytes())
-                    .unwrap();
-                let file_name = String::from_utf8_lossy(&file_name).to_string();
-                (seq as usize, db_name, file_name)
-            })
-            .collect::<Vec<_>>()
-    }))
+    let deadline = resolve_deadline(ctx, deadline);
+    let stmt = prepare(ctx, connection, "PRAGMA database_list", None, deadline)?;
+    // the rows come from sqlite, a surprise is an error and not a panic
+    let unexpected = || RusqliteError::CustomError("Unexpected database_list row".to_owned());
+    let Some(mut rows) = step_all(ctx, connection, &stmt, deadline)? else {
+        return Ok(None);
+    };
+    rows.iter_mut()
+        .map(|row| {
+            let [seq, db_name, path] = &mut row[..] else {
+                return Err(unexpected());
+            };
+            let rusqlite::types::Value::Integer(seq) = seq.0 else {
+                return Err(unexpected());
+            };
+            let rusqlite::types::Value::Text(ref mut db_name) = db_name.0 else {
+                return Err(unexpected());
+            };
+            let db_name = std::mem::take(db_name);
+            let rusqlite::types::Value::Text(ref mut path) = path.0 else {
+                return Err(unexpected());
+            };
+            let path = std::mem::take(path);
+            // convert path to PathBuf
+            let path = std::path::PathBuf::from(path);
+            let file_name = path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.strip_suffix(".db"))
+                .ok_or_else(unexpected)?;
+            let file_name = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(file_name.as_bytes())
+                .map_err(|_| unexpected())?;
+            let file_name = String::from_utf8_lossy(&file_name).to_string();
+            Ok((seq as usize, db_name, file_name))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
 }
 
 pub fn step_all(
     ctx: &Context,
     conn: &VirtualConnection,
     stmt: &VirtualStatement,
+    deadline: Option<Instant>,
 ) -> Result<Option<Vec<Vec<SQLiteValue>>>> {
+    // one deadline for all the steps
+    let deadline = resolve_deadline(ctx, deadline);
     let mut res = Vec::new();
-    loop {
-        match step_by(ctx, conn, stmt, 100)? {
-            Some(tmp) => res.extend(tmp),
-            None => break,
-        }
+    while let Some(tmp) = step_by(ctx, conn, stmt, 100, CallOptions::default(), deadline)? {
+        res.extend(tmp);
     }
     if res.is_empty() {
         Ok(None)
@@ -1116,9 +3132,138 @@ pub fn column_name(
     conn: &VirtualConnection,
     stmt: &VirtualStatement,
     n: usize,
+    deadline: Option<Instant>,
 ) -> Result<Box<str>> {
-    do_stmt(ctx, conn, stmt, StmtInput::ColumnName(n), |tmp| match tmp {
+    do_stmt(ctx, conn, stmt, StmtInput::ColumnName(n), deadline, column_name_output)
+}
+
+pub fn column_name_then(
+    ctx: &Context,
+    conn: &VirtualConnection,
+    stmt: &VirtualStatement,
+    n: usize,
+    then: impl FnOnce(Result<Box<str>>) + Send + 'static,
+) {
+    do_stmt_then(ctx, conn, stmt, StmtInput::ColumnName(n), column_name_output, then)
+}
+
+fn column_name_output(output: StmtOutput) -> Result<Box<str>> {
+    match output {
         StmtOutput::ColumnName(name) => name,
-        _ => unreachable!(),
-    })
+        _ => Err(unexpected_answer()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[Option<&str>]) -> Box<[Option<Box<str>>]> {
+        names.iter().map(|name| name.map(Box::from)).collect()
+    }
+
+    fn integer(value: i64) -> SQLiteValue {
+        SQLiteValue(rusqlite::types::Value::Integer(value))
+    }
+
+    fn resolved(values: &HashMap<usize, Rc<SQLiteValue>>, n: usize) -> Option<i64> {
+        match values.get(&n).map(|value| &value.0) {
+            Some(rusqlite::types::Value::Integer(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn positional_parameters() {
+        let params = Parameters::Positional(vec![integer(1), integer(2)].into());
+        let values = resolve_parameters(&names(&[None, None]), params).unwrap();
+        assert_eq!(resolved(&values, 1), Some(1));
+        assert_eq!(resolved(&values, 2), Some(2));
+    }
+
+    #[test]
+    fn named_parameters() {
+        let params = Parameters::Named(vec![(":b".into(), integer(2)), (":a".into(), integer(1))].into());
+        let values = resolve_parameters(&names(&[Some(":a"), Some(":b")]), params).unwrap();
+        assert_eq!(resolved(&values, 1), Some(1));
+        assert_eq!(resolved(&values, 2), Some(2));
+    }
+
+    #[test]
+    fn parameter_count_must_match() {
+        let params = Parameters::Positional(vec![integer(1)].into());
+        let Err(err) = resolve_parameters(&names(&[None, None]), params) else {
+            panic!("one value for two parameters");
+        };
+        assert_eq!(err.details().kind, "parameter");
+    }
+
+    #[test]
+    fn unknown_or_repeated_name() {
+        let params = Parameters::Named(vec![(":c".into(), integer(1))].into());
+        assert!(resolve_parameters(&names(&[Some(":a")]), params).is_err());
+        let params = Parameters::Named(vec![(":a".into(), integer(1)), (":a".into(), integer(2))].into());
+        assert!(resolve_parameters(&names(&[Some(":a"), Some(":b")]), params).is_err());
+    }
+
+    fn home() -> PathBuf {
+        std::env::temp_dir().join(Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn expired_request_does_not_run() {
+        let home = home();
+        let ctx = create_context(home.to_str().unwrap()).unwrap();
+        let conn = create_connection(&ctx, "bucket", "file", None).unwrap();
+        let query = "CREATE TABLE t(x)";
+        let late = execute(&ctx, &conn, query, vec![], CallOptions::default(), Some(Instant::now()));
+        assert!(matches!(late, Err(RusqliteError::Timeout)));
+        // answered after the worker dropped the expired one
+        execute(&ctx, &conn, query, vec![], CallOptions::default(), None).unwrap();
+        drop(conn);
+        let _ = std::fs::remove_dir_all(home);
+    }
+
+    #[test]
+    fn lease_runs_out_by_the_entries_of_other_calls() {
+        let home = home();
+        let ctx = create_context(home.to_str().unwrap()).unwrap();
+        set_transaction_lease(&ctx, None, Some(2));
+        let writer = create_connection(&ctx, "bucket", "file", None).unwrap();
+        let reader = create_connection(&ctx, "bucket", "file", None).unwrap();
+        let at = |index| CallOptions {
+            apply_index: Some(index),
+            ..CallOptions::default()
+        };
+        execute(&ctx, &writer, "CREATE TABLE t(x)", vec![], at(1), None).unwrap();
+        execute(&ctx, &writer, "INSERT INTO t VALUES (1), (2), (3)", vec![], at(2), None).unwrap();
+        let stmt = prepare(&ctx, &reader, "SELECT x FROM t", None, None).unwrap();
+        step_by(&ctx, &reader, &stmt, 1, at(3), None).unwrap();
+        begin(&ctx, &writer, TransactionMode::Deferred, Some(4), None).unwrap();
+        // the steps that continue the read count as entries too
+        step_by(&ctx, &reader, &stmt, 1, at(5), None).unwrap();
+        assert!(in_transaction(&ctx, &writer, None).unwrap());
+        step_by(&ctx, &reader, &stmt, 1, at(6), None).unwrap();
+        assert!(matches!(in_transaction(&ctx, &writer, None), Err(RusqliteError::LeaseExpired)));
+        drop((stmt, reader, writer));
+        let _ = std::fs::remove_dir_all(home);
+    }
+
+    #[test]
+    fn only_the_owned_handle_closes() {
+        let home = home();
+        let ctx = create_context(home.to_str().unwrap()).unwrap();
+        let conn = create_connection(&ctx, "bucket", "file", None).unwrap();
+        let stmt = prepare(&ctx, &conn, "SELECT 1", None, None).unwrap();
+        drop(weak_statement(&ctx, &conn, &stmt).unwrap());
+        drop(weak_connection(&ctx, &conn).unwrap());
+        assert_eq!(column_count(&ctx, &conn, &stmt, None).unwrap(), 1);
+        let weak_stmt = weak_statement(&ctx, &conn, &stmt).unwrap();
+        drop(stmt);
+        assert!(column_count(&ctx, &conn, &weak_stmt, None).is_err());
+        let weak_conn = weak_connection(&ctx, &conn).unwrap();
+        drop(conn);
+        assert!(changes(&ctx, &weak_conn, None).is_err());
+        let _ = std::fs::remove_dir_all(home);
+    }
 }
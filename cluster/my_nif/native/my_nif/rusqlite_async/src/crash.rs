@@ -0,0 +1,49 @@
+use crate::connection::Result;
+use crate::connection::RusqliteError;
+use std::any::Any;
+use std::future::Future;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::pin::pin;
+use std::task::Poll;
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panicked".to_owned()
+    }
+}
+
+// A panic in f is returned as WorkerCrashed instead of unwinding into the
+// caller, e.g. a nif or a thread that has to answer anyway.
+pub fn contain<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(RusqliteError::WorkerCrashed(panic_message(&*payload))))
+}
+
+// same for a task, the future is dropped after the panic and the message is
+// returned instead
+pub(crate) async fn contain_task<F: Future>(task: F) -> std::result::Result<F::Output, String> {
+    let mut task = pin!(task);
+    std::future::poll_fn(|cx| match catch_unwind(AssertUnwindSafe(|| task.as_mut().poll(cx))) {
+        Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+        Ok(Poll::Pending) => Poll::Pending,
+        Err(payload) => Poll::Ready(Err(panic_message(&*payload))),
+    })
+    .await
+}
+
+#[derive(Clone, Debug)]
+pub struct Health {
+    // the thread that opens the connections is running, see restart_context
+    pub context_alive: bool,
+    pub connections: usize,
+    pub statements: usize,
+    // panics caught in the workers since the context was created, the
+    // connection or statement that panicked fails its calls with WorkerCrashed
+    pub crashes: u64,
+    pub last_crash: Option<String>,
+}
@@ -0,0 +1,57 @@
+use crate::connection::Answer;
+use crate::connection::Result;
+use crate::connection::RusqliteError;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::task::Wake;
+use std::task::Waker;
+use std::thread::Thread;
+use std::time::Instant;
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+// the answer is polled on the calling thread, which parks until it is woken
+// or the deadline passes
+fn recv_until<T>(answer: Answer<T>, deadline: Option<Instant>) -> Result<T> {
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut recv = pin!(answer);
+    loop {
+        if let Poll::Ready(rv) = recv.as_mut().poll(&mut cx) {
+            // the worker dropped the request without answering, it is gone
+            return rv.map_err(|e| RusqliteError::CommunicationError(e.to_string()))?;
+        }
+        let Some(deadline) = deadline else {
+            std::thread::park();
+            continue;
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(RusqliteError::Timeout);
+        }
+        std::thread::park_timeout(deadline - now);
+    }
+}
+
+// Every call waits on its own answer, a call that timed out drops it and the
+// worker's late answer goes nowhere. A worker that gets to the request after
+// its deadline does not run it.
+pub(crate) fn recv_answer<T>(
+    answer: Answer<T>,
+    deadline: Option<Instant>,
+    on_timeout: impl FnOnce(),
+) -> Result<T> {
+    let rv = recv_until(answer, deadline);
+    if let Err(RusqliteError::Timeout) = rv {
+        on_timeout();
+    }
+    rv
+}
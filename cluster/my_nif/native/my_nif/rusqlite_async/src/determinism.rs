@@ -0,0 +1,313 @@
+use rusqlite::ffi;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+
+// seed and logical timestamp (unix epoch in milliseconds) shared by all the
+// replicas for a given call, e.g. derived from the raft index and term
+#[derive(Clone, Debug)]
+pub struct Determinism {
+    pub seed: i64,
+    pub timestamp: i64,
+    // reject the nondeterministic functions instead of overriding them
+    pub strict: bool,
+}
+
+struct State {
+    determinism: Determinism,
+    rng: u64,
+}
+
+// functions whose first argument (or second for strftime) is a time value
+const DATE_FUNCTIONS: [(&str, usize); 6] = [
+    ("date", 0),
+    ("time", 0),
+    ("datetime", 0),
+    ("julianday", 0),
+    ("unixepoch", 0),
+    ("strftime", 1),
+];
+
+const CURRENT_FUNCTIONS: [(&str, &str); 3] = [
+    ("current_timestamp", "datetime"),
+    ("current_date", "date"),
+    ("current_time", "time"),
+];
+
+// The overrides are installed on a connection the first time a call carries a
+// determinism context, without one they forward to the builtin implementation
+// evaluated on a private in memory connection.
+pub(crate) struct Overrides {
+    installed: Cell<bool>,
+    current: Arc<Mutex<Option<State>>>,
+}
+
+fn user_error(msg: String) -> rusqlite::Error {
+    rusqlite::Error::UserFunctionError(msg.into())
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn julian_day(timestamp: i64) -> f64 {
+    timestamp as f64 / 86_400_000.0 + 2_440_587.5
+}
+
+fn call_builtin(helper: &Mutex<Connection>, name: &str, args: &[Value]) -> rusqlite::Result<Value> {
+    let placeholders = (1..=args.len())
+        .map(|i| format!("?{}", i))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let helper = helper.lock().map_err(|_| user_error("poisoned helper".to_owned()))?;
+    let mut stmt = helper.prepare_cached(&format!("SELECT {}({})", name, placeholders))?;
+    stmt.query_row(rusqlite::params_from_iter(args), |row| row.get(0))
+}
+
+fn args_of(ctx: &rusqlite::functions::Context<'_>) -> rusqlite::Result<Vec<Value>> {
+    (0..ctx.len()).map(|i| ctx.get::<Value>(i)).collect()
+}
+
+fn is_now(value: &Value) -> bool {
+    matches!(value, Value::Text(s) if s.eq_ignore_ascii_case("now"))
+}
+
+fn is_localtime(value: &Value) -> bool {
+    matches!(value, Value::Text(s) if s.eq_ignore_ascii_case("localtime"))
+}
+
+impl Overrides {
+    pub(crate) fn new() -> Self {
+        Overrides {
+            installed: Cell::new(false),
+            current: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // make determinism the context of the next evaluation on conn, every call
+    // that evaluates sql sets it so that a stale context is never used
+    pub(crate) fn set(
+        &self,
+        conn: &Connection,
+        determinism: Option<&Determinism>,
+    ) -> rusqlite::Result<()> {
+        if determinism.is_some() && !self.installed.get() {
+            self.install(conn)?;
+            self.installed.set(true);
+        }
+        if !self.installed.get() {
+            return Ok(());
+        }
+        let mut current = self
+            .current
+            .lock()
+            .map_err(|_| user_error("poisoned determinism context".to_owned()))?;
+        *current = determinism.map(|determinism| State {
+            determinism: determinism.clone(),
+            rng: determinism.seed as u64,
+        });
+        Ok(())
+    }
+
+    // The date functions are deterministic for a given timestamp, like the
+    // builtins they can be used in check constraints, generated columns and
+    // indexes. random and randomblob are not flagged: sqlite would evaluate a
+    // deterministic call once per statement and repeat it on every row.
+    fn install(&self, conn: &Connection) -> rusqlite::Result<()> {
+        let helper = Arc::new(Mutex::new(Connection::open_in_memory()?));
+        // randomblob is bounded like the builtin
+        let max_length = unsafe { ffi::sqlite3_limit(conn.handle(), ffi::SQLITE_LIMIT_LENGTH, -1) };
+
+        let (current, builtin) = (Arc::clone(&self.current), Arc::clone(&helper));
+        conn.create_scalar_function("random", 0, FunctionFlags::SQLITE_UTF8, move |_| {
+            let mut current = current.lock().map_err(|_| user_error("poisoned".to_owned()))?;
+            match current.as_mut() {
+                None => call_builtin(&builtin, "random", &[]),
+                Some(state) if state.determinism.strict => {
+                    Err(user_error("random() is rejected in strict mode".to_owned()))
+                }
+                Some(state) => Ok(Value::Integer(splitmix64(&mut state.rng) as i64)),
+            }
+        })?;
+
+        let (current, builtin) = (Arc::clone(&self.current), Arc::clone(&helper));
+        conn.create_scalar_function("randomblob", 1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+            let mut current = current.lock().map_err(|_| user_error("poisoned".to_owned()))?;
+            match current.as_mut() {
+                None => call_builtin(&builtin, "randomblob", &args_of(ctx)?),
+                Some(state) if state.determinism.strict => {
+                    Err(user_error("randomblob() is rejected in strict mode".to_owned()))
+                }
+                Some(state) => {
+                    let n = ctx.get::<i64>(0).unwrap_or(1).max(1);
+                    if n > i64::from(max_length) {
+                        // without a message, a message would replace the code
+                        return Err(rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_TOOBIG), None));
+                    }
+                    let n = n as usize;
+                    let mut blob = Vec::with_capacity(n + 8);
+                    while blob.len() < n {
+                        blob.extend_from_slice(&splitmix64(&mut state.rng).to_le_bytes());
+                    }
+                    blob.truncate(n);
+                    Ok(Value::Blob(blob))
+                }
+            }
+        })?;
+
+        for (name, position) in DATE_FUNCTIONS {
+            let (current, builtin) = (Arc::clone(&self.current), Arc::clone(&helper));
+            let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+            conn.create_scalar_function(name, -1, flags, move |ctx| {
+                let mut args = args_of(ctx)?;
+                let current = current.lock().map_err(|_| user_error("poisoned".to_owned()))?;
+                let Some(state) = current.as_ref() else {
+                    return call_builtin(&builtin, name, &args);
+                };
+                let uses_now = args.get(position).is_none_or(is_now);
+                let uses_localtime = args.iter().skip(position + 1).any(is_localtime);
+                if state.determinism.strict && (uses_now || uses_localtime) {
+                    return Err(user_error(format!(
+                        "{}() with the current time is rejected in strict mode",
+                        name
+                    )));
+                }
+                if uses_now {
+                    // strftime requires its format, the builtin reports the error
+                    if args.len() >= position {
+                        let now = Value::Real(julian_day(state.determinism.timestamp));
+                        match args.get_mut(position) {
+                            Some(arg) => *arg = now,
+                            None => args.push(now),
+                        }
+                    }
+                }
+                call_builtin(&builtin, name, &args)
+            })?;
+        }
+
+        for (name, equivalent) in CURRENT_FUNCTIONS {
+            let (current, builtin) = (Arc::clone(&self.current), Arc::clone(&helper));
+            conn.create_scalar_function(name, 0, FunctionFlags::SQLITE_UTF8, move |_| {
+                let current = current.lock().map_err(|_| user_error("poisoned".to_owned()))?;
+                match current.as_ref() {
+                    None => call_builtin(&builtin, equivalent, &[Value::Text("now".to_owned())]),
+                    Some(state) if state.determinism.strict => Err(user_error(format!(
+                        "{} is rejected in strict mode",
+                        name
+                    ))),
+                    Some(state) => call_builtin(
+                        &builtin,
+                        equivalent,
+                        &[Value::Real(julian_day(state.determinism.timestamp))],
+                    ),
+                }
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-11-14 22:13:20 UTC
+    const TIMESTAMP: i64 = 1_700_000_000_000;
+
+    fn connection(seed: i64) -> (Connection, Overrides) {
+        let conn = Connection::open_in_memory().unwrap();
+        let overrides = Overrides::new();
+        let determinism = Determinism {
+            seed,
+            timestamp: TIMESTAMP,
+            strict: false,
+        };
+        overrides.set(&conn, Some(&determinism)).unwrap();
+        (conn, overrides)
+    }
+
+    fn randoms(conn: &Connection) -> Vec<i64> {
+        let sql = "WITH RECURSIVE r(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM r WHERE i < 4) SELECT random() FROM r";
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn seeded_random_repeats() {
+        let (a, _a) = connection(42);
+        let (b, _b) = connection(42);
+        let (c, _c) = connection(43);
+        let first = randoms(&a);
+        assert_eq!(first, randoms(&b));
+        assert_ne!(first, randoms(&c));
+        // a new value on every row
+        assert_eq!(first.iter().collect::<std::collections::HashSet<_>>().len(), first.len());
+    }
+
+    #[test]
+    fn now_is_the_timestamp() {
+        let (conn, _overrides) = connection(0);
+        let now: String = conn.query_row("SELECT datetime('now')", [], |row| row.get(0)).unwrap();
+        assert_eq!(now, "2023-11-14 22:13:20");
+        let now: String = conn.query_row("SELECT current_date", [], |row| row.get(0)).unwrap();
+        assert_eq!(now, "2023-11-14");
+        let epoch: i64 = conn.query_row("SELECT unixepoch()", [], |row| row.get(0)).unwrap();
+        assert_eq!(epoch, TIMESTAMP / 1000);
+    }
+
+    #[test]
+    fn without_context_the_builtins_run() {
+        let (conn, overrides) = connection(0);
+        overrides.set(&conn, None).unwrap();
+        let now: String = conn.query_row("SELECT datetime('now')", [], |row| row.get(0)).unwrap();
+        assert_ne!(now, "2023-11-14 22:13:20");
+    }
+
+    #[test]
+    fn date_functions_in_constraints() {
+        let (conn, _overrides) = connection(0);
+        conn.execute_batch(
+            "CREATE TABLE t(d TEXT CHECK (date(d) IS NOT NULL), y AS (strftime('%Y', d)));
+             CREATE INDEX t_day ON t(julianday(d));
+             INSERT INTO t(d) VALUES ('2024-02-29 10:00:00');",
+        )
+        .unwrap();
+        let year: String = conn.query_row("SELECT y FROM t", [], |row| row.get(0)).unwrap();
+        assert_eq!(year, "2024");
+    }
+
+    #[test]
+    fn randomblob_is_bounded() {
+        let (conn, _overrides) = connection(0);
+        let blob: Vec<u8> = conn.query_row("SELECT randomblob(3)", [], |row| row.get(0)).unwrap();
+        assert_eq!(blob.len(), 3);
+        let err = conn
+            .query_row("SELECT randomblob(1 << 40)", [], |row| row.get::<_, Vec<u8>>(0))
+            .unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(rusqlite::ErrorCode::TooBig));
+    }
+
+    #[test]
+    fn strict_rejects_now() {
+        let conn = Connection::open_in_memory().unwrap();
+        let overrides = Overrides::new();
+        let determinism = Determinism {
+            seed: 0,
+            timestamp: TIMESTAMP,
+            strict: true,
+        };
+        overrides.set(&conn, Some(&determinism)).unwrap();
+        assert!(conn.query_row("SELECT datetime('now')", [], |row| row.get::<_, String>(0)).is_err());
+        assert!(conn.query_row("SELECT random()", [], |row| row.get::<_, i64>(0)).is_err());
+        let day: String = conn.query_row("SELECT date('2024-01-02 03:04')", [], |row| row.get(0)).unwrap();
+        assert_eq!(day, "2024-01-02");
+    }
+}
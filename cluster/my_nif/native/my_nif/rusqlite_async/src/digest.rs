@@ -0,0 +1,145 @@
+use crate::apply_index::META_TABLE;
+use base64::Engine as _;
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use sha2::Digest as _;
+use sha2::Sha256;
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableDigest {
+    pub name: String,
+    pub digest: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileDigest {
+    pub bucket: String,
+    pub file: String,
+    pub schema: String,
+    pub tables: Vec<TableDigest>,
+    pub digest: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateDigest {
+    pub digest: String,
+    pub files: Vec<FileDigest>,
+}
+
+fn encode(hasher: Sha256) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+// length prefixed so that ("ab", "c") and ("a", "bc") differ
+fn update_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+fn update_value(hasher: &mut Sha256, value: ValueRef<'_>) {
+    match value {
+        ValueRef::Null => hasher.update([0]),
+        ValueRef::Integer(i) => {
+            hasher.update([1]);
+            hasher.update(i.to_le_bytes());
+        }
+        ValueRef::Real(f) => {
+            hasher.update([2]);
+            hasher.update(f.to_bits().to_le_bytes());
+        }
+        ValueRef::Text(t) => {
+            hasher.update([3]);
+            update_bytes(hasher, t);
+        }
+        ValueRef::Blob(b) => {
+            hasher.update([4]);
+            update_bytes(hasher, b);
+        }
+    }
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn table_digest(conn: &Connection, name: &str) -> rusqlite::Result<String> {
+    let table = quote_identifier(name);
+    // WITHOUT ROWID tables have no rowid but are scanned in primary key order
+    let mut stmt = conn
+        .prepare(&format!("SELECT * FROM {} ORDER BY rowid", table))
+        .or_else(|_| conn.prepare(&format!("SELECT * FROM {}", table)))?;
+    let ncols = stmt.column_count();
+    let mut hasher = Sha256::new();
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        hasher.update((ncols as u64).to_le_bytes());
+        for i in 0..ncols {
+            update_value(&mut hasher, row.get_ref(i)?);
+        }
+    }
+    Ok(encode(hasher))
+}
+
+pub(crate) fn file_digest(bucket: &str, file: &str, path: &Path) -> rusqlite::Result<FileDigest> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    // a single read transaction so that schema and tables are consistent
+    conn.execute_batch("BEGIN")?;
+
+    let mut schema = Sha256::new();
+    let mut tables = Vec::new();
+    {
+        // the apply index table is bookkeeping, not the user's data
+        let mut stmt = conn.prepare(
+            "SELECT type, name, tbl_name, sql FROM sqlite_master WHERE tbl_name <> ?1 \
+             ORDER BY type, name",
+        )?;
+        let mut rows = stmt.query([META_TABLE])?;
+        while let Some(row) = rows.next()? {
+            for i in 0..4 {
+                update_value(&mut schema, row.get_ref(i)?);
+            }
+            let kind: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            if kind == "table" && !name.starts_with("sqlite_stat") {
+                tables.push(name);
+            }
+        }
+    }
+    let schema = encode(schema);
+
+    let mut hasher = Sha256::new();
+    update_bytes(&mut hasher, schema.as_bytes());
+    let tables = tables
+        .into_iter()
+        .map(|name| {
+            let digest = table_digest(&conn, &name)?;
+            update_bytes(&mut hasher, name.as_bytes());
+            update_bytes(&mut hasher, digest.as_bytes());
+            Ok(TableDigest { name, digest })
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    conn.execute_batch("COMMIT")?;
+
+    Ok(FileDigest {
+        bucket: bucket.to_owned(),
+        file: file.to_owned(),
+        schema,
+        tables,
+        digest: encode(hasher),
+    })
+}
+
+pub(crate) fn state_digest(files: Vec<FileDigest>) -> StateDigest {
+    let mut hasher = Sha256::new();
+    for file in &files {
+        update_bytes(&mut hasher, file.bucket.as_bytes());
+        update_bytes(&mut hasher, file.file.as_bytes());
+        update_bytes(&mut hasher, file.digest.as_bytes());
+    }
+    StateDigest {
+        digest: encode(hasher),
+        files,
+    }
+}
@@ -0,0 +1,221 @@
+use crate::connection::RusqliteError;
+use rusqlite::ffi;
+
+// What a caller needs to tell errors apart without parsing the message. The
+// kind is the name of the primary sqlite result code when there is one.
+#[derive(Clone, Debug)]
+pub struct ErrorDetails {
+    pub kind: &'static str,
+    pub code: Option<i32>,
+    pub extended_code: Option<i32>,
+    pub message: String,
+    // byte offset in the sql of the token the error is about
+    pub offset: Option<usize>,
+    // index of the parameter that could not be bound
+    pub parameter: Option<usize>,
+    // index and byte offset of the statement of a script that failed, the
+    // offset of the token is from the start of the script then
+    pub statement: Option<usize>,
+    pub statement_offset: Option<usize>,
+    // index of the parameter set of an execute_many that failed
+    pub row: Option<usize>,
+}
+
+fn primary_kind(code: i32) -> &'static str {
+    match code {
+        ffi::SQLITE_ERROR => "error",
+        ffi::SQLITE_INTERNAL => "internal",
+        ffi::SQLITE_PERM => "perm",
+        ffi::SQLITE_ABORT => "abort",
+        ffi::SQLITE_BUSY => "busy",
+        ffi::SQLITE_LOCKED => "locked",
+        ffi::SQLITE_NOMEM => "nomem",
+        ffi::SQLITE_READONLY => "readonly",
+        ffi::SQLITE_INTERRUPT => "interrupted",
+        ffi::SQLITE_IOERR => "ioerr",
+        ffi::SQLITE_CORRUPT => "corrupt",
+        ffi::SQLITE_NOTFOUND => "notfound",
+        ffi::SQLITE_FULL => "full",
+        ffi::SQLITE_CANTOPEN => "cantopen",
+        ffi::SQLITE_PROTOCOL => "protocol",
+        ffi::SQLITE_EMPTY => "empty",
+        ffi::SQLITE_SCHEMA => "schema",
+        ffi::SQLITE_TOOBIG => "toobig",
+        ffi::SQLITE_CONSTRAINT => "constraint",
+        ffi::SQLITE_MISMATCH => "mismatch",
+        ffi::SQLITE_MISUSE => "misuse",
+        ffi::SQLITE_NOLFS => "nolfs",
+        ffi::SQLITE_AUTH => "auth",
+        ffi::SQLITE_FORMAT => "format",
+        ffi::SQLITE_RANGE => "range",
+        ffi::SQLITE_NOTADB => "notadb",
+        ffi::SQLITE_NOTICE => "notice",
+        ffi::SQLITE_WARNING => "warning",
+        _ => "error",
+    }
+}
+
+fn sqlite(error: &ffi::Error, message: String) -> ErrorDetails {
+    let extended_code = error.extended_code;
+    let code = extended_code & 0xff;
+    ErrorDetails {
+        kind: primary_kind(code),
+        code: Some(code),
+        extended_code: Some(extended_code),
+        message,
+        offset: None,
+        parameter: None,
+        statement: None,
+        statement_offset: None,
+        row: None,
+    }
+}
+
+fn other(kind: &'static str, message: String) -> ErrorDetails {
+    ErrorDetails {
+        kind,
+        code: None,
+        extended_code: None,
+        message,
+        offset: None,
+        parameter: None,
+        statement: None,
+        statement_offset: None,
+        row: None,
+    }
+}
+
+fn rusqlite_details(error: &rusqlite::Error) -> ErrorDetails {
+    use rusqlite::Error;
+    match error {
+        Error::SqliteFailure(e, message) => {
+            sqlite(e, message.clone().unwrap_or_else(|| e.to_string()))
+        }
+        Error::SqlInputError {
+            error, msg, offset, ..
+        } => ErrorDetails {
+            // sqlite reports -1 when the error is not about a token
+            offset: usize::try_from(*offset).ok(),
+            ..sqlite(error, msg.clone())
+        },
+        Error::InvalidParameterName(_) | Error::InvalidParameterCount(_, _) => {
+            other("parameter", error.to_string())
+        }
+        Error::InvalidColumnIndex(_)
+        | Error::InvalidColumnName(_)
+        | Error::InvalidColumnType(_, _, _)
+        | Error::FromSqlConversionFailure(_, _, _) => other("column", error.to_string()),
+        Error::ToSqlConversionFailure(_)
+        | Error::IntegralValueOutOfRange(_, _)
+        | Error::Utf8Error(_)
+        | Error::NulError(_) => other("conversion", error.to_string()),
+        Error::ExecuteReturnedResults
+        | Error::QueryReturnedNoRows
+        | Error::MultipleStatement
+        | Error::InvalidQuery
+        | Error::StatementChangedRows(_) => other("misuse", error.to_string()),
+        _ => other("internal", error.to_string()),
+    }
+}
+
+impl RusqliteError {
+    pub fn details(&self) -> ErrorDetails {
+        match self {
+            RusqliteError::RusqliteError(e) => rusqlite_details(e),
+            RusqliteError::Bind(n, e) => ErrorDetails {
+                parameter: Some(*n),
+                ..rusqlite_details(e)
+            },
+            RusqliteError::Script(index, offset, e) => {
+                let details = rusqlite_details(e);
+                ErrorDetails {
+                    offset: details.offset.map(|token| offset + token),
+                    statement: Some(*index),
+                    statement_offset: Some(*offset),
+                    ..details
+                }
+            }
+            RusqliteError::Row(row, e) => ErrorDetails {
+                row: Some(*row),
+                ..rusqlite_details(e)
+            },
+            RusqliteError::CommunicationError(s) | RusqliteError::CustomError(s) => {
+                other("internal", s.clone())
+            }
+            RusqliteError::IoError(e) => other("io", e.to_string()),
+            RusqliteError::Interrupted => other("interrupted", self.to_string()),
+            RusqliteError::BudgetExceeded(_) => other("budget_exceeded", self.to_string()),
+            RusqliteError::Timeout => other("timeout", self.to_string()),
+            RusqliteError::Expired => other("expired", self.to_string()),
+            RusqliteError::LeaseExpired => other("lease_expired", self.to_string()),
+            RusqliteError::WorkerCrashed(_) => other("worker_crashed", self.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax_error(offset: i32) -> rusqlite::Error {
+        rusqlite::Error::SqlInputError {
+            error: ffi::Error::new(ffi::SQLITE_ERROR),
+            msg: "near \"SELEC\": syntax error".to_owned(),
+            sql: "SELEC 1".to_owned(),
+            offset,
+        }
+    }
+
+    #[test]
+    fn kind_is_the_primary_code() {
+        let error = ffi::Error::new(ffi::SQLITE_CONSTRAINT_UNIQUE);
+        let details = RusqliteError::from(rusqlite::Error::SqliteFailure(error, None)).details();
+        assert_eq!(details.kind, "constraint");
+        assert_eq!(details.code, Some(ffi::SQLITE_CONSTRAINT));
+        assert_eq!(details.extended_code, Some(ffi::SQLITE_CONSTRAINT_UNIQUE));
+    }
+
+    #[test]
+    fn offset_of_the_token() {
+        let details = RusqliteError::from(syntax_error(0)).details();
+        assert_eq!(details.kind, "error");
+        assert_eq!(details.offset, Some(0));
+        let details = RusqliteError::from(syntax_error(-1)).details();
+        assert_eq!(details.offset, None);
+    }
+
+    #[test]
+    fn offset_in_a_script() {
+        let details = RusqliteError::Script(2, 10, syntax_error(3)).details();
+        assert_eq!(details.statement, Some(2));
+        assert_eq!(details.statement_offset, Some(10));
+        assert_eq!(details.offset, Some(13));
+    }
+
+    #[test]
+    fn parameter_and_row() {
+        let error = rusqlite::Error::InvalidParameterName(":id".to_owned());
+        let details = RusqliteError::Bind(3, error).details();
+        assert_eq!(details.kind, "parameter");
+        assert_eq!(details.parameter, Some(3));
+        let error = rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_BUSY), None);
+        let details = RusqliteError::Row(4, error).details();
+        assert_eq!(details.kind, "busy");
+        assert_eq!(details.row, Some(4));
+    }
+
+    #[test]
+    fn errors_without_a_code() {
+        for (error, kind) in [
+            (RusqliteError::Timeout, "timeout"),
+            (RusqliteError::Expired, "expired"),
+            (RusqliteError::LeaseExpired, "lease_expired"),
+            (RusqliteError::BudgetExceeded(10), "budget_exceeded"),
+            (RusqliteError::WorkerCrashed("panic".to_owned()), "worker_crashed"),
+        ] {
+            let details = error.details();
+            assert_eq!(details.kind, kind);
+            assert_eq!(details.code, None);
+        }
+    }
+}
@@ -1,17 +1,24 @@
 #![feature(try_trait_v2)]
-#![feature(fmt_internals)]
 
+pub mod apply_index;
+pub mod budget;
+pub mod bulk;
+pub mod classification;
+pub mod column_metadata;
 pub mod connection;
+pub mod crash;
+pub mod deadline;
+pub mod determinism;
+pub mod digest;
+pub mod error_details;
+pub mod recovery;
+pub mod registry;
+pub mod script;
+pub mod transaction;
 use std::collections::HashMap;
-use std::fmt::Formatter;
-use std::time::Duration;
 
 use base64::Engine as _;
-use connection::Result;
 use connection::*;
-use rustler::resource;
-use rustler::resource::ResourceType;
-use rustler::resource::ResourceTypeProvider;
 use rustler::Decoder;
 use rustler::Encoder;
 use rustler::Env;
@@ -79,31 +86,247 @@ impl<'a> Decoder<'a> for SQLiteValue {
     }
 }
 
-impl Encoder for RusqliteError {
-    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
-        let mut s = String::new();
-        let mut fmt = Formatter::new(&mut s);
-        match self {
-            RusqliteError::RusqliteError(error) => {
-                write!(fmt, "{}", "rusqlite: ").unwrap();
-                std::fmt::Display::fmt(&error, &mut fmt).unwrap();
-            }
-            RusqliteError::CommunicationError(error) => {
-                write!(fmt, "{}", "internal: ").unwrap();
-                std::fmt::Display::fmt(&error, &mut fmt).unwrap();
-            }
+// a list of values or a map from the parameter names
+impl<'a> Decoder<'a> for Parameters {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        if term.is_map() {
+            let values: HashMap<String, SQLiteValue> = term.decode()?;
+            Ok(Parameters::Named(
+                values
+                    .into_iter()
+                    .map(|(name, value)| (name.into_boxed_str(), value))
+                    .collect(),
+            ))
+        } else {
+            let values: Vec<SQLiteValue> = term.decode()?;
+            Ok(Parameters::Positional(values.into_boxed_slice()))
+        }
+    }
+}
 
-            RusqliteError::CustomError(error) => {
-                write!(fmt, "{}", "internal: ").unwrap();
-                std::fmt::Display::fmt(&error, &mut fmt).unwrap();
-            }
+impl<'a> Decoder<'a> for Determinism {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        let (seed, timestamp, strict): (i64, i64, bool) = term.decode()?;
+        Ok(Self {
+            seed,
+            timestamp,
+            strict,
+        })
+    }
+}
 
-            RusqliteError::IoError(error) => {
-                write!(fmt, "{}", "io: ").unwrap();
-                std::fmt::Display::fmt(&error, &mut fmt).unwrap();
-            }
+impl<'a> Decoder<'a> for TransactionMode {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        match term.atom_to_string()?.as_str() {
+            "deferred" => Ok(TransactionMode::Deferred),
+            "immediate" => Ok(TransactionMode::Immediate),
+            "exclusive" => Ok(TransactionMode::Exclusive),
+            _ => Err(rustler::Error::BadArg),
+        }
+    }
+}
+
+impl<'a> Decoder<'a> for OpenOptions {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        let (create, check): (bool, Term<'a>) = term.decode()?;
+        let integrity_check = match check.atom_to_string()?.as_str() {
+            "skip" => IntegrityCheck::Skip,
+            "quick" => IntegrityCheck::Quick,
+            "full" => IntegrityCheck::Full,
+            _ => return Err(rustler::Error::BadArg),
         };
-        s.encode(env)
+        Ok(Self {
+            create,
+            integrity_check,
+        })
+    }
+}
+
+fn atom<'a>(env: Env<'a>, name: &str) -> Term<'a> {
+    rustler::types::atom::Atom::from_str(env, name)
+        .unwrap_or_else(|_| panic!("invalid atom {}", name))
+        .encode(env)
+}
+
+// erlang map with atom keys
+fn map<'a>(env: Env<'a>, pairs: &[(&str, Term<'a>)]) -> Term<'a> {
+    pairs.iter().fold(Term::map_new(env), |map, &(key, value)| {
+        map.map_put(atom(env, key), value)
+            .unwrap_or_else(|_| panic!("duplicate key {}", key))
+    })
+}
+
+impl Encoder for Classification {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        map(
+            env,
+            &[
+                ("kind", atom(env, self.kind.as_str())),
+                ("read_only", self.read_only.encode(env)),
+            ],
+        )
+    }
+}
+
+impl Encoder for TableDigest {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        map(
+            env,
+            &[
+                ("name", self.name.encode(env)),
+                ("digest", self.digest.encode(env)),
+            ],
+        )
+    }
+}
+
+impl Encoder for FileDigest {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        map(
+            env,
+            &[
+                ("bucket", self.bucket.encode(env)),
+                ("file", self.file.encode(env)),
+                ("schema", self.schema.encode(env)),
+                ("tables", self.tables.encode(env)),
+                ("digest", self.digest.encode(env)),
+            ],
+        )
+    }
+}
+
+impl Encoder for StateDigest {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        map(
+            env,
+            &[
+                ("digest", self.digest.encode(env)),
+                ("files", self.files.encode(env)),
+            ],
+        )
+    }
+}
+
+impl Encoder for FileReport {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        map(
+            env,
+            &[
+                ("bucket", self.bucket.encode(env)),
+                ("file", self.file.encode(env)),
+                ("size", self.size.encode(env)),
+                ("hot_journal", self.hot_journal.encode(env)),
+                ("wal", self.wal.encode(env)),
+                ("integrity", self.integrity.encode(env)),
+                ("apply_index", self.apply_index.encode(env)),
+            ],
+        )
+    }
+}
+
+impl Encoder for Inventory {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        map(env, &[("files", self.files.encode(env))])
+    }
+}
+
+fn unix_millis(time: &std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+impl Encoder for ConnectionInfo {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        map(
+            env,
+            &[
+                ("id", self.id.to_string().encode(env)),
+                ("bucket", self.bucket.encode(env)),
+                ("file", self.file.encode(env)),
+                ("opened", unix_millis(&self.opened).encode(env)),
+                ("last_activity", unix_millis(&self.last_activity).encode(env)),
+                ("closed", self.closed.encode(env)),
+            ],
+        )
+    }
+}
+
+impl Encoder for Health {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let mut pairs = vec![
+            ("context_alive", self.context_alive.encode(env)),
+            ("connections", self.connections.encode(env)),
+            ("statements", self.statements.encode(env)),
+            ("crashes", self.crashes.encode(env)),
+        ];
+        if let Some(ref last_crash) = self.last_crash {
+            pairs.push(("last_crash", last_crash.encode(env)));
+        }
+        map(env, &pairs)
+    }
+}
+
+// the origin and the flags are left out when the column has none
+impl Encoder for ColumnMetadata {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let mut pairs = vec![("name", self.name.encode(env))];
+        let optional = [
+            ("decl_type", self.decl_type.as_ref().map(|v| v.encode(env))),
+            ("database", self.database.as_ref().map(|v| v.encode(env))),
+            ("table", self.table.as_ref().map(|v| v.encode(env))),
+            ("column", self.column.as_ref().map(|v| v.encode(env))),
+            ("not_null", self.not_null.map(|v| v.encode(env))),
+            ("primary_key", self.primary_key.map(|v| v.encode(env))),
+            ("autoincrement", self.autoincrement.map(|v| v.encode(env))),
+        ];
+        pairs.extend(
+            optional
+                .into_iter()
+                .filter_map(|(key, value)| value.map(|value| (key, value))),
+        );
+        map(env, &pairs)
+    }
+}
+
+impl Encoder for StatementInfo {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        map(
+            env,
+            &[
+                ("id", self.id.to_string().encode(env)),
+                ("query", self.query.encode(env)),
+                ("bound_values", self.bound_values.encode(env)),
+                ("rows_stepped", self.rows_stepped.encode(env)),
+                ("mid_step", self.mid_step.encode(env)),
+            ],
+        )
+    }
+}
+
+// #{kind, message} and, when known, code, extended_code, offset and parameter,
+// missing fields are left out instead of being nil
+impl Encoder for RusqliteError {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let details = self.details();
+        let mut pairs = vec![
+            ("kind", atom(env, details.kind)),
+            ("message", details.message.encode(env)),
+        ];
+        let optional = [
+            ("code", details.code.map(|code| code.encode(env))),
+            ("extended_code", details.extended_code.map(|code| code.encode(env))),
+            ("offset", details.offset.map(|offset| offset.encode(env))),
+            ("parameter", details.parameter.map(|n| n.encode(env))),
+            ("statement", details.statement.map(|n| n.encode(env))),
+            ("statement_offset", details.statement_offset.map(|offset| offset.encode(env))),
+            ("row", details.row.map(|row| row.encode(env))),
+        ];
+        pairs.extend(
+            optional
+                .into_iter()
+                .filter_map(|(key, value)| value.map(|value| (key, value))),
+        );
+        map(env, &pairs)
     }
 }
 
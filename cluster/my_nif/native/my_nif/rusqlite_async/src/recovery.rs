@@ -0,0 +1,105 @@
+use rusqlite::Connection;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    Skip,
+    // PRAGMA quick_check, linear in the size of the file
+    Quick,
+    // PRAGMA integrity_check, also verifies the indexes
+    Full,
+}
+
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    // create the home if it doesn't exist, otherwise it is an error
+    pub create: bool,
+    pub integrity_check: IntegrityCheck,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            create: true,
+            integrity_check: IntegrityCheck::Quick,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FileReport {
+    pub bucket: String,
+    pub file: String,
+    pub size: u64,
+    // a rollback journal or a wal file was left by a crash and was
+    // recovered when the file was opened
+    pub hot_journal: bool,
+    pub wal: bool,
+    // problems reported by the integrity check, empty if the file is fine
+    pub integrity: Vec<String>,
+    // last raft index applied to the file, 0 if none
+    pub apply_index: u64,
+}
+
+impl FileReport {
+    pub fn is_ok(&self) -> bool {
+        self.integrity.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Inventory {
+    pub files: Vec<FileReport>,
+}
+
+fn sidecar(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn non_empty(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.len() > 0)
+}
+
+fn integrity(conn: &Connection, check: IntegrityCheck) -> rusqlite::Result<Vec<String>> {
+    let pragma = match check {
+        IntegrityCheck::Skip => return Ok(Vec::new()),
+        IntegrityCheck::Quick => "PRAGMA quick_check",
+        IntegrityCheck::Full => "PRAGMA integrity_check",
+    };
+    let mut stmt = conn.prepare(pragma)?;
+    let problems = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if problems == ["ok"] {
+        Ok(Vec::new())
+    } else {
+        Ok(problems)
+    }
+}
+
+// a file that cannot be read at all is reported with the error instead of
+// failing the whole open
+pub(crate) fn check_file(bucket: &str, file: &str, path: &Path, options: &OpenOptions) -> FileReport {
+    let hot_journal = non_empty(&sidecar(path, "-journal"));
+    let wal = non_empty(&sidecar(path, "-wal"));
+    let checked = Connection::open(path).and_then(|conn| {
+        // the first read rolls back a hot journal or replays the wal
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))?;
+        let integrity = integrity(&conn, options.integrity_check)?;
+        let apply_index = crate::apply_index::stored_index(&conn)?;
+        Ok((integrity, apply_index))
+    });
+    let (integrity, apply_index) = checked.unwrap_or_else(|err| (vec![err.to_string()], 0));
+    FileReport {
+        bucket: bucket.to_owned(),
+        file: file.to_owned(),
+        size: std::fs::metadata(path).map_or(0, |m| m.len()),
+        hot_journal,
+        wal,
+        integrity,
+        apply_index,
+    }
+}
@@ -0,0 +1,281 @@
+use crate::connection::ConnectionRequest;
+use crate::connection::SQLiteValue;
+use async_channel::Sender;
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub id: Uuid,
+    pub bucket: String,
+    pub file: String,
+    pub opened: SystemTime,
+    pub last_activity: SystemTime,
+    // closed, but some statements are still open
+    pub closed: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct StatementInfo {
+    pub id: Uuid,
+    pub query: String,
+    pub bound_values: Vec<(usize, SQLiteValue)>,
+    // since the statement was prepared
+    pub rows_stepped: u64,
+    // a step_by returned rows and the statement wasn't reset yet
+    pub mid_step: bool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: ConnectionInfo,
+    statements: BTreeMap<Uuid, StatementInfo>,
+    // the apply index of the last replicated call on the connection
+    lease_index: u64,
+    // it got a replicated call, its lease is counted in entries only
+    replicated: bool,
+    in_transaction: bool,
+    // to roll it back from the worker of another connection
+    sender: Sender<ConnectionRequest>,
+}
+
+// in milliseconds, 0 means never
+#[derive(Debug, Default)]
+struct IdleTimeouts {
+    statement: AtomicU64,
+    connection: AtomicU64,
+}
+
+// 0 means no lease
+#[derive(Debug, Default)]
+struct LeaseLimits {
+    millis: AtomicU64,
+    entries: AtomicU64,
+}
+
+fn millis(timeout: &AtomicU64) -> Option<Duration> {
+    match timeout.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+// panics caught in the workers of a context
+#[derive(Debug, Default)]
+struct Crashes {
+    count: u64,
+    last: Option<String>,
+}
+
+// What is open in a context. The workers keep it up to date, the callers only
+// read it.
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    connections: Mutex<BTreeMap<Uuid, Entry>>,
+    idle: IdleTimeouts,
+    lease: LeaseLimits,
+    // the highest apply index of a call in the context
+    applied: AtomicU64,
+    crashes: Mutex<Crashes>,
+}
+
+impl Registry {
+    pub(crate) fn set_idle_timeouts(&self, statement: Option<Duration>, connection: Option<Duration>) {
+        let ms = |timeout: Option<Duration>| timeout.map_or(0, |t| t.as_millis().max(1) as u64);
+        self.idle.statement.store(ms(statement), Ordering::Relaxed);
+        self.idle.connection.store(ms(connection), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_transaction_lease(&self, duration: Option<Duration>, entries: Option<u64>) {
+        let ms = duration.map_or(0, |t| t.as_millis().max(1) as u64);
+        self.lease.millis.store(ms, Ordering::Relaxed);
+        self.lease.entries.store(entries.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub(crate) fn connections(&self) -> Vec<ConnectionInfo> {
+        let connections = self.connections.lock().unwrap();
+        connections.values().map(|entry| entry.info.clone()).collect()
+    }
+
+    pub(crate) fn statements(&self, connection: Uuid) -> Vec<StatementInfo> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .get(&connection)
+            .map(|entry| entry.statements.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // connections and statements
+    pub(crate) fn counts(&self) -> (usize, usize) {
+        let connections = self.connections.lock().unwrap();
+        let statements = connections.values().map(|entry| entry.statements.len()).sum();
+        (connections.len(), statements)
+    }
+
+    pub(crate) fn crashed(&self, message: &str) {
+        let mut crashes = self.crashes.lock().unwrap();
+        crashes.count += 1;
+        crashes.last = Some(message.to_owned());
+    }
+
+    pub(crate) fn crashes(&self) -> (u64, Option<String>) {
+        let crashes = self.crashes.lock().unwrap();
+        (crashes.count, crashes.last.clone())
+    }
+
+    pub(crate) fn open(self: &Arc<Self>, info: ConnectionInfo, sender: Sender<ConnectionRequest>) -> Tracker {
+        let connection = info.id;
+        let entry = Entry {
+            info,
+            statements: BTreeMap::new(),
+            lease_index: self.applied.load(Ordering::Relaxed),
+            replicated: false,
+            in_transaction: false,
+            sender,
+        };
+        self.connections.lock().unwrap().insert(connection, entry);
+        Tracker {
+            registry: Arc::clone(self),
+            connection,
+        }
+    }
+}
+
+// the handle of a connection worker on its entry
+#[derive(Clone)]
+pub(crate) struct Tracker {
+    registry: Arc<Registry>,
+    connection: Uuid,
+}
+
+impl Tracker {
+    fn update(&self, f: impl FnOnce(&mut Entry)) {
+        let mut connections = self.registry.connections.lock().unwrap();
+        if let Some(entry) = connections.get_mut(&self.connection) {
+            entry.info.last_activity = SystemTime::now();
+            f(entry);
+        }
+    }
+
+    pub(crate) fn statement_idle(&self) -> Option<Duration> {
+        millis(&self.registry.idle.statement)
+    }
+
+    pub(crate) fn connection_idle(&self) -> Option<Duration> {
+        millis(&self.registry.idle.connection)
+    }
+
+    // since the last call on the connection or on one of its statements
+    pub(crate) fn idle_for(&self) -> Duration {
+        let connections = self.registry.connections.lock().unwrap();
+        connections
+            .get(&self.connection)
+            .and_then(|entry| entry.info.last_activity.elapsed().ok())
+            .unwrap_or_default()
+    }
+
+    // how long the worker may wait before checking the lease, None without
+    // one. The lease in time is for the connections that get no replicated
+    // call, the replicas would not roll back at the same point.
+    pub(crate) fn lease_wait(&self) -> Option<Duration> {
+        let lease = millis(&self.registry.lease.millis)?;
+        let connections = self.registry.connections.lock().unwrap();
+        let entry = connections.get(&self.connection)?;
+        if entry.replicated {
+            return None;
+        }
+        let idle = entry.info.last_activity.elapsed().unwrap_or_default();
+        Some(lease.saturating_sub(idle))
+    }
+
+    pub(crate) fn lease_expired(&self) -> bool {
+        self.lease_wait().is_some_and(|wait| wait.is_zero())
+    }
+
+    // The connections in a transaction that no replicated call renewed while
+    // the entries up to index were applied, true for the one of the tracker.
+    // Every replica sees the same calls in the same order, they roll back at
+    // the same entry.
+    pub(crate) fn outrun(&self, index: u64) -> Vec<(bool, Sender<ConnectionRequest>)> {
+        let entries = match self.registry.lease.entries.load(Ordering::Relaxed) {
+            0 => return Vec::new(),
+            entries => entries,
+        };
+        let connections = self.registry.connections.lock().unwrap();
+        connections
+            .iter()
+            .filter(|(_, entry)| {
+                entry.in_transaction && !entry.info.closed && index.saturating_sub(entry.lease_index) >= entries
+            })
+            .map(|(id, entry)| (*id == self.connection, entry.sender.clone()))
+            .collect()
+    }
+
+    // a replicated call renews the lease
+    pub(crate) fn applied(&self, index: u64) {
+        self.registry.applied.fetch_max(index, Ordering::Relaxed);
+        let mut connections = self.registry.connections.lock().unwrap();
+        if let Some(entry) = connections.get_mut(&self.connection) {
+            entry.lease_index = index;
+            entry.replicated = true;
+        }
+    }
+
+    pub(crate) fn in_transaction(&self, active: bool) {
+        let mut connections = self.registry.connections.lock().unwrap();
+        if let Some(entry) = connections.get_mut(&self.connection) {
+            entry.in_transaction = active;
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        self.update(|_| ());
+    }
+
+    pub(crate) fn closed(&self) {
+        self.update(|entry| entry.info.closed = true);
+    }
+
+    pub(crate) fn crashed(&self, message: &str) {
+        self.registry.crashed(message);
+    }
+
+    // the worker thread is gone with the last statement
+    pub(crate) fn gone(&self) {
+        let mut connections = self.registry.connections.lock().unwrap();
+        connections.remove(&self.connection);
+    }
+
+    pub(crate) fn statement_opened(&self, id: Uuid, query: &str, bound_values: Vec<(usize, SQLiteValue)>) {
+        let info = StatementInfo {
+            id,
+            query: query.to_owned(),
+            bound_values,
+            rows_stepped: 0,
+            mid_step: false,
+        };
+        self.update(|entry| {
+            entry.statements.insert(id, info);
+        });
+    }
+
+    pub(crate) fn statement(&self, id: Uuid, f: impl FnOnce(&mut StatementInfo)) {
+        self.update(|entry| {
+            if let Some(info) = entry.statements.get_mut(&id) {
+                f(info);
+            }
+        });
+    }
+
+    pub(crate) fn statement_closed(&self, id: Uuid) {
+        self.update(|entry| {
+            entry.statements.remove(&id);
+        });
+    }
+}
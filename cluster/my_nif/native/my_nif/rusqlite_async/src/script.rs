@@ -0,0 +1,132 @@
+use crate::budget::Budget;
+use crate::classification::classify_statement;
+use crate::classification::first_keyword;
+use crate::classification::StatementKind;
+use crate::connection::Result;
+use crate::connection::RusqliteError;
+use rusqlite::ffi;
+use rusqlite::Connection;
+use std::ffi::CString;
+
+fn complete(sql: &str) -> bool {
+    match CString::new(sql) {
+        Ok(sql) => unsafe { ffi::sqlite3_complete(sql.as_ptr()) != 0 },
+        // the nul ends the statement for sqlite anyway
+        Err(_) => true,
+    }
+}
+
+// The statements of a script with their byte offsets. A statement ends at a
+// semicolon that sqlite3_complete accepts, e.g. not the ones in a string or
+// in the body of a trigger. Empty statements and comments are skipped.
+fn split(sql: &str) -> Vec<(usize, &str)> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let ends = sql
+        .match_indices(';')
+        .map(|(i, _)| i + 1)
+        .chain(std::iter::once(sql.len()));
+    for end in ends {
+        if end <= start || (end < sql.len() && !complete(&sql[start..end])) {
+            continue;
+        }
+        let statement = &sql[start..end];
+        let trimmed = statement.trim_start();
+        if !first_keyword(trimmed).is_empty() {
+            statements.push((start + statement.len() - trimmed.len(), trimmed));
+        }
+        start = end;
+    }
+    statements
+}
+
+// Runs the statements in order, the rows of a query are read and dropped.
+// Returns the changes of every statement, 0 for anything but dml. The
+// statements before the one that fails are not rolled back, unless the
+// script is stamped with an apply index: then it runs in one savepoint, so
+// it cannot open or end a transaction itself.
+pub(crate) fn execute_script(
+    conn: &Connection,
+    sql: &str,
+    apply_index: Option<u64>,
+    budget: &Budget,
+) -> Result<Vec<u64>> {
+    let statements = split(sql);
+    let mut failed = None;
+    let mut run = || {
+        let mut changes = Vec::with_capacity(statements.len());
+        for (index, (offset, statement)) in statements.iter().enumerate() {
+            failed = Some((index, *offset));
+            let mut stmt = conn.prepare(statement)?;
+            let kind = classify_statement(&stmt, statement).kind;
+            let mut rows = stmt.raw_query();
+            while rows.next()?.is_some() {}
+            changes.push(match kind {
+                StatementKind::Dml => conn.changes(),
+                _ => 0,
+            });
+        }
+        Ok(changes)
+    };
+    let rv = match apply_index {
+        // already applied, nothing changed
+        Some(index) => crate::apply_index::stamped(conn, index, run)
+            .map(|changes| changes.unwrap_or_else(|| vec![0; statements.len()])),
+        None => run(),
+    };
+    rv.map_err(|err| match (budget.exceeded(), failed) {
+        (None, Some((index, offset))) => RusqliteError::Script(index, offset, err),
+        _ => budget.error(err),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_at_semicolons() {
+        let sql = "CREATE TABLE t(x); INSERT INTO t VALUES (1);";
+        assert_eq!(
+            split(sql),
+            vec![(0, "CREATE TABLE t(x);"), (19, "INSERT INTO t VALUES (1);")]
+        );
+    }
+
+    #[test]
+    fn semicolon_in_string_and_last_without_one() {
+        let sql = "INSERT INTO t VALUES ('a;b'); SELECT 1";
+        assert_eq!(split(sql), vec![(0, "INSERT INTO t VALUES ('a;b');"), (30, "SELECT 1")]);
+    }
+
+    #[test]
+    fn trigger_body_is_one_statement() {
+        let trigger = "CREATE TRIGGER r AFTER INSERT ON t BEGIN UPDATE t SET x = 1; END;";
+        let sql = format!("{} SELECT 1;", trigger);
+        assert_eq!(split(&sql), vec![(0, trigger), (trigger.len() + 1, "SELECT 1;")]);
+    }
+
+    #[test]
+    fn empty_statements_and_comments_are_skipped() {
+        let sql = ";; -- note\n ;SELECT 1";
+        assert_eq!(split(sql), vec![(13, "SELECT 1")]);
+        assert!(split("").is_empty());
+        assert!(split("/* only */").is_empty());
+    }
+
+    #[test]
+    fn stamped_script_rolls_back_as_a_whole() {
+        let conn = Connection::open_in_memory().unwrap();
+        let budget = Budget::new();
+        conn.execute_batch("CREATE TABLE t(x)").unwrap();
+        let sql = "INSERT INTO t VALUES (1); INSERT INTO nope VALUES (2);";
+        let count = || conn.query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0)).unwrap();
+        match execute_script(&conn, sql, Some(1), &budget) {
+            Err(RusqliteError::Script(1, 26, _)) => {}
+            rv => panic!("{:?}", rv),
+        }
+        assert_eq!(count(), 0);
+        assert!(execute_script(&conn, sql, None, &budget).is_err());
+        assert_eq!(count(), 1);
+    }
+}
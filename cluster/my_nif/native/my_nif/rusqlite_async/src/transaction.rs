@@ -0,0 +1,123 @@
+use crate::connection::Result;
+use crate::connection::RusqliteError;
+use rusqlite::ffi;
+use rusqlite::Connection;
+use std::ptr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionMode {
+    Deferred,
+    Immediate,
+    Exclusive,
+}
+
+#[derive(Debug)]
+pub enum TransactionOp {
+    Begin(TransactionMode),
+    Commit,
+    Rollback,
+    Savepoint(Box<str>),
+    Release(Box<str>),
+    RollbackTo(Box<str>),
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql(op: &TransactionOp) -> Result<String> {
+    let savepoint = |name: &str| {
+        if name.is_empty() {
+            Err(RusqliteError::CustomError(
+                "Empty savepoint name".to_owned(),
+            ))
+        } else {
+            Ok(quote(name))
+        }
+    };
+    Ok(match op {
+        TransactionOp::Begin(TransactionMode::Deferred) => "BEGIN DEFERRED".to_owned(),
+        TransactionOp::Begin(TransactionMode::Immediate) => "BEGIN IMMEDIATE".to_owned(),
+        TransactionOp::Begin(TransactionMode::Exclusive) => "BEGIN EXCLUSIVE".to_owned(),
+        TransactionOp::Commit => "COMMIT".to_owned(),
+        TransactionOp::Rollback => "ROLLBACK".to_owned(),
+        TransactionOp::Savepoint(name) => format!("SAVEPOINT {}", savepoint(name)?),
+        TransactionOp::Release(name) => format!("RELEASE {}", savepoint(name)?),
+        TransactionOp::RollbackTo(name) => format!("ROLLBACK TO {}", savepoint(name)?),
+    })
+}
+
+// sqlite keeps the state, a SAVEPOINT outside of a transaction starts one and
+// releasing the outermost savepoint commits it
+pub(crate) fn apply(conn: &Connection, op: &TransactionOp) -> Result<()> {
+    conn.execute_batch(&sql(op)?).map_err(RusqliteError::from)
+}
+
+// the ops always run, they change the state of the connection. A commit or a
+// release records index with the transaction when it has written, a read only
+// or rolled back transaction leaves the file as it was.
+pub(crate) fn apply_stamped(conn: &Connection, op: &TransactionOp, index: u64) -> Result<()> {
+    if let TransactionOp::Commit | TransactionOp::Release(_) = op {
+        if writing(conn) {
+            crate::apply_index::stamp_pending(conn, index)?;
+        }
+    }
+    apply(conn, op)
+}
+
+fn writing(conn: &Connection) -> bool {
+    unsafe { ffi::sqlite3_txn_state(conn.handle(), ptr::null()) == ffi::SQLITE_TXN_WRITE }
+}
+
+pub(crate) fn active(conn: &Connection) -> bool {
+    !conn.is_autocommit()
+}
+
+// a connection that goes away in the middle of a transaction rolls it back
+// right away instead of whenever sqlite closes it, the statements may keep it
+// open for a while
+pub(crate) fn rollback(conn: &Connection) -> Result<()> {
+    if active(conn) {
+        conn.execute_batch("ROLLBACK").map_err(RusqliteError::from)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn begin_modes() {
+        let begin = |mode| sql(&TransactionOp::Begin(mode)).unwrap();
+        assert_eq!(begin(TransactionMode::Deferred), "BEGIN DEFERRED");
+        assert_eq!(begin(TransactionMode::Immediate), "BEGIN IMMEDIATE");
+        assert_eq!(begin(TransactionMode::Exclusive), "BEGIN EXCLUSIVE");
+        assert_eq!(sql(&TransactionOp::Commit).unwrap(), "COMMIT");
+        assert_eq!(sql(&TransactionOp::Rollback).unwrap(), "ROLLBACK");
+    }
+
+    #[test]
+    fn savepoint_names_are_quoted() {
+        let op = TransactionOp::Savepoint("a\"b; DROP TABLE t".into());
+        assert_eq!(sql(&op).unwrap(), "SAVEPOINT \"a\"\"b; DROP TABLE t\"");
+        assert_eq!(sql(&TransactionOp::Release("s".into())).unwrap(), "RELEASE \"s\"");
+        assert_eq!(sql(&TransactionOp::RollbackTo("s".into())).unwrap(), "ROLLBACK TO \"s\"");
+    }
+
+    #[test]
+    fn empty_savepoint_name() {
+        assert!(sql(&TransactionOp::Savepoint("".into())).is_err());
+        assert!(sql(&TransactionOp::Release("".into())).is_err());
+    }
+
+    #[test]
+    fn quoted_name_is_one_savepoint() {
+        let conn = Connection::open_in_memory().unwrap();
+        apply(&conn, &TransactionOp::Savepoint("a\"b".into())).unwrap();
+        assert!(!conn.is_autocommit());
+        apply(&conn, &TransactionOp::Release("a\"b".into())).unwrap();
+        assert!(conn.is_autocommit());
+    }
+}
@@ -1,9 +1,14 @@
-#![feature(try_trait_v2)]
-
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
 
 use base64::Engine as _;
+use rusqlite_async::connection::CallOptions;
 use rusqlite_async::connection::Result;
+use rusqlite_async::crash::contain;
+use rustler::Encoder;
 use rustler::Env;
 use rustler::Term;
 
@@ -13,200 +18,565 @@ pub struct Statement(rusqlite_async::connection::VirtualStatement);
 
 #[rustler::nif]
 pub fn set_busy_timeout(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     timeout: u64,
 ) -> Result<()> {
-    let timeout = Duration::from_millis(timeout);
-    rusqlite_async::connection::set_busy_timeout(&ctx.0, &conn.0, timeout)
+    contain(|| {
+        let timeout = Duration::from_millis(timeout);
+        rusqlite_async::connection::set_busy_timeout(&ctx.0, &conn.0, timeout, None)
+    })
+}
+
+// milliseconds from now, None for the default timeout of the context
+fn deadline(timeout: Option<u64>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + Duration::from_millis(timeout))
+}
+
+// the calls from erlang that carry no deadline use this one, nil removes it.
+// Only for a context that applies no raft entries, see my_nif.erl
+#[rustler::nif]
+pub fn set_default_timeout(
+    ctx: rustler::ResourceArc<Context>,
+    timeout: Option<u64>,
+) -> Result<()> {
+    contain(|| {
+        rusqlite_async::connection::set_default_timeout(&ctx.0, timeout.map(Duration::from_millis));
+        Ok(())
+    })
 }
 
+// milliseconds or nil, for the statements and for the connections
 #[rustler::nif]
-pub fn create_context(env: Env, home: String) -> Result<rustler::ResourceArc<Context>> {
-    let ctx = rusqlite_async::connection::create_context(&home)?;
-    let ctx = Context(ctx);
-    Ok(rustler::ResourceArc::new(ctx))
+pub fn set_idle_timeouts(
+    ctx: rustler::ResourceArc<Context>,
+    statement: Option<u64>,
+    connection: Option<u64>,
+) -> Result<()> {
+    contain(|| {
+        rusqlite_async::connection::set_idle_timeouts(
+            &ctx.0,
+            statement.map(Duration::from_millis),
+            connection.map(Duration::from_millis),
+        );
+        Ok(())
+    })
+}
+
+// milliseconds or nil, raft entries or nil
+#[rustler::nif]
+pub fn set_transaction_lease(
+    ctx: rustler::ResourceArc<Context>,
+    duration: Option<u64>,
+    entries: Option<u64>,
+) -> Result<()> {
+    contain(|| {
+        rusqlite_async::connection::set_transaction_lease(
+            &ctx.0,
+            duration.map(Duration::from_millis),
+            entries,
+        );
+        Ok(())
+    })
+}
+
+#[rustler::nif]
+pub fn create_context(home: String) -> Result<rustler::ResourceArc<Context>> {
+    contain(|| {
+        let ctx = rusqlite_async::connection::create_context(&home)?;
+        let ctx = Context(ctx);
+        Ok(rustler::ResourceArc::new(ctx))
+    })
+}
+
+#[rustler::nif]
+pub fn open_context(
+    home: String,
+    options: rusqlite_async::connection::OpenOptions,
+) -> Result<(rustler::ResourceArc<Context>, rusqlite_async::connection::Inventory)> {
+    contain(|| {
+        let (ctx, inventory) = rusqlite_async::connection::open_context(&home, &options)?;
+        Ok((rustler::ResourceArc::new(Context(ctx)), inventory))
+    })
 }
 
 #[rustler::nif]
 pub fn create_connection(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     directory: String,
     file: String,
 ) -> Result<rustler::ResourceArc<Connection>> {
-    let conn = rusqlite_async::connection::create_connection(&ctx.0, &directory, &file)?;
-    Ok(rustler::ResourceArc::new(Connection(conn)))
+    contain(|| {
+        let conn = rusqlite_async::connection::create_connection(&ctx.0, &directory, &file, None)?;
+        Ok(rustler::ResourceArc::new(Connection(conn)))
+    })
 }
 
 #[rustler::nif]
 pub fn prepare(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     query: String,
+    budget: Option<u64>,
 ) -> Result<rustler::ResourceArc<Statement>> {
-    let stmt = rusqlite_async::connection::prepare(&ctx.0, &conn.0, &query)?;
-    Ok(rustler::ResourceArc::new(Statement(stmt)))
+    contain(|| {
+        let stmt = rusqlite_async::connection::prepare(&ctx.0, &conn.0, &query, budget, None)?;
+        Ok(rustler::ResourceArc::new(Statement(stmt)))
+    })
 }
 
 
 #[rustler::nif]
 pub fn list_files(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     bucket: String,
 ) -> Result<Vec<String>> {
-    rusqlite_async::connection::list_files(&ctx.0, &bucket)
+    contain(|| {
+        rusqlite_async::connection::list_files(&ctx.0, &bucket)
+    })
 }
 
 #[rustler::nif]
 pub fn list_buckets(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
 ) -> Result<Vec<String>> {
-    rusqlite_async::connection::list_buckets(&ctx.0)
+    contain(|| {
+        rusqlite_async::connection::list_buckets(&ctx.0)
+    })
 }
 
 
 #[rustler::nif]
 pub fn bind(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     stmt: rustler::ResourceArc<Statement>,
     n: usize,
     value: rusqlite_async::connection::SQLiteValue,
 ) -> Result<()> {
-    rusqlite_async::connection::bind(&ctx.0, &conn.0, &stmt.0, n, value)
+    contain(|| {
+        rusqlite_async::connection::bind(&ctx.0, &conn.0, &stmt.0, n, value, None)
+    })
+}
+
+#[rustler::nif]
+pub fn bind_named(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    name: String,
+    value: rusqlite_async::connection::SQLiteValue,
+) -> Result<()> {
+    contain(|| {
+        rusqlite_async::connection::bind_named(&ctx.0, &conn.0, &stmt.0, &name, value, None)
+    })
 }
 
 #[rustler::nif]
 pub fn column_names(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     stmt: rustler::ResourceArc<Statement>,
+    timeout: Option<u64>,
 ) -> Result<Vec<String>> {
-    rusqlite_async::connection::column_names(&ctx.0, &conn.0, &stmt.0)
-        .map(|v| v.into_iter().map(ToString::to_string).collect())
+    contain(|| {
+        rusqlite_async::connection::column_names(&ctx.0, &conn.0, &stmt.0, deadline(timeout))
+            .map(|v| v.iter().map(ToString::to_string).collect())
+    })
 }
 
 #[rustler::nif]
 pub fn column_count(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     stmt: rustler::ResourceArc<Statement>,
+    timeout: Option<u64>,
 ) -> Result<usize> {
-    rusqlite_async::connection::column_count(&ctx.0, &conn.0, &stmt.0)
+    contain(|| {
+        rusqlite_async::connection::column_count(&ctx.0, &conn.0, &stmt.0, deadline(timeout))
+    })
+}
+
+#[rustler::nif]
+pub fn column_metadata(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    timeout: Option<u64>,
+) -> Result<Vec<rusqlite_async::connection::ColumnMetadata>> {
+    contain(|| {
+        rusqlite_async::connection::column_metadata(&ctx.0, &conn.0, &stmt.0, deadline(timeout))
+            .map(|v| v.to_vec())
+    })
 }
 
 #[rustler::nif]
 pub fn execute(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     query: String,
     params: Vec<rusqlite_async::connection::SQLiteValue>,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
 ) -> Result<usize> {
-    rusqlite_async::connection::execute(&ctx.0, &conn.0, &query, params)
+    contain(|| {
+        rusqlite_async::connection::execute(
+            &ctx.0,
+            &conn.0,
+            &query,
+            params,
+            CallOptions { determinism, apply_index, budget },
+            None,
+        )
+    })
+}
+
+// params is a map from the parameter names, with their prefix, to the values
+#[rustler::nif]
+pub fn execute_named(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    query: String,
+    params: HashMap<String, rusqlite_async::connection::SQLiteValue>,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
+) -> Result<usize> {
+    contain(|| {
+        rusqlite_async::connection::execute_named(
+            &ctx.0,
+            &conn.0,
+            &query,
+            params.into_iter().collect(),
+            CallOptions { determinism, apply_index, budget },
+            None,
+        )
+    })
+}
+
+#[rustler::nif]
+pub fn execute_many(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    query: String,
+    rows: Vec<Vec<rusqlite_async::connection::SQLiteValue>>,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
+) -> Result<(usize, Vec<usize>)> {
+    contain(|| {
+        rusqlite_async::connection::execute_many(
+            &ctx.0,
+            &conn.0,
+            &query,
+            rows,
+            CallOptions { determinism, apply_index, budget },
+            None,
+        )
+    })
+}
+
+#[rustler::nif]
+pub fn execute_script(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    sql: String,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
+) -> Result<Vec<u64>> {
+    contain(|| {
+        rusqlite_async::connection::execute_script(
+            &ctx.0,
+            &conn.0,
+            &sql,
+            CallOptions { determinism, apply_index, budget },
+            None,
+        )
+    })
+}
+
+#[rustler::nif]
+pub fn classify(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    query: String,
+    timeout: Option<u64>,
+) -> Result<rusqlite_async::connection::Classification> {
+    contain(|| {
+        rusqlite_async::connection::classify(&ctx.0, &conn.0, &query, deadline(timeout))
+    })
 }
 
 #[rustler::nif]
 pub fn bind_parameter_count(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     stmt: rustler::ResourceArc<Statement>,
+    timeout: Option<u64>,
 ) -> Result<usize> {
-    rusqlite_async::connection::bind_parameter_count(&ctx.0, &conn.0, &stmt.0)
+    contain(|| {
+        rusqlite_async::connection::bind_parameter_count(&ctx.0, &conn.0, &stmt.0, deadline(timeout))
+    })
+}
+
+// params is a list of values or a map from the parameter names
+#[rustler::nif]
+pub fn bind_all(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    params: rusqlite_async::connection::Parameters,
+) -> Result<()> {
+    contain(|| rusqlite_async::connection::bind_all(&ctx.0, &conn.0, &stmt.0, params, None))
+}
+
+#[rustler::nif]
+pub fn bind_parameter_name(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    n: usize,
+) -> Result<Option<String>> {
+    contain(|| {
+        rusqlite_async::connection::bind_parameter_name(&ctx.0, &conn.0, &stmt.0, n, None)
+            .map(|name| name.map(String::from))
+    })
+}
+
+#[rustler::nif]
+pub fn bind_parameter_index(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    name: String,
+) -> Result<Option<usize>> {
+    contain(|| {
+        rusqlite_async::connection::bind_parameter_index(&ctx.0, &conn.0, &stmt.0, &name, None)
+    })
 }
 
 #[rustler::nif]
 pub fn clone_and_reset(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     stmt: rustler::ResourceArc<Statement>,
 ) -> Result<rustler::ResourceArc<Statement>> {
-    let stmt = rusqlite_async::connection::clone_and_reset(&ctx.0, &conn.0, &stmt.0)?;
-    Ok(rustler::ResourceArc::new(Statement(stmt)))
+    contain(|| {
+        let stmt = rusqlite_async::connection::clone_and_reset(&ctx.0, &conn.0, &stmt.0, None)?;
+        Ok(rustler::ResourceArc::new(Statement(stmt)))
+    })
 }
 
 #[rustler::nif]
-pub fn lib_version(env: Env, ctx: rustler::ResourceArc<Context>) -> String {
+pub fn lib_version(ctx: rustler::ResourceArc<Context>) -> String {
     rusqlite_async::connection::libversion(&ctx.0).to_string()
 }
 
 #[rustler::nif]
 pub fn clear_bindings(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     stmt: rustler::ResourceArc<Statement>,
 ) -> Result<bool> {
-    rusqlite_async::connection::clear_bindings(&ctx.0, &conn.0, &stmt.0)
+    contain(|| {
+        rusqlite_async::connection::clear_bindings(&ctx.0, &conn.0, &stmt.0, None)
+    })
 }
 
 #[rustler::nif]
 pub fn finalize(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     stmt: rustler::ResourceArc<Statement>,
 ) -> Result<()> {
-    rusqlite_async::connection::finalize(&ctx.0, &conn.0, &stmt.0)
+    contain(|| {
+        rusqlite_async::connection::finalize(&ctx.0, &conn.0, &stmt.0, None)
+    })
 }
 
 #[rustler::nif]
 pub fn close(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
 ) -> Result<()> {
-    rusqlite_async::connection::close(&ctx.0, &conn.0)
+    contain(|| {
+        rusqlite_async::connection::close(&ctx.0, &conn.0, None)
+    })
+}
+
+#[rustler::nif]
+pub fn release(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+) -> Result<()> {
+    contain(|| {
+        rusqlite_async::connection::release(&ctx.0, &conn.0, None)
+    })
+}
+
+// not scheduled as dirty on purpose, it must run while another call on conn is blocked
+#[rustler::nif]
+pub fn interrupt(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+) -> Result<()> {
+    contain(|| {
+        rusqlite_async::connection::interrupt(&ctx.0, &conn.0)
+    })
+}
+
+// the same connection, dropping it leaves the connection open
+#[rustler::nif]
+pub fn weak_connection(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+) -> Result<rustler::ResourceArc<Connection>> {
+    contain(|| {
+        let conn = rusqlite_async::connection::weak_connection(&ctx.0, &conn.0)?;
+        Ok(rustler::ResourceArc::new(Connection(conn)))
+    })
+}
+
+#[rustler::nif]
+pub fn weak_statement(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Result<rustler::ResourceArc<Statement>> {
+    contain(|| {
+        let stmt = rusqlite_async::connection::weak_statement(&ctx.0, &conn.0, &stmt.0)?;
+        Ok(rustler::ResourceArc::new(Statement(stmt)))
+    })
+}
+
+#[rustler::nif]
+pub fn cancel(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Result<bool> {
+    contain(|| {
+        rusqlite_async::connection::cancel(&ctx.0, &conn.0, &stmt.0)
+    })
 }
 
 #[rustler::nif]
 pub fn last_insert_rowid(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
+    timeout: Option<u64>,
 ) -> Result<i64> {
-    rusqlite_async::connection::last_insert_rowid(&ctx.0, &conn.0)
+    contain(|| {
+        rusqlite_async::connection::last_insert_rowid(&ctx.0, &conn.0, deadline(timeout))
+    })
 }
 
 #[rustler::nif]
 pub fn changes(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
+    timeout: Option<u64>,
 ) -> Result<u64> {
-    rusqlite_async::connection::changes(&ctx.0, &conn.0)
+    contain(|| {
+        rusqlite_async::connection::changes(&ctx.0, &conn.0, deadline(timeout))
+    })
+}
+
+#[rustler::nif]
+pub fn begin(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    mode: rusqlite_async::connection::TransactionMode,
+    apply_index: Option<u64>,
+) -> Result<()> {
+    contain(|| rusqlite_async::connection::begin(&ctx.0, &conn.0, mode, apply_index, None))
+}
+
+#[rustler::nif]
+pub fn commit(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    apply_index: Option<u64>,
+) -> Result<()> {
+    contain(|| rusqlite_async::connection::commit(&ctx.0, &conn.0, apply_index, None))
+}
+
+#[rustler::nif]
+pub fn rollback(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    apply_index: Option<u64>,
+) -> Result<()> {
+    contain(|| rusqlite_async::connection::rollback(&ctx.0, &conn.0, apply_index, None))
+}
+
+#[rustler::nif]
+pub fn savepoint(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    name: String,
+    apply_index: Option<u64>,
+) -> Result<()> {
+    contain(|| rusqlite_async::connection::savepoint(&ctx.0, &conn.0, &name, apply_index, None))
+}
+
+#[rustler::nif]
+pub fn release_savepoint(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    name: String,
+    apply_index: Option<u64>,
+) -> Result<()> {
+    contain(|| rusqlite_async::connection::release_savepoint(&ctx.0, &conn.0, &name, apply_index, None))
+}
+
+#[rustler::nif]
+pub fn rollback_to(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    name: String,
+    apply_index: Option<u64>,
+) -> Result<()> {
+    contain(|| rusqlite_async::connection::rollback_to(&ctx.0, &conn.0, &name, apply_index, None))
+}
+
+#[rustler::nif]
+pub fn in_transaction(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+) -> Result<bool> {
+    contain(|| rusqlite_async::connection::in_transaction(&ctx.0, &conn.0, None))
 }
 
 #[rustler::nif]
 pub fn step_by(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     stmt: rustler::ResourceArc<Statement>,
     n: usize,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
 ) -> Result<Option<Vec<Vec<rusqlite_async::connection::SQLiteValue>>>> {
-    rusqlite_async::connection::step_by(&ctx.0, &conn.0, &stmt.0, n)
+    contain(|| {
+        let options = CallOptions { determinism, apply_index, budget };
+        rusqlite_async::connection::step_by(&ctx.0, &conn.0, &stmt.0, n, options, None)
+    })
 }
 
 #[rustler::nif]
 pub fn column_name(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     conn: rustler::ResourceArc<Connection>,
     stmt: rustler::ResourceArc<Statement>,
     n: usize,
+    timeout: Option<u64>,
 ) -> Result<String> {
-    rusqlite_async::connection::column_name(&ctx.0, &conn.0, &stmt.0, n).map(String::from)
+    contain(|| {
+        rusqlite_async::connection::column_name(&ctx.0, &conn.0, &stmt.0, n, deadline(timeout)).map(String::from)
+    })
 }
 
 #[rustler::nif]
@@ -219,25 +589,536 @@ pub fn generate_uuid() -> String {
 
 
 
+#[rustler::nif]
+pub fn list_connections(
+    ctx: rustler::ResourceArc<Context>,
+) -> Result<Vec<rusqlite_async::connection::ConnectionInfo>> {
+    contain(|| {
+        rusqlite_async::connection::list_connections(&ctx.0)
+    })
+}
+
+#[rustler::nif]
+pub fn list_statements(
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+) -> Result<Vec<rusqlite_async::connection::StatementInfo>> {
+    contain(|| {
+        rusqlite_async::connection::list_statements(&ctx.0, &conn.0)
+    })
+}
+
 #[rustler::nif]
 pub fn delete_file(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     bucket: String,
     file: String,
 ) -> Result<()> {
-    rusqlite_async::connection::delete_file(&ctx.0, &bucket, &file)
+    contain(|| {
+        rusqlite_async::connection::delete_file(&ctx.0, &bucket, &file)
+    })
 }
 
 #[rustler::nif]
 pub fn delete_bucket(
-    env: Env,
     ctx: rustler::ResourceArc<Context>,
     bucket: String,
 ) -> Result<()> {
-    rusqlite_async::connection::delete_bucket(&ctx.0, &bucket)
+    contain(|| {
+        rusqlite_async::connection::delete_bucket(&ctx.0, &bucket)
+    })
+}
+
+#[rustler::nif]
+pub fn snapshot_context(
+    ctx: rustler::ResourceArc<Context>,
+    dest: String,
+) -> Result<Vec<(String, String)>> {
+    contain(|| {
+        rusqlite_async::connection::snapshot_context(&ctx.0, &dest)
+    })
+}
+
+#[rustler::nif]
+pub fn restore_context(
+    home: String,
+    archive: String,
+) -> Result<rustler::ResourceArc<Context>> {
+    contain(|| {
+        let ctx = rusqlite_async::connection::restore_context(&home, &archive)?;
+        Ok(rustler::ResourceArc::new(Context(ctx)))
+    })
+}
+
+#[rustler::nif]
+pub fn restore_home(
+    ctx: rustler::ResourceArc<Context>,
+    archive: String,
+) -> Result<()> {
+    contain(|| {
+        rusqlite_async::connection::restore_home(&ctx.0, &archive)
+    })
+}
+
+#[rustler::nif]
+pub fn state_digest(
+    ctx: rustler::ResourceArc<Context>,
+) -> Result<rusqlite_async::connection::StateDigest> {
+    contain(|| {
+        rusqlite_async::connection::state_digest(&ctx.0)
+    })
+}
+
+// rustler 0.30 has no Env::make_ref
+fn make_ref(env: Env) -> Term {
+    unsafe { Term::new(env, rustler_sys::enif_make_ref(env.as_c_arg())) }
+}
+
+type Then<T> = Box<dyn FnOnce(Result<T>) + Send>;
+
+// Ref is returned right away, the worker that handles the call sends
+// {Ref, Result} to the caller once it is done
+fn reply_later<'a, T>(env: Env<'a>, call: impl FnOnce(Then<T>)) -> Term<'a>
+where
+    T: rustler::Encoder + Send + 'static,
+{
+    let pid = env.pid();
+    let reference = make_ref(env);
+    let mut owned_env = rustler::OwnedEnv::new();
+    let saved = owned_env.save(reference);
+    // an OwnedEnv cannot send from a scheduler thread, the answer of a call
+    // that failed before reaching its worker is sent by the nif
+    let caller = std::thread::current().id();
+    let early = Arc::new(Mutex::new(None));
+    let slot = early.clone();
+    let then: Then<T> = Box::new(move |rv| {
+        if std::thread::current().id() == caller {
+            *slot.lock().unwrap() = Some(rv);
+        } else {
+            // the caller may be gone
+            let _ = owned_env.send_and_clear(&pid, |env| (saved.load(env), rv).encode(env));
+        }
+    });
+    let _ = contain(|| {
+        call(then);
+        Ok(())
+    });
+    if let Some(rv) = early.lock().unwrap().take() {
+        let _ = env.send(&pid, (reference, rv).encode(env));
+    }
+    reference
+}
+
+// for the calls that have no worker, they run on a dirty scheduler and
+// {Ref, Result} is already sent when Ref is returned
+fn reply_now<'a, T>(env: Env<'a>, f: impl FnOnce() -> Result<T>) -> Term<'a>
+where
+    T: rustler::Encoder,
+{
+    let reference = make_ref(env);
+    let rv = contain(f);
+    let _ = env.send(&env.pid(), (reference, rv).encode(env));
+    reference
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn open_context_async<'a>(
+    env: Env<'a>,
+    home: String,
+    options: rusqlite_async::connection::OpenOptions,
+) -> Term<'a> {
+    reply_now(env, move || {
+        let (ctx, inventory) = rusqlite_async::connection::open_context(&home, &options)?;
+        Result::Ok((rustler::ResourceArc::new(Context(ctx)), inventory))
+    })
+}
+
+#[rustler::nif]
+pub fn create_connection_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    directory: String,
+    file: String,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::create_connection_then(&ctx.0, &directory, &file, move |rv| {
+            then(rv.map(|conn| rustler::ResourceArc::new(Connection(conn))))
+        })
+    })
+}
+
+#[rustler::nif]
+pub fn set_busy_timeout_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    timeout: u64,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::set_busy_timeout_then(&ctx.0, &conn.0, Duration::from_millis(timeout), then)
+    })
+}
+
+#[rustler::nif]
+pub fn prepare_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    query: String,
+    budget: Option<u64>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::prepare_then(&ctx.0, &conn.0, &query, budget, move |rv| {
+            then(rv.map(|stmt| rustler::ResourceArc::new(Statement(stmt))))
+        })
+    })
+}
+
+// env and the seven arguments of the erlang function
+#[rustler::nif]
+#[allow(clippy::too_many_arguments)]
+pub fn execute_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    query: String,
+    params: Vec<rusqlite_async::connection::SQLiteValue>,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::execute_then(
+            &ctx.0,
+            &conn.0,
+            &query,
+            params,
+            CallOptions { determinism, apply_index, budget },
+            then,
+        )
+    })
+}
+
+#[rustler::nif]
+#[allow(clippy::too_many_arguments)]
+pub fn execute_named_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    query: String,
+    params: HashMap<String, rusqlite_async::connection::SQLiteValue>,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::execute_named_then(
+            &ctx.0,
+            &conn.0,
+            &query,
+            params.into_iter().collect(),
+            CallOptions { determinism, apply_index, budget },
+            then,
+        )
+    })
+}
+
+#[rustler::nif]
+#[allow(clippy::too_many_arguments)]
+pub fn execute_many_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    query: String,
+    rows: Vec<Vec<rusqlite_async::connection::SQLiteValue>>,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::execute_many_then(
+            &ctx.0,
+            &conn.0,
+            &query,
+            rows,
+            CallOptions { determinism, apply_index, budget },
+            then,
+        )
+    })
+}
+
+#[rustler::nif]
+pub fn execute_script_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    sql: String,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::execute_script_then(
+            &ctx.0,
+            &conn.0,
+            &sql,
+            CallOptions { determinism, apply_index, budget },
+            then,
+        )
+    })
+}
+
+#[rustler::nif]
+pub fn classify_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    query: String,
+) -> Term<'a> {
+    reply_later(env, |then| rusqlite_async::connection::classify_then(&ctx.0, &conn.0, &query, then))
+}
+
+#[rustler::nif]
+#[allow(clippy::too_many_arguments)]
+pub fn step_by_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    n: usize,
+    determinism: Option<rusqlite_async::connection::Determinism>,
+    apply_index: Option<u64>,
+    budget: Option<u64>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        let options = CallOptions { determinism, apply_index, budget };
+        rusqlite_async::connection::step_by_then(&ctx.0, &conn.0, &stmt.0, n, options, then)
+    })
+}
+
+#[rustler::nif]
+pub fn bind_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    n: usize,
+    value: rusqlite_async::connection::SQLiteValue,
+) -> Term<'a> {
+    reply_later(env, |then| rusqlite_async::connection::bind_then(&ctx.0, &conn.0, &stmt.0, n, value, then))
+}
+
+#[rustler::nif]
+pub fn bind_named_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    name: String,
+    value: rusqlite_async::connection::SQLiteValue,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::bind_named_then(&ctx.0, &conn.0, &stmt.0, &name, value, then)
+    })
+}
+
+#[rustler::nif]
+pub fn bind_all_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    params: rusqlite_async::connection::Parameters,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::bind_all_then(&ctx.0, &conn.0, &stmt.0, params, then)
+    })
+}
+
+#[rustler::nif]
+pub fn column_names_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::column_names_then(&ctx.0, &conn.0, &stmt.0, move |rv| {
+            then(rv.map(|v| v.iter().map(ToString::to_string).collect::<Vec<_>>()))
+        })
+    })
+}
+
+#[rustler::nif]
+pub fn column_count_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Term<'a> {
+    reply_later(env, |then| rusqlite_async::connection::column_count_then(&ctx.0, &conn.0, &stmt.0, then))
+}
+
+#[rustler::nif]
+pub fn column_metadata_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::column_metadata_then(&ctx.0, &conn.0, &stmt.0, move |rv| {
+            then(rv.map(|v| v.to_vec()))
+        })
+    })
+}
+
+#[rustler::nif]
+pub fn column_name_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+    n: usize,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::column_name_then(&ctx.0, &conn.0, &stmt.0, n, move |rv| {
+            then(rv.map(String::from))
+        })
+    })
+}
+
+#[rustler::nif]
+pub fn bind_parameter_count_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::bind_parameter_count_then(&ctx.0, &conn.0, &stmt.0, then)
+    })
+}
+
+#[rustler::nif]
+pub fn clear_bindings_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Term<'a> {
+    reply_later(env, |then| rusqlite_async::connection::clear_bindings_then(&ctx.0, &conn.0, &stmt.0, then))
+}
+
+#[rustler::nif]
+pub fn clone_and_reset_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::clone_and_reset_then(&ctx.0, &conn.0, &stmt.0, move |rv| {
+            then(rv.map(|stmt| rustler::ResourceArc::new(Statement(stmt))))
+        })
+    })
+}
+
+#[rustler::nif]
+pub fn finalize_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Term<'a> {
+    reply_later(env, |then| rusqlite_async::connection::finalize_then(&ctx.0, &conn.0, &stmt.0, then))
+}
+
+#[rustler::nif]
+pub fn close_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+) -> Term<'a> {
+    reply_later(env, |then| rusqlite_async::connection::close_then(&ctx.0, &conn.0, then))
+}
+
+#[rustler::nif]
+pub fn last_insert_rowid_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+) -> Term<'a> {
+    reply_later(env, |then| rusqlite_async::connection::last_insert_rowid_then(&ctx.0, &conn.0, then))
+}
+
+#[rustler::nif]
+pub fn changes_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+) -> Term<'a> {
+    reply_later(env, |then| rusqlite_async::connection::changes_then(&ctx.0, &conn.0, then))
+}
+
+#[rustler::nif]
+pub fn closed_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+) -> Term<'a> {
+    reply_later(env, |then| rusqlite_async::connection::closed_then(&ctx.0, &conn.0, then))
+}
+
+#[rustler::nif]
+pub fn finalized_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    conn: rustler::ResourceArc<Connection>,
+    stmt: rustler::ResourceArc<Statement>,
+) -> Term<'a> {
+    reply_later(env, |then| {
+        rusqlite_async::connection::finalized_then(&ctx.0, &conn.0, &stmt.0, then)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn snapshot_context_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+    dest: String,
+) -> Term<'a> {
+    reply_now(env, || rusqlite_async::connection::snapshot_context(&ctx.0, &dest))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn state_digest_async<'a>(
+    env: Env<'a>,
+    ctx: rustler::ResourceArc<Context>,
+) -> Term<'a> {
+    reply_now(env, || rusqlite_async::connection::state_digest(&ctx.0))
+}
+
+#[rustler::nif]
+pub fn health(
+    ctx: rustler::ResourceArc<Context>,
+) -> Result<rusqlite_async::connection::Health> {
+    contain(|| Ok(rusqlite_async::connection::health(&ctx.0)))
+}
+
+// the open connections keep their threads, only the context thread is replaced
+#[rustler::nif]
+pub fn restart_context(
+    ctx: rustler::ResourceArc<Context>,
+) -> Result<()> {
+    contain(|| {
+        rusqlite_async::connection::restart_context(&ctx.0);
+        Ok(())
+    })
 }
 
+// the impls of rustler::resource! are inside the function
+#[allow(non_local_definitions)]
 fn load(env: Env, _info: Term) -> bool {
     rustler::resource!(Context, env);
     rustler::resource!(Connection, env);
@@ -249,29 +1130,89 @@ rustler::init!(
     "my_nif", // This should match the Erlang module you want to bind to
     [
         create_context,
+        open_context,
         create_connection,
         prepare,
         bind,
+        bind_named,
+        bind_all,
         column_names,
         column_count,
+        column_metadata,
         list_files,
         list_buckets,
+        list_connections,
+        list_statements,
         execute,
+        execute_named,
+        execute_many,
+        execute_script,
+        classify,
         lib_version,
         bind_parameter_count,
+        bind_parameter_name,
+        bind_parameter_index,
         clear_bindings,
         clone_and_reset,
         finalize,
         close,
+        release,
+        interrupt,
+        weak_connection,
+        weak_statement,
+        cancel,
         last_insert_rowid,
         changes,
+        begin,
+        commit,
+        rollback,
+        savepoint,
+        release_savepoint,
+        rollback_to,
+        in_transaction,
         step_by,
         column_name,
         set_busy_timeout,
+        set_default_timeout,
+        set_idle_timeouts,
+        set_transaction_lease,
         generate_uuid,
         delete_file,
         delete_bucket,
-
+        snapshot_context,
+        restore_context,
+        restore_home,
+        state_digest,
+        health,
+        restart_context,
+        open_context_async,
+        create_connection_async,
+        set_busy_timeout_async,
+        prepare_async,
+        execute_async,
+        execute_named_async,
+        execute_many_async,
+        execute_script_async,
+        classify_async,
+        step_by_async,
+        bind_async,
+        bind_named_async,
+        bind_all_async,
+        column_names_async,
+        column_count_async,
+        column_metadata_async,
+        column_name_async,
+        bind_parameter_count_async,
+        clear_bindings_async,
+        clone_and_reset_async,
+        finalize_async,
+        close_async,
+        last_insert_rowid_async,
+        changes_async,
+        closed_async,
+        finalized_async,
+        snapshot_context_async,
+        state_digest_async,
     ],
     load = load
 );
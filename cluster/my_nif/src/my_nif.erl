@@ -3,28 +3,93 @@
 -export([
     %start/2,
     create_context/1,
+    open_context/2,
     set_busy_timeout/3,
+    set_default_timeout/2,
+    set_idle_timeouts/3,
+    set_transaction_lease/3,
     create_connection/3,
-    prepare/3,
+    create_connection/4,
+    prepare/4,
+    prepare/5,
     bind/5,
-    column_names/3,
-    column_count/3,
-    execute/4,
-    bind_parameter_count/3,
+    bind_named/5,
+    bind_all/4,
+    column_names/4,
+    column_count/4,
+    column_metadata/4,
+    execute/7,
+    execute_named/7,
+    execute_many/7,
+    execute_script/6,
+    classify/4,
+    bind_parameter_count/4,
+    bind_parameter_name/4,
+    bind_parameter_index/4,
     clear_bindings/3,
     finalize/3,
     close/2,
-    last_insert_rowid/2,
-    changes/2,
+    release/2,
+    interrupt/2,
+    weak_connection/2,
+    weak_statement/3,
+    cancel/3,
+    last_insert_rowid/3,
+    changes/3,
+    'begin'/4,
+    commit/3,
+    rollback/3,
+    savepoint/4,
+    release_savepoint/4,
+    rollback_to/4,
+    in_transaction/2,
     list_buckets/1,
-    step_by/4,
+    list_connections/1,
+    list_statements/2,
+    step_by/7,
     clone_and_reset/3,
     lib_version/1,
-    column_name/4,
+    column_name/5,
     generate_uuid/0,
     list_files/2,
     delete_file/3,
     delete_bucket/2,
+    snapshot_context/2,
+    restore_context/2,
+    restore_home/2,
+    state_digest/1,
+    health/1,
+    restart_context/1,
+    open_context_async/2,
+    create_connection_async/3,
+    set_busy_timeout_async/3,
+    prepare_async/4,
+    execute_async/7,
+    execute_named_async/7,
+    execute_many_async/7,
+    execute_script_async/6,
+    classify_async/3,
+    step_by_async/7,
+    bind_async/5,
+    bind_named_async/5,
+    bind_all_async/4,
+    column_names_async/3,
+    column_count_async/3,
+    column_metadata_async/3,
+    column_name_async/4,
+    bind_parameter_count_async/3,
+    clear_bindings_async/3,
+    clone_and_reset_async/3,
+    finalize_async/3,
+    close_async/2,
+    last_insert_rowid_async/2,
+    changes_async/2,
+    closed_async/2,
+    finalized_async/3,
+    snapshot_context_async/2,
+    state_digest_async/1,
+    await/2,
+    flush/1,
     main/0
 ]).
 
@@ -36,23 +101,120 @@
 %%% API
 %%%===================================================================
 
+% Errors are {error, #{kind := atom(), message := binary()}}, the map also has
+% code and extended_code for sqlite errors, offset when the error is about a
+% token of the query and parameter when binding it failed. The kind is the
+% name of the primary sqlite result code (busy, constraint, ...) or one of
+% parameter, column, conversion, misuse, internal, io, interrupted,
+% budget_exceeded, timeout, expired, lease_expired and worker_crashed.
+
 set_busy_timeout(_Ctx, _Conn, _Timeout) -> ?NOT_LOADED.
 
+% milliseconds or nil, a call running longer is interrupted and returns
+% {error, #{kind := timeout}}. Not for a context applying raft entries, each
+% replica would time out on its own clock, the calls served by a single node
+% take their own Timeout instead.
+set_default_timeout(_Ctx, _Timeout) -> ?NOT_LOADED.
+
+% milliseconds or nil, an idle statement is finalized and an idle connection
+% closed, later calls on them return {error, #{kind := expired}}. Not for a
+% context applying raft entries, erlang_project_ra closes them through the log.
+set_idle_timeouts(_Ctx, _Statement, _Connection) -> ?NOT_LOADED.
+
+% milliseconds or nil, raft entries or nil. An open transaction is rolled back
+% and its connection closed when that many entries are applied since its last
+% replicated call, or, on a connection without replicated calls, after that
+% long without a call. Later calls return {error, #{kind := lease_expired}}
+set_transaction_lease(_Ctx, _Duration, _Entries) -> ?NOT_LOADED.
+
 create_context(_Home) -> ?NOT_LOADED.
 
+% Options is {Create :: boolean(), skip | quick | full}, returns {Ctx, #{files := [Report]}}
+% where Report is #{bucket, file, size, hot_journal, wal, integrity := [binary()], apply_index}
+open_context(_Home, _Options) -> ?NOT_LOADED.
+
 create_connection(_Ctx, _Bucket, _File) -> ?NOT_LOADED.
 
-prepare(_Ctx, _Conn, _Query) -> ?NOT_LOADED.
+% the connection is released when Owner exits
+create_connection(Ctx, Bucket, File, Owner) ->
+    case create_connection(Ctx, Bucket, File) of
+        {ok, Conn} = Rv ->
+            {ok, Weak} = weak_connection(Ctx, Conn),
+            watch_owner(Owner, fun() -> closed_async(Ctx, Weak) end, fun() -> release(Ctx, Weak) end),
+            Rv;
+        Error ->
+            Error
+    end.
+
+% Budget is nil or the default budget of the step_by calls on the statement
+prepare(_Ctx, _Conn, _Query, _Budget) -> ?NOT_LOADED.
+
+% the statement is finalized when Owner exits
+prepare(Ctx, Conn, Query, Budget, Owner) ->
+    case prepare(Ctx, Conn, Query, Budget) of
+        {ok, Stmt} = Rv ->
+            {ok, WeakConn} = weak_connection(Ctx, Conn),
+            {ok, WeakStmt} = weak_statement(Ctx, Conn, Stmt),
+            watch_owner(
+                Owner,
+                fun() -> finalized_async(Ctx, WeakConn, WeakStmt) end,
+                fun() -> finalize(Ctx, WeakConn, WeakStmt) end
+            ),
+            Rv;
+        Error ->
+            Error
+    end.
 
 bind(_Ctx, _Conn, _Stmt, _N, _Value) -> ?NOT_LOADED.
 
-column_names(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+% Name with its prefix, e.g. <<":id">>, <<"@id">> or <<"$id">>
+bind_named(_Ctx, _Conn, _Stmt, _Name, _Value) -> ?NOT_LOADED.
+
+% Params is a list with a value for every parameter or a map from their names,
+% either all of them are bound or none
+bind_all(_Ctx, _Conn, _Stmt, _Params) -> ?NOT_LOADED.
+
+% Timeout is milliseconds or nil for the default timeout of the context, the
+% same goes for the calls below that take one
+column_names(_Ctx, _Conn, _Stmt, _Timeout) -> ?NOT_LOADED.
+
+column_count(_Ctx, _Conn, _Stmt, _Timeout) -> ?NOT_LOADED.
+
+% a map per column with name and, where known, decl_type, database, table, column,
+% not_null, primary_key and autoincrement. Read when the statement is prepared,
+% the origin and the flags need the nif built with the column_metadata feature.
+column_metadata(_Ctx, _Conn, _Stmt, _Timeout) -> ?NOT_LOADED.
 
-column_count(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+% Determinism is nil or {Seed, TimestampMillis, Strict}, ApplyIndex is nil or the raft index
+% of the entry, writes at or below the index stored in the file are skipped, Budget is nil
+% or the maximum number of virtual machine steps, {error, #{kind := budget_exceeded}} otherwise
+execute(_Ctx, _Conn, _Query, _Params, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
 
-execute(_Ctx, _Conn, _Query, _Params) -> ?NOT_LOADED.
+% Params is a map from the parameter names, with their prefix, to the values
+execute_named(_Ctx, _Conn, _Query, _Params, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
 
-bind_parameter_count(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+% Query is prepared once and run with every list of Rows in one savepoint,
+% {ok, {Total, [Changes]}}. A failing set rolls back the others, the error
+% has its index as row.
+execute_many(_Ctx, _Conn, _Query, _Rows, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
+
+% runs every statement of Sql in order, {ok, [Changes]} with one entry per
+% statement, the error has statement and statement_offset when one failed.
+% With an ApplyIndex the script runs in one savepoint and cannot hold BEGIN
+% or COMMIT, without one the statements before a failing one stay applied.
+execute_script(_Ctx, _Conn, _Sql, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
+
+% #{kind := query | dml | ddl | transaction | pragma | attach | maintenance | explain | other,
+%   read_only := boolean()}
+classify(_Ctx, _Conn, _Query, _Timeout) -> ?NOT_LOADED.
+
+bind_parameter_count(_Ctx, _Conn, _Stmt, _Timeout) -> ?NOT_LOADED.
+
+% {ok, nil} for a plain ? or an index out of range
+bind_parameter_name(_Ctx, _Conn, _Stmt, _N) -> ?NOT_LOADED.
+
+% {ok, nil} when the query has no parameter with that name
+bind_parameter_index(_Ctx, _Conn, _Stmt, _Name) -> ?NOT_LOADED.
 
 clear_bindings(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
 
@@ -60,13 +222,47 @@ finalize(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
 
 close(_Ctx, _Conn) -> ?NOT_LOADED.
 
-last_insert_rowid(_Ctx, _Conn) -> ?NOT_LOADED.
+% rolls back the open transaction, finalizes the statements and closes Conn
+release(_Ctx, _Conn) -> ?NOT_LOADED.
+
+% the interrupted call returns {error, #{kind := interrupted}}
+interrupt(_Ctx, _Conn) -> ?NOT_LOADED.
+
+% a handle that does not keep the connection or the statement open, they are
+% still closed when the last of the others is collected
+weak_connection(_Ctx, _Conn) -> ?NOT_LOADED.
+
+weak_statement(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+
+% interrupts only while Stmt is being stepped, {ok, false} otherwise
+cancel(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
 
-changes(_Ctx, _Conn) -> ?NOT_LOADED.
+last_insert_rowid(_Ctx, _Conn, _Timeout) -> ?NOT_LOADED.
 
-step_by(_Ctx, _Conn, _Stmt, _N) -> ?NOT_LOADED.
+changes(_Ctx, _Conn, _Timeout) -> ?NOT_LOADED.
 
-column_name(_Ctx, _Conn, _Stmt, _N) -> ?NOT_LOADED.
+% Mode is deferred, immediate or exclusive. Closing or dropping Conn in the
+% middle of a transaction rolls it back.
+'begin'(_Ctx, _Conn, _Mode, _ApplyIndex) -> ?NOT_LOADED.
+
+% with an apply index, a commit of a transaction that wrote records it in the
+% file together with the writes
+commit(_Ctx, _Conn, _ApplyIndex) -> ?NOT_LOADED.
+
+rollback(_Ctx, _Conn, _ApplyIndex) -> ?NOT_LOADED.
+
+% a savepoint outside of a transaction starts one, releasing it commits
+savepoint(_Ctx, _Conn, _Name, _ApplyIndex) -> ?NOT_LOADED.
+
+release_savepoint(_Ctx, _Conn, _Name, _ApplyIndex) -> ?NOT_LOADED.
+
+rollback_to(_Ctx, _Conn, _Name, _ApplyIndex) -> ?NOT_LOADED.
+
+in_transaction(_Ctx, _Conn) -> ?NOT_LOADED.
+
+step_by(_Ctx, _Conn, _Stmt, _N, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
+
+column_name(_Ctx, _Conn, _Stmt, _N, _Timeout) -> ?NOT_LOADED.
 
 generate_uuid() -> ?NOT_LOADED.
 
@@ -76,12 +272,134 @@ list_files(_Ctx, _Bucket) -> ?NOT_LOADED.
 
 list_buckets(_Ctx) -> ?NOT_LOADED.
 
+% maps with id, bucket, file, opened, last_activity (unix millis) and closed
+list_connections(_Ctx) -> ?NOT_LOADED.
+
+% maps with id, query, bound_values, rows_stepped and mid_step
+list_statements(_Ctx, _Conn) -> ?NOT_LOADED.
+
 lib_version(_Ctx) -> ?NOT_LOADED.
 
 delete_file(_Ctx, _Bucket, _File) -> ?NOT_LOADED.
 
 delete_bucket(_Ctx, _Bucket) -> ?NOT_LOADED.
 
+snapshot_context(_Ctx, _Dest) -> ?NOT_LOADED.
+
+restore_context(_Home, _Archive) -> ?NOT_LOADED.
+
+% the home of Ctx must be empty
+restore_home(_Ctx, _Archive) -> ?NOT_LOADED.
+
+% #{digest := binary(), files := [#{bucket, file, schema, digest, tables := [#{name, digest}]}]}
+state_digest(_Ctx) -> ?NOT_LOADED.
+
+% #{context_alive := boolean(), connections, statements, crashes, last_crash}
+% a call to a connection or statement whose worker panicked returns
+% {error, #{kind := worker_crashed}}
+health(_Ctx) -> ?NOT_LOADED.
+
+% replaces the thread that opens connections, the open ones keep working
+restart_context(_Ctx) -> ?NOT_LOADED.
+
+%%%===================================================================
+%%% Async API
+%%%===================================================================
+
+% The *_async variants take the same arguments as the blocking ones, return a
+% reference right away and send {Ref, Result} to the caller once the call is done.
+% The worker of the connection sends it, open_context_async, snapshot_context_async
+% and state_digest_async have none and run on a dirty scheduler instead.
+
+open_context_async(_Home, _Options) -> ?NOT_LOADED.
+
+create_connection_async(_Ctx, _Bucket, _File) -> ?NOT_LOADED.
+
+set_busy_timeout_async(_Ctx, _Conn, _Timeout) -> ?NOT_LOADED.
+
+prepare_async(_Ctx, _Conn, _Query, _Budget) -> ?NOT_LOADED.
+
+execute_async(_Ctx, _Conn, _Query, _Params, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
+
+execute_named_async(_Ctx, _Conn, _Query, _Params, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
+
+execute_many_async(_Ctx, _Conn, _Query, _Rows, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
+
+execute_script_async(_Ctx, _Conn, _Sql, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
+
+classify_async(_Ctx, _Conn, _Query) -> ?NOT_LOADED.
+
+step_by_async(_Ctx, _Conn, _Stmt, _N, _Determinism, _ApplyIndex, _Budget) -> ?NOT_LOADED.
+
+bind_async(_Ctx, _Conn, _Stmt, _N, _Value) -> ?NOT_LOADED.
+
+bind_named_async(_Ctx, _Conn, _Stmt, _Name, _Value) -> ?NOT_LOADED.
+
+bind_all_async(_Ctx, _Conn, _Stmt, _Params) -> ?NOT_LOADED.
+
+column_names_async(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+
+column_count_async(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+
+column_metadata_async(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+
+column_name_async(_Ctx, _Conn, _Stmt, _N) -> ?NOT_LOADED.
+
+bind_parameter_count_async(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+
+clear_bindings_async(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+
+clone_and_reset_async(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+
+finalize_async(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+
+close_async(_Ctx, _Conn) -> ?NOT_LOADED.
+
+last_insert_rowid_async(_Ctx, _Conn) -> ?NOT_LOADED.
+
+changes_async(_Ctx, _Conn) -> ?NOT_LOADED.
+
+% {Ref, {ok, {}}} comes once the connection is closed or the statement
+% finalized, however that happens
+closed_async(_Ctx, _Conn) -> ?NOT_LOADED.
+
+finalized_async(_Ctx, _Conn, _Stmt) -> ?NOT_LOADED.
+
+snapshot_context_async(_Ctx, _Dest) -> ?NOT_LOADED.
+
+state_digest_async(_Ctx) -> ?NOT_LOADED.
+
+% The call keeps running after a timeout and its {Ref, _} answer still comes
+% later, it stays in the mailbox until flush/1 drops it.
+await(Ref, Timeout) ->
+    receive
+        {Ref, Result} -> Result
+    after Timeout ->
+        {error, timeout}
+    end.
+
+% drop the answer to Ref if it came, tell whether it did
+flush(Ref) ->
+    receive
+        {Ref, _} -> true
+    after 0 ->
+        false
+    end.
+
+% rustler 0.30 resources cannot monitor a process, a watcher does it with weak
+% handles, so that the resource can still be collected. It stops without
+% cleaning up when the connection or the statement is gone first.
+watch_owner(Owner, Closed, Cleanup) ->
+    spawn(fun() ->
+        Ref = erlang:monitor(process, Owner),
+        ClosedRef = Closed(),
+        receive
+            {'DOWN', Ref, process, Owner, _Reason} -> Cleanup();
+            {ClosedRef, _} -> erlang:demonitor(Ref, [flush])
+        end
+    end),
+    ok.
+
 %%%===================================================================
 %%% NIF
 %%%===================================================================
@@ -134,18 +452,18 @@ main() ->
     {ok, Ctx} = create_context(<<"/tmp">>),
     {ok, Conn} = create_connection(Ctx, <<"db">>, <<"test.db">>),
     % create a table
-    {ok, Count} = execute(Ctx, Conn, <<"CREATE TABLE IF NOT EXISTS foo (id INTEGER PRIMARY KEY, name TEXT NOT NULL, age INTEGER NOT NULL)">>, []),
+    {ok, Count} = execute(Ctx, Conn, <<"CREATE TABLE IF NOT EXISTS foo (id INTEGER PRIMARY KEY, name TEXT NOT NULL, age INTEGER NOT NULL)">>, [], nil, nil, nil),
     io:format("create table: ~p~n", [Count]),
     % insert some data
-    {ok, Stmt} = prepare(Ctx, Conn, <<"INSERT INTO foo (name, age) VALUES ('Alice', 20)">>),
+    {ok, Stmt} = prepare(Ctx, Conn, <<"INSERT INTO foo (name, age) VALUES ('Alice', 20)">>, nil),
     %bind(Ctx, Conn, Stmt, 1, {<<"Alice">>),
     %bind(Ctx, Conn, Stmt, 2, 20),
-    {ok, Res} = step_by(Ctx, Conn, Stmt, 1),
+    {ok, Res} = step_by(Ctx, Conn, Stmt, 1, nil, nil, nil),
     io:format("insert: ~p~n", [Res]),
     finalize(Ctx, Conn, Stmt),
     % select some data
-    {ok, Stmt1} = prepare(Ctx, Conn, <<"SELECT * FROM foo">>),
-    {ok, Res1} = step_by(Ctx, Conn, Stmt1, 1),
+    {ok, Stmt1} = prepare(Ctx, Conn, <<"SELECT * FROM foo">>, nil),
+    {ok, Res1} = step_by(Ctx, Conn, Stmt1, 1, nil, nil, nil),
     io:format("select: ~p~n", [Res1]),
     finalize(Ctx, Conn, Stmt1).
 
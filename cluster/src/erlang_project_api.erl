@@ -6,7 +6,7 @@
   [
     %
     % api for cowboy handler
-    get_target_func/1,
+    get_target_func/3,
     %
     % wrap my_nif functions
     list_buckets/2,
@@ -16,9 +16,14 @@
     set_busy_timeout/2,
     prepare/2,
     bind/2,
+    bind_all/2,
     column_names/2,
     column_count/2,
+    column_metadata/2,
     execute/2,
+    execute_many/2,
+    execute_script/2,
+    classify/2,
     bind_parameter_count/2,
     clear_bindings/2,
     finalize/2,
@@ -29,13 +34,24 @@
     reset/2,
     column_name/2,
     delete_file/2,
-    delete_bucket/2
+    delete_bucket/2,
+    state_digest/2,
+    %
+    % appended by the leader, see erlang_project_ra:tick/2
+    reap_idle/2,
+    idle/4,
+    %
+    % cancel a read only step running on this node
+    cancel_cursor/1,
+    cancel_on_leader/2
   ]
 ).
 
--spec get_target_func(Target :: binary()) ->
+-define(CURSORS, erlang_project_cursors).
+
+-spec get_target_func(Target :: binary(), Args :: api_args(), LocalServer :: ra:server_id()) ->
   {ok, api_fun(), ClusterWideOp :: boolean()} | {error, Reason :: atom()}.
-get_target_func(Target) ->
+get_target_func(Target, Args, LocalServer) ->
   ApiFun =
     case Target of
       <<"lib_version">> -> {ok, fun lib_version/2, false};
@@ -45,20 +61,33 @@ get_target_func(Target) ->
       <<"set_busy_timeout">> -> {ok, fun set_busy_timeout/2, true};
       <<"prepare">> -> {ok, fun prepare/2, true};
       <<"bind">> -> {ok, fun bind/2, true};
+      <<"bind_all">> -> {ok, fun bind_all/2, true};
       <<"column_names">> -> {ok, fun column_names/2, false};
       <<"column_count">> -> {ok, fun column_count/2, false};
+      <<"column_metadata">> -> {ok, fun column_metadata/2, false};
       <<"execute">> -> {ok, fun execute/2, true};
+      <<"execute_many">> -> {ok, fun execute_many/2, true};
+      <<"execute_script">> -> {ok, fun execute_script/2, true};
+      <<"classify">> -> {ok, fun classify/2, false};
       <<"bind_parameter_count">> -> {ok, fun bind_parameter_count/2, false};
       <<"clear_bindings">> -> {ok, fun clear_bindings/2, true};
       <<"finalize">> -> {ok, fun finalize/2, true};
       <<"close">> -> {ok, fun close/2, true};
       <<"last_insert_rowid">> -> {ok, fun last_insert_rowid/2, false};
       <<"changes">> -> {ok, fun changes/2, false};
-      <<"step_by">> -> {ok, fun step_by/2, true};
+      % stepping a read only statement does not need the log, only the cursor of the
+      % leader advances so after a leader change the statement restarts from the first
+      % row. Such a step is not activity for the idle reaper, see erlang_project_ra:tick/2
+      <<"step_by">> ->
+        case read_only_stmt(LocalServer, Args) of
+          true -> {ok, fun step_read_only/2, false};
+          false -> {ok, fun step_by/2, true}
+        end;
       <<"reset">> -> {ok, fun reset/2, true};
       <<"column_name">> -> {ok, fun column_name/2, false};
       <<"delete_file">> -> {ok, fun delete_file/2, true};
       <<"delete_bucket">> -> {ok, fun delete_bucket/2, true};
+      <<"state_digest">> -> {ok, fun state_digest/2, true};
       _ -> {error, unknown_target}
     end,
   %
@@ -117,6 +146,19 @@ get_target_func(Target) ->
   end.
 
 
+% ids of connections and statements that are gone, e.g. opened before the snapshot
+% a replica was restored from, fail the call instead of the apply of the entry
+conn(ConnId, #app_state{conns = Conns}) -> lookup(ConnId, Conns, invalid_conn_id).
+
+stmt(StmtId, #app_state{stmts = Stmts}) -> lookup(StmtId, Stmts, invalid_stmt_id).
+
+lookup(Id, Map, Error) ->
+  case maps:find(Id, Map) of
+    {ok, Value} -> Value;
+    error -> throw(Error)
+  end.
+
+
 % {ok, version :: binary()}
 lib_version(#app_state{ctx = Ctx} = State, #{} = _Args) ->
   Version = my_nif:lib_version(Ctx),
@@ -130,7 +172,9 @@ create_connection(
   #app_state{index = Index, ctx = Ctx, conns = Conns} = State,
   #{<<"Bucket">> := Bucket, <<"File">> := File} = _Args
 ) ->
-  case my_nif:create_connection(Ctx, Bucket, File) of
+  % owned by the ra server applying the entry, a replica that dies releases its
+  % connections and their locks right away
+  case my_nif:create_connection(Ctx, Bucket, File, self()) of
     {ok, Conn} ->
       ConnId = Index,
       {State#app_state{conns = maps:put(ConnId, Conn, Conns)}, {ok, ConnId}};
@@ -152,10 +196,10 @@ list_buckets(S, _) -> {S, {error, invalid_args}}.
 
 % {ok , {}}
 set_busy_timeout(
-  #app_state{ctx = Ctx, conns = Conns} = State,
+  #app_state{ctx = Ctx} = State,
   #{<<"Conn">> := ConnId, <<"Timeout">> := Timeout} = _Args
 ) ->
-  Conn = maps:get(ConnId, Conns),
+  Conn = conn(ConnId, State),
   {State, my_nif:set_busy_timeout(Ctx, Conn, Timeout)};
 
 set_busy_timeout(S, _) -> {S, {error, invalid_args}}.
@@ -163,37 +207,121 @@ set_busy_timeout(S, _) -> {S, {error, invalid_args}}.
 
 % {ok, stmtid :: integer()}
 prepare(
-  #app_state{index = Index, ctx = Ctx, conns = Conns, stmts = Stmts} = State,
-  #{<<"Conn">> := ConnId, <<"Query">> := Query} = _Args
+  #app_state{index = Index, ctx = Ctx, stmts = Stmts} = State,
+  #{<<"Conn">> := ConnId, <<"Query">> := Query} = Args
 ) ->
-  Conn = maps:get(ConnId, Conns),
+  Conn = conn(ConnId, State),
   StmtId = Index,
-  case my_nif:prepare(Ctx, Conn, Query) of
-    {ok, Stmt} -> {State#app_state{stmts = maps:put(StmtId, Stmt, Stmts)}, {ok, StmtId}};
+  case my_nif:prepare(Ctx, Conn, Query, budget(Args), self()) of
+    {ok, Stmt} ->
+      State1 = State#app_state{stmts = maps:put(StmtId, Stmt, Stmts)},
+      {mark_read_only(State1, ConnId, Conn, Stmt, StmtId, Query), {ok, StmtId}};
+
     Err -> {State, Err}
   end;
 
 prepare(S, _) -> {S, {error, invalid_args}}.
 
 
+% classified once when the entry is applied, every replica marks the same statements
+mark_read_only(#app_state{ctx = Ctx, read_only_stmts = ReadOnly} = State, ConnId, Conn, Stmt, StmtId, Query) ->
+  case my_nif:classify(Ctx, Conn, Query, nil) of
+    {ok, #{kind := Kind, read_only := true}} when Kind =:= query; Kind =:= explain ->
+      register_cursor(StmtId, ConnId, Ctx, Conn, Stmt),
+      State#app_state{read_only_stmts = maps:put(StmtId, true, ReadOnly)};
+
+    _ -> State
+  end.
+
+
+% the local state is enough, statements are only marked by replicated prepare
+% calls, one the local replica did not apply yet goes through the log
+read_only_stmt(LocalServer, #{<<"Stmt">> := StmtId}) ->
+  case
+  erlang_project_ra:local_call(
+    LocalServer,
+    fun (#app_state{read_only_stmts = ReadOnly}) -> maps:is_key(StmtId, ReadOnly) end
+  ) of
+    {ok, IsReadOnly} -> IsReadOnly;
+    _ -> false
+  end;
+
+read_only_stmt(_, _) -> false.
+
+
+% read only statements are also kept in a public table, a step served by
+% single_node_call holds the ra process so a cancel cannot go through it
+register_cursor(StmtId, ConnId, Ctx, Conn, Stmt) ->
+  case ets:whereis(?CURSORS) of
+    undefined -> ets:new(?CURSORS, [named_table, public, set]);
+    _ -> ok
+  end,
+  ets:insert(?CURSORS, {StmtId, ConnId, Ctx, Conn, Stmt}).
+
+
+% a reset statement is a new resource, keep the cursor cancellable
+reregister_cursor(StmtId, Stmt) ->
+  case ets:whereis(?CURSORS) =/= undefined andalso ets:lookup(?CURSORS, StmtId) of
+    [{StmtId, ConnId, Ctx, Conn, _}] -> register_cursor(StmtId, ConnId, Ctx, Conn, Stmt);
+    _ -> ok
+  end.
+
+
+unregister_cursor(StmtId) ->
+  case ets:whereis(?CURSORS) of
+    undefined -> true;
+    _ -> ets:delete(?CURSORS, StmtId)
+  end.
+
+
+% {ok, {boolean(), ConnId}}, true if the statement was being stepped and got interrupted
+cancel_cursor(StmtId) ->
+  case ets:whereis(?CURSORS) =/= undefined andalso ets:lookup(?CURSORS, StmtId) of
+    [{StmtId, ConnId, Ctx, Conn, Stmt}] ->
+      case my_nif:cancel(Ctx, Conn, Stmt) of
+        {ok, Cancelled} -> {ok, {Cancelled, ConnId}};
+        Err -> Err
+      end;
+
+    _ -> {error, invalid_stmt_id}
+  end.
+
+
+% used by the http layer, e.g. when the client of a long read disconnects,
+% same return value as erlang_project_ra:single_node_call. Only the leader is
+% interrupted, the followers finish the step, a reset goes through the log
+% after it so that every replica starts the cursor over from the same entry.
+cancel_on_leader({_, Node} = Leader, #{<<"Stmt">> := StmtId}) ->
+  Result =
+    try
+      erpc:call(Node, ?MODULE, cancel_cursor, [StmtId])
+    catch
+      _ : Reason -> {error, Reason}
+    end,
+  case Result of
+    {ok, {true, ConnId}} ->
+      Args = #{<<"Conn">> => ConnId, <<"Stmt">> => StmtId},
+      case erlang_project_ra:cluster_call(Leader, fun reset/2, Args) of
+        {ok, {{ok, {}}, Index, Term}, Leader1} -> {ok, {[{ok, true}], Index, Term}, Leader1};
+        {ok, {ResetErr, Index, Term}, Leader1} -> {ok, {[ResetErr], Index, Term}, Leader1};
+        Other -> Other
+      end;
+
+    {ok, {false, _}} -> {ok, {[{ok, false}], undefined, undefined}, Leader};
+    Err -> {ok, {[Err], undefined, undefined}, Leader}
+  end;
+
+cancel_on_leader(Leader, _) -> {ok, {[{error, invalid_args}], undefined, undefined}, Leader}.
+
+
 % {ok, {}}
 bind(
-  #app_state{ctx = Ctx, conns = Conns, stmts = Stmts} = State,
+  #app_state{ctx = Ctx} = State,
   #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId, <<"N">> := N, <<"Value">> := Value} = _Args
 ) ->
-  Conn = maps:get(ConnId, Conns),
-  Stmt = maps:get(StmtId, Stmts),
-  % convert to sql format (i.e. [Type :: integer(), Value :: term()])
-  ValueSql =
-    case Value of
-      [0, null] -> {0, null};
-      [1, Int] when is_integer(Int) -> {1, Int};
-      [2, Num] when is_number(Num) -> {2, float(Num)};
-      [3, Str] when is_binary(Str) -> {3, Str};
-      [4, Blob] when is_binary(Blob) -> {4, Blob};
-      _ -> error
-    end,
-  case ValueSql of
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
+  case value_sql(Value) of
     error -> {State, {error, invalid_value}};
     V -> {State, my_nif:bind(Ctx, Conn, Stmt, N, V)}
   end;
@@ -201,61 +329,159 @@ bind(
 bind(S, _) -> {S, {error, invalid_args}}.
 
 
+% {ok, {}}, Params is a list with a value for every parameter or an object
+% from their names (with the prefix, e.g. ":id") to the values
+bind_all(
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId, <<"Params">> := Params} = _Args
+) ->
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
+  case params_sql(Params) of
+    error -> {State, {error, invalid_value}};
+    ParamsSql -> {State, my_nif:bind_all(Ctx, Conn, Stmt, ParamsSql)}
+  end;
+
+bind_all(S, _) -> {S, {error, invalid_args}}.
+
+
+params_sql(Params) when is_list(Params) ->
+  Values = [value_sql(V) || V <- Params],
+  case lists:member(error, Values) of
+    true -> error;
+    false -> Values
+  end;
+
+params_sql(Params) when is_map(Params) ->
+  case params_sql(maps:values(Params)) of
+    error -> error;
+    _ -> maps:map(fun (_Name, V) -> value_sql(V) end, Params)
+  end;
+
+params_sql(_) -> error.
+
+
+% convert to sql format (i.e. [Type :: integer(), Value :: term()])
+value_sql([0, null]) -> {0, null};
+value_sql([1, Int]) when is_integer(Int) -> {1, Int};
+value_sql([2, Num]) when is_number(Num) -> {2, float(Num)};
+value_sql([3, Str]) when is_binary(Str) -> {3, Str};
+value_sql([4, Blob]) when is_binary(Blob) -> {4, Blob};
+value_sql(_) -> error.
+
+
 % {ok, [binary()]}
 column_names(
-  #app_state{ctx = Ctx, conns = Conns, stmts = Stmts} = State,
-  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId} = _Args
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId} = Args
 ) ->
-  Conn = maps:get(ConnId, Conns),
-  Stmt = maps:get(StmtId, Stmts),
-  {State, my_nif:column_names(Ctx, Conn, Stmt)};
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
+  {State, my_nif:column_names(Ctx, Conn, Stmt, timeout(Args))};
 
 column_names(S, _) -> {S, {error, invalid_args}}.
 
 
 % {ok, integer()}
 column_count(
-  #app_state{ctx = Ctx, conns = Conns, stmts = Stmts} = State,
-  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId} = _Args
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId} = Args
 ) ->
-  Conn = maps:get(ConnId, Conns),
-  Stmt = maps:get(StmtId, Stmts),
-  {State, my_nif:column_count(Ctx, Conn, Stmt)};
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
+  {State, my_nif:column_count(Ctx, Conn, Stmt, timeout(Args))};
 
 column_count(S, _) -> {S, {error, invalid_args}}.
 
 
+% {ok, [map()]}, one map per column
+column_metadata(
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId} = Args
+) ->
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
+  {State, my_nif:column_metadata(Ctx, Conn, Stmt, timeout(Args))};
+
+column_metadata(S, _) -> {S, {error, invalid_args}}.
+
+
 % {ok, integer()}
 execute(
-  #app_state{ctx = Ctx, conns = Conns} = State,
-  #{<<"Conn">> := ConnId, <<"Query">> := Query, <<"Params">> := Params} = _Args
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Query">> := Query, <<"Params">> := Params} = Args
 ) ->
-  Conn = maps:get(ConnId, Conns),
+  Conn = conn(ConnId, State),
   ParamsSql = [{T, V} || [T, V] <- Params],
-  {State, my_nif:execute(Ctx, Conn, Query, ParamsSql)};
+  Res = my_nif:execute(Ctx, Conn, Query, ParamsSql, determinism(State), apply_index(State), budget(Args)),
+  {State, Res};
 
 execute(S, _) -> {S, {error, invalid_args}}.
 
 
+% {ok, #{total, changes}}, Rows is a list of Params lists, Query is run once
+% for each in one log entry
+execute_many(
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Query">> := Query, <<"Rows">> := Rows} = Args
+) when is_list(Rows) ->
+  Conn = conn(ConnId, State),
+  RowsSql = [params_sql(Params) || Params <- Rows, is_list(Params)],
+  case length(RowsSql) =:= length(Rows) andalso not lists:member(error, RowsSql) of
+    false -> {State, {error, invalid_value}};
+    true ->
+      Res = my_nif:execute_many(Ctx, Conn, Query, RowsSql, determinism(State), apply_index(State), budget(Args)),
+      case Res of
+        {ok, {Total, Changes}} -> {State, {ok, #{total => Total, changes => Changes}}};
+        Err -> {State, Err}
+      end
+  end;
+
+execute_many(S, _) -> {S, {error, invalid_args}}.
+
+
+% {ok, [Changes]}, one entry per statement of Sql
+execute_script(
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Sql">> := Sql} = Args
+) ->
+  Conn = conn(ConnId, State),
+  Res = my_nif:execute_script(Ctx, Conn, Sql, determinism(State), apply_index(State), budget(Args)),
+  {State, Res};
+
+execute_script(S, _) -> {S, {error, invalid_args}}.
+
+
+% {ok, #{kind := atom(), read_only := boolean()}}
+classify(
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Query">> := Query} = Args
+) ->
+  Conn = conn(ConnId, State),
+  {State, my_nif:classify(Ctx, Conn, Query, timeout(Args))};
+
+classify(S, _) -> {S, {error, invalid_args}}.
+
+
 % {ok, integer()}
 bind_parameter_count(
-  #app_state{ctx = Ctx, conns = Conns, stmts = Stmts} = State,
-  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId}
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId} = Args
 ) ->
-  Conn = maps:get(ConnId, Conns),
-  Stmt = maps:get(StmtId, Stmts),
-  {State, my_nif:bind_parameter_count(Ctx, Conn, Stmt)};
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
+  {State, my_nif:bind_parameter_count(Ctx, Conn, Stmt, timeout(Args))};
 
 bind_parameter_count(S, _) -> {S, {error, invalid_args}}.
 
 
 % {ok, boolean()}
 clear_bindings(
-  #app_state{ctx = Ctx, conns = Conns, stmts = Stmts} = State,
+  #app_state{ctx = Ctx} = State,
   #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId}
 ) ->
-  Conn = maps:get(ConnId, Conns),
-  Stmt = maps:get(StmtId, Stmts),
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
   {State, my_nif:clear_bindings(Ctx, Conn, Stmt)};
 
 clear_bindings(S, _) -> {S, {error, invalid_args}}.
@@ -263,23 +489,62 @@ clear_bindings(S, _) -> {S, {error, invalid_args}}.
 
 % {ok, {}}
 finalize(
-  #app_state{ctx = Ctx, conns = Conns, stmts = Stmts} = State,
+  #app_state{ctx = Ctx, stmts = Stmts} = State,
   #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId}
 ) ->
-  Conn = maps:get(ConnId, Conns),
-  Stmt = maps:get(StmtId, Stmts),
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
   % remove stmt from stmts
   _Res = my_nif:finalize(Ctx, Conn, Stmt),
   Stmts1 = maps:remove(StmtId, Stmts),
-  State1 = State#app_state{stmts = Stmts1},
+  ReadOnly1 = maps:remove(StmtId, State#app_state.read_only_stmts),
+  unregister_cursor(StmtId),
+  State1 = State#app_state{stmts = Stmts1, read_only_stmts = ReadOnly1},
   {State1, {ok, {}}};
 
 finalize(S, _) -> {S, {error, invalid_args}}.
 
 
+% {ok, integer()}, the number of statements and connections closed, the
+% timeouts are part of the args so that every replica closes the same ones
+reap_idle(
+  #app_state{timestamp = Now, used = Used} = State,
+  #{<<"Statement">> := _, <<"Connection">> := _} = Args
+) ->
+  Idle = [Key || {Key, Last} <- maps:to_list(Used), idle(Key, Last, Now, Args)],
+  % {stmt, _, _} sorts after {conn, _}, statements go first
+  State1 = lists:foldl(fun reap/2, State, lists:reverse(lists:sort(Idle))),
+  {State1#app_state{used = maps:without(Idle, State1#app_state.used)}, {ok, length(Idle)}};
+
+reap_idle(S, _) -> {S, {error, invalid_args}}.
+
+
+% milliseconds since the last use, 0 disables the timeout
+idle({conn, _}, Last, Now, #{<<"Connection">> := Timeout}) -> Timeout > 0 andalso Now - Last >= Timeout;
+idle({stmt, _, _}, Last, Now, #{<<"Statement">> := Timeout}) -> Timeout > 0 andalso Now - Last >= Timeout.
+
+
+reap({stmt, ConnId, StmtId}, #app_state{conns = Conns, stmts = Stmts} = State) ->
+  case maps:is_key(ConnId, Conns) of
+    true ->
+      {State1, _} = finalize(State, #{<<"Conn">> => ConnId, <<"Stmt">> => StmtId}),
+      State1;
+
+    % its connection is closed, the collected resource finalizes it
+    false ->
+      unregister_cursor(StmtId),
+      ReadOnly = maps:remove(StmtId, State#app_state.read_only_stmts),
+      State#app_state{stmts = maps:remove(StmtId, Stmts), read_only_stmts = ReadOnly}
+  end;
+
+reap({conn, ConnId}, State) ->
+  {State1, _} = close(State, #{<<"Conn">> => ConnId}),
+  State1.
+
+
 % {ok, {}}
 close(#app_state{ctx = Ctx, conns = Conns} = State, #{<<"Conn">> := ConnId} = _Args) ->
-  Conn = maps:get(ConnId, Conns),
+  Conn = conn(ConnId, State),
   % remove conn from conns
   _Res = my_nif:close(Ctx, Conn),
   Conns1 = maps:remove(ConnId, Conns),
@@ -290,29 +555,35 @@ close(S, _) -> {S, {error, invalid_args}}.
 
 
 % {ok, integer()}
-last_insert_rowid(#app_state{ctx = Ctx, conns = Conns} = State, #{<<"Conn">> := ConnId} = _Args) ->
-  Conn = maps:get(ConnId, Conns),
-  {State, my_nif:last_insert_rowid(Ctx, Conn)};
+last_insert_rowid(#app_state{ctx = Ctx} = State, #{<<"Conn">> := ConnId} = Args) ->
+  Conn = conn(ConnId, State),
+  {State, my_nif:last_insert_rowid(Ctx, Conn, timeout(Args))};
 
 last_insert_rowid(S, _) -> {S, {error, invalid_args}}.
 
 
 % {ok, integer()}
-changes(#app_state{ctx = Ctx, conns = Conns} = State, #{<<"Conn">> := ConnId} = _Args) ->
-  Conn = maps:get(ConnId, Conns),
-  {State, my_nif:changes(Ctx, Conn)};
+changes(#app_state{ctx = Ctx} = State, #{<<"Conn">> := ConnId} = Args) ->
+  Conn = conn(ConnId, State),
+  {State, my_nif:changes(Ctx, Conn, timeout(Args))};
 
 changes(S, _) -> {S, {error, invalid_args}}.
 
 
 % {ok, [ Rows :: [ Vals :: [Type :: integer(), Value :: term()] ] ]
-step_by(
-  #app_state{ctx = Ctx, conns = Conns, stmts = Stmts} = State,
-  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId, <<"N">> := N} = _Args
+step_by(State, Args) -> step(State, Args, apply_index(State)).
+
+% served by the leader outside the log, there is no entry to stamp
+step_read_only(State, Args) -> step(State, Args, nil).
+
+step(
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId, <<"N">> := N} = Args,
+  ApplyIndex
 ) ->
-  Conn = maps:get(ConnId, Conns),
-  Stmt = maps:get(StmtId, Stmts),
-  Res = my_nif:step_by(Ctx, Conn, Stmt, N),
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
+  Res = my_nif:step_by(Ctx, Conn, Stmt, N, determinism(State), ApplyIndex, budget(Args)),
   % convert to jsx serializable format
   JsxRes =
     case Res of
@@ -322,31 +593,53 @@ step_by(
     end,
   {State, JsxRes};
 
-step_by(S, _) -> {S, {error, invalid_args}}.
+step(S, _, _) -> {S, {error, invalid_args}}.
+
+
+% seed random() and "now" from the log entry being applied so that replicas agree
+determinism(#app_state{index = Index, term = Term, timestamp = Timestamp}) ->
+  {erlang:phash2({Index, Term}), Timestamp, false}.
+
+% writes are stamped with the raft index in the database file, so that
+% entries replayed after a restart are not applied twice
+apply_index(#app_state{index = Index}) -> Index.
+
+
+% maximum number of sqlite virtual machine steps, it is part of the args so that
+% every replica enforces the same one, see erlang_project_cow:with_default_budget/1
+budget(#{<<"Budget">> := Budget}) when is_integer(Budget), Budget > 0 -> Budget;
+budget(_) -> nil.
+
+
+% milliseconds, only for the calls served by a single node, the replicated
+% ones would give up at a different point on every replica
+timeout(#{<<"Timeout">> := Timeout}) when is_integer(Timeout), Timeout > 0 -> Timeout;
+timeout(_) -> nil.
 
 
 % {ok, binary()}
 column_name(
-  #app_state{ctx = Ctx, conns = Conns, stmts = Stmts} = State,
-  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId, <<"N">> := N} = _Args
+  #app_state{ctx = Ctx} = State,
+  #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId, <<"N">> := N} = Args
 ) ->
-  Conn = maps:get(ConnId, Conns),
-  Stmt = maps:get(StmtId, Stmts),
-  {State, my_nif:column_name(Ctx, Conn, Stmt, N)};
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
+  {State, my_nif:column_name(Ctx, Conn, Stmt, N, timeout(Args))};
 
 column_name(S, _) -> {S, {error, invalid_args}}.
 
 
 % {ok, {}}
 reset(
-  #app_state{ctx = Ctx, conns = Conns, stmts = Stmts} = State,
+  #app_state{ctx = Ctx, stmts = Stmts} = State,
   #{<<"Conn">> := ConnId, <<"Stmt">> := StmtId} = _Args
 ) ->
-  Conn = maps:get(ConnId, Conns),
-  Stmt = maps:get(StmtId, Stmts),
+  Conn = conn(ConnId, State),
+  Stmt = stmt(StmtId, State),
   case my_nif:clone_and_reset(Ctx, Conn, Stmt) of
     {ok, NewStmt} ->
       my_nif:finalize(Ctx, Conn, Stmt),
+      reregister_cursor(StmtId, NewStmt),
       {State#app_state{stmts = maps:put(StmtId, NewStmt, Stmts)}, {ok, {}}};
 
     Err -> {State, Err}
@@ -366,3 +659,14 @@ delete_bucket(#app_state{ctx = Ctx} = State, #{<<"Bucket">> := Bucket} = _Args)
   {State, my_nif:delete_bucket(Ctx, Bucket)};
 
 delete_bucket(S, _) -> {S, {error, invalid_args}}.
+
+
+% {ok, binary()}
+% every replica computes the digest at the same index and keeps it for erlang_project_ra:check_divergence/1
+state_digest(#app_state{index = Index, ctx = Ctx} = State, #{} = _Args) ->
+  case my_nif:state_digest(Ctx) of
+    {ok, #{digest := Digest} = Full} -> {State#app_state{digest = {Index, Full}}, {ok, Digest}};
+    Err -> {State, Err}
+  end;
+
+state_digest(S, _) -> {S, {error, invalid_args}}.
@@ -22,19 +22,25 @@
 init(Req = #{method := <<"POST">>, path := _Path}, State) ->
   Leader = proplists:get_value(leader, State),
   {ok, Body, _} = cowboy_req:read_body(Req),
-  Args = jsx:decode(Body, [return_maps]),
+  Args = with_default_budget(jsx:decode(Body, [return_maps])),
   ReqQueryParams = cowboy_req:parse_qs(Req),
   Target = proplists:get_value(<<"target">>, ReqQueryParams, undefined),
   logger:debug("Target: ~p, Args: ~p~n", [Target, Args]),
   Result =
-    case erlang_project_api:get_target_func(Target) of
-      {error, unknown_target} -> {client_error, <<"unknown target">>};
+    case Target of
+      % bypasses ra, the step to cancel keeps the leader busy
+      <<"cancel">> -> erlang_project_api:cancel_on_leader(Leader, Args);
 
-      {ok, TargetFunc, _ClusterWideOp = false} ->
-        erlang_project_ra:single_node_call(Leader, TargetFunc, Args);
+      _ ->
+        case erlang_project_api:get_target_func(Target, Args, {element(1, Leader), node()}) of
+          {error, unknown_target} -> {client_error, <<"unknown target">>};
 
-      {ok, TargetFunc, _ClusterWideOp = true} ->
-        erlang_project_ra:cluster_call(Leader, TargetFunc, Args)
+          {ok, TargetFunc, _ClusterWideOp = false} ->
+            erlang_project_ra:single_node_call(Leader, TargetFunc, Args);
+
+          {ok, TargetFunc, _ClusterWideOp = true} ->
+            erlang_project_ra:cluster_call(Leader, TargetFunc, Args)
+        end
     end,
   Resp =
     case Result of
@@ -67,7 +73,8 @@ init(Req = #{method := <<"POST">>, path := _Path}, State) ->
           case Output of
             % ok response from rust nif
             [{ok, _}] -> 200;
-            % error response from rust nif, probably a query error
+            % error response from rust nif
+            [{error, #{kind := Kind}}] -> error_status(Kind);
             [{error, _}] -> 400;
             _ShouldNotHappen -> 500
           end,
@@ -87,5 +94,34 @@ init(Req, State) ->
   {ok, Resp, State}.
 
 
+% the step budget is added before the command is proposed, replicas configured
+% differently still apply the same budget. A client can ask for less than the
+% configured one, never for more.
+with_default_budget(Args) when is_map(Args) ->
+  case {application:get_env(erlang_project, step_budget), maps:get(<<"Budget">>, Args, nil)} of
+    {{ok, Budget}, ClientBudget} when is_integer(Budget), Budget > 0 ->
+      case is_integer(ClientBudget) andalso ClientBudget > 0 of
+        true -> Args#{<<"Budget">> => min(ClientBudget, Budget)};
+        false -> Args#{<<"Budget">> => Budget}
+      end;
+
+    _ -> Args
+  end;
+
+with_default_budget(Args) -> Args.
+
+
+% the status of an error returned by the nif, by kind
+error_status(Kind) when Kind =:= busy; Kind =:= locked -> 503;
+error_status(constraint) -> 409;
+error_status(timeout) -> 504;
+error_status(Kind) when Kind =:= expired; Kind =:= lease_expired -> 410;
+error_status(Kind)
+  when Kind =:= internal; Kind =:= worker_crashed; Kind =:= io; Kind =:= ioerr; Kind =:= corrupt; Kind =:= nomem;
+       Kind =:= full; Kind =:= cantopen; Kind =:= notadb; Kind =:= protocol ->
+  500;
+error_status(_Kind) -> 400.
+
+
 % convert any erlang term to string
 erl_to_str(Term) -> lists:flatten(io_lib:format("~p", [Term])).
@@ -19,28 +19,88 @@
 
 -include_lib("erlang_project.hrl").
 
+-define(CONTEXT, erlang_project_context).
+% index of the last snapshot restored into the context
+-define(RESTORED, erlang_project_restored).
+
 -export(
   [
     % ra_machine
     init/1,
     apply/3,
+    tick/2,
     % user api
     single_node_call/3,
-    cluster_call/3
+    cluster_call/3,
+    local_call/2,
+    check_divergence/1,
+    first_difference/2,
+    % copied by a replica that was sent the snapshot of this node
+    read_archive/1
   ]
 ).
 
 init(_Config) ->
-  % create a unique data dir for each run because in case of a crash we will restart from zero
-  {ok, DataDir} = application:get_env(erlang_project, sql_data_dir),
-  {Mega, Sec, Micro} = erlang:timestamp(),
-  Timestamp = integer_to_list(Mega * 1000000000000 + Sec * 1000000 + Micro),
-  UniqueDataDir = filename:join([DataDir, Timestamp]),
-  filelib:ensure_dir(UniqueDataDir),
-  {ok, Ctx} = my_nif:create_context(list_to_binary(UniqueDataDir)),
+  % writes are stamped with their raft index, the log replayed on top of the
+  % previous data only applies what is missing
+  {ok, {Ctx, Inventory}} = my_nif:open_context(home_dir(), {true, quick}),
+  report_inventory(Inventory),
+  % a state recovered from a snapshot restores its databases into this context,
+  % there is one context per home
+  persistent_term:put(?CONTEXT, Ctx),
   #app_state{index = 0, term = 0, ctx = Ctx, conns = #{}, stmts = #{}}.
 
 
+home_dir() ->
+  {ok, DataDir} = application:get_env(erlang_project, sql_data_dir),
+  HomeDir = filename:join([DataDir, "home"]),
+  filelib:ensure_dir(HomeDir),
+  list_to_binary(HomeDir).
+
+
+report_inventory(#{files := Files}) ->
+  lists:foreach(
+    fun
+      (#{bucket := Bucket, file := File, integrity := [_ | _] = Problems}) ->
+        logger:error("Database ~s/~s failed the integrity check: ~p~n", [Bucket, File, Problems]);
+
+      (#{bucket := Bucket, file := File, hot_journal := true}) ->
+        logger:warning("Database ~s/~s was recovered from a hot journal~n", [Bucket, File]);
+
+      (#{bucket := Bucket, file := File, wal := true}) ->
+        logger:info("Database ~s/~s was recovered from its wal~n", [Bucket, File]);
+
+      (_) -> ok
+    end,
+    Files
+  ).
+
+
+% the ctx resource does not survive a restart or a copy to another node, when the
+% machine state comes from a snapshot the databases are restored from its archive
+% into the context opened by init. A query served by a new leader can come before
+% the first entry applied after the snapshot, the state it sees is not kept so the
+% restore is done once and the entry finds the databases restored.
+ensure_context(#app_state{snapshot = undefined} = State) -> State;
+
+ensure_context(#app_state{index = Index, snapshot = Snapshot} = State) ->
+  Ctx = persistent_term:get(?CONTEXT),
+  case persistent_term:get(?RESTORED, undefined) of
+    Index -> ok;
+    _ ->
+      % the home can be older than the snapshot, e.g. on a follower that was
+      % sent one, the entries after it are applied again on top of the archive
+      HomeDir = home_dir(),
+      logger:info("Restoring context from the snapshot at ~p~n", [Index]),
+      Archive = fetch_archive(Index, Snapshot),
+      _ = file:del_dir_r(HomeDir),
+      {ok, _} = my_nif:restore_home(Ctx, Archive),
+      persistent_term:put(?RESTORED, Index)
+  end,
+  % connections and statements are not part of the snapshot
+  State#app_state{ctx = Ctx, conns = #{}, stmts = #{}, read_only_stmts = #{}, used = #{}, snapshot = undefined}.
+
+
 -spec single_node_call(Leader :: ra:server_id(), TargetFunc :: api_fun(), Args :: api_args()) ->
   {ok, {api_ret(), ra:index(), ra:term()}, ra:server_id()} | {error, term()} | {timeout, term()}.
 single_node_call(Leader, TargetFunc, Args) ->
@@ -48,7 +108,8 @@ single_node_call(Leader, TargetFunc, Args) ->
   ra:consistent_query(
     Leader,
     fun
-      (#app_state{index = Index, term = Term} = State) -> {TargetFunc(State, Args), Index, Term}
+      (#app_state{index = Index, term = Term} = State) ->
+        {TargetFunc(ensure_context(State), Args), Index, Term}
     end
   ) of
     {ok, {{_State, Result}, Index, Term}, Leader1} -> {ok, {Result, Index, Term}, Leader1};
@@ -56,30 +117,232 @@ single_node_call(Leader, TargetFunc, Args) ->
   end.
 
 
+% read the state of the local replica, it can be stale
+-spec local_call(Server :: ra:server_id(), Fun :: fun((app_state()) -> term())) ->
+  {ok, term()} | {error, term()} | {timeout, term()}.
+local_call(Server, Fun) ->
+  case ra:local_query(Server, Fun) of
+    {ok, {_IndexTerm, Result}, _Leader} -> {ok, Result};
+    Err -> Err
+  end.
+
+
+% compare the last digest of every member with the one of the leader, see the state_digest api
+-spec check_divergence(Leader :: ra:server_id()) ->
+  {ok, [{ra:server_id(), ok | {diverged, term()} | {not_comparable, term()}}]} | {error, term()}.
+check_divergence(Leader) ->
+  case ra:members(Leader) of
+    {ok, Members, Leader1} ->
+      GetDigest = fun (#app_state{digest = Digest}) -> Digest end,
+      {ok, Reference} = local_call(Leader1, GetDigest),
+      {
+        ok,
+        [
+          {Member, compare_digests(Reference, local_call(Member, GetDigest))}
+          || Member <- Members, Member =/= Leader1
+        ]
+      };
+
+    Err -> {error, Err}
+  end.
+
+
+compare_digests({Index, Reference}, {ok, {Index, Digest}}) ->
+  case first_difference(Reference, Digest) of
+    none -> ok;
+    Difference -> {diverged, Difference}
+  end;
+
+compare_digests({Index, _}, {ok, {OtherIndex, _}}) -> {not_comparable, {Index, OtherIndex}};
+compare_digests(Reference, Other) -> {not_comparable, {Reference, Other}}.
+
+
+% name the first file, and table when the schemas are equal, that differs between two digests.
+% files and tables are sorted by name in both digests.
+-spec first_difference(map(), map()) ->
+  none | {Bucket :: binary(), File :: binary(), Table :: binary() | undefined}.
+first_difference(#{digest := Same}, #{digest := Same}) -> none;
+first_difference(#{files := FilesA}, #{files := FilesB}) -> first_file_difference(FilesA, FilesB).
+
+
+first_file_difference(
+  [#{bucket := Bucket, file := File, digest := Same} | RestA],
+  [#{bucket := Bucket, file := File, digest := Same} | RestB]
+) ->
+  first_file_difference(RestA, RestB);
+
+first_file_difference(
+  [#{bucket := Bucket, file := File} = FileA | _],
+  [#{bucket := Bucket, file := File} = FileB | _]
+) ->
+  {Bucket, File, first_table_difference(FileA, FileB)};
+
+first_file_difference([#{bucket := BucketA, file := FileA} | _], [#{bucket := BucketB, file := FileB} | _]) ->
+  {Bucket, File} = min({BucketA, FileA}, {BucketB, FileB}),
+  {Bucket, File, undefined};
+
+first_file_difference([#{bucket := Bucket, file := File} | _], []) -> {Bucket, File, undefined};
+first_file_difference([], [#{bucket := Bucket, file := File} | _]) -> {Bucket, File, undefined};
+first_file_difference([], []) -> none.
+
+
+first_table_difference(#{schema := Same, tables := TablesA}, #{schema := Same, tables := TablesB}) ->
+  case [Name || {#{name := Name} = A, B} <- lists:zip(TablesA, TablesB), A =/= B] of
+    [Name | _] -> Name;
+    [] -> undefined
+  end;
+
+first_table_difference(_, _) -> undefined.
+
+
 -spec cluster_call(Leader :: ra:server_id(), TargetFunc :: api_fun(), Args :: api_args()) ->
   {ok, {api_ret(), ra:index(), ra:term()}, ra:server_id()} | {error, term()} | {timeout, term()}.
-cluster_call(Leader, TargetFunc, Args) -> ra:process_command(Leader, {api_call, TargetFunc, Args}).
+cluster_call(Leader, TargetFunc, Args) ->
+  % the timestamp travels in the log so that every replica sees the same "now"
+  Timestamp = erlang:system_time(millisecond),
+  ra:process_command(Leader, {api_call, TargetFunc, Args, Timestamp}).
 
 % called by raft on every node when a new log entry is committed
-apply(#{index := Index, term := Term} = _Metadata, {api_call, TargetFunc, Args} = _Command, State) ->
-  State1 = State#app_state{index = Index, term = Term},
+apply(Metadata, {api_call, TargetFunc, Args}, State) ->
+  apply(Metadata, {api_call, TargetFunc, Args, 0}, State);
+
+apply(
+  #{index := Index, term := Term} = _Metadata,
+  {api_call, TargetFunc, Args, Timestamp} = _Command,
+  State
+) ->
+  State1 = (ensure_context(State))#app_state{index = Index, term = Term, timestamp = Timestamp},
   {State2, Result} = TargetFunc(State1, Args),
-  % never do side effects for now
-  SideEffects = side_effects(Index, State2),
+  {State3, SideEffects} = side_effects(Index, touch(Index, Args, State2)),
   % return also Index, Term for debugging purposes
-  {State2, {Result, Index, Term}, SideEffects}.
+  {State3, {Result, Index, Term}, SideEffects}.
+
+
+% the entry used the connection and the statement of its args, or created them,
+% at the timestamp of the log entry, the ones it closed are forgotten
+touch(Index, Args, #app_state{timestamp = Timestamp, conns = Conns, stmts = Stmts, used = Used} = State) ->
+  ConnId = maps:get(<<"Conn">>, Args, Index),
+  StmtId = maps:get(<<"Stmt">>, Args, Index),
+  Used1 = touch({conn, ConnId}, maps:is_key(ConnId, Conns), Timestamp, Used),
+  Used2 = touch({stmt, ConnId, StmtId}, maps:is_key(StmtId, Stmts), Timestamp, Used1),
+  State#app_state{used = Used2}.
+
+touch(Key, true, Timestamp, Used) -> Used#{Key => Timestamp};
+touch(Key, false, _, Used) -> maps:remove(Key, Used).
+
+
+% statements that clients never finalize hold read transactions and block
+% checkpoints. The context does not reap them on its own, every replica would
+% do it at a different point, the leader appends an entry that closes the ones
+% idle for longer than the timeouts it sends along, 0 disables them. Only the
+% calls that go through the log count as activity. Called on the leader.
+tick(Now, #app_state{used = Used}) ->
+  Args =
+    #{
+      <<"Statement">> => idle_timeout(idle_statement_timeout),
+      <<"Connection">> => idle_timeout(idle_connection_timeout)
+    },
+  case [Key || {Key, Last} <- maps:to_list(Used), erlang_project_api:idle(Key, Last, Now, Args)] of
+    [] -> [];
+    _ -> [{append, {api_call, fun erlang_project_api:reap_idle/2, Args, Now}}]
+  end.
+
+
+idle_timeout(Key) ->
+  case application:get_env(erlang_project, Key) of
+    {ok, Millis} when is_integer(Millis), Millis > 0 -> Millis;
+    _ -> 0
+  end.
 
 
 % We take a snapshot every `release_cursor_every` log entries.
 % (release cursor side effect means taking a snapshot)
+% The archive of the databases stays on disk, the state given to ra only names
+% the node and the directory it is in. The state kept applying entries does not
+% hold it.
 side_effects(RaftIndex, MachineState) ->
   case application:get_env(erlang_project, release_cursor_every) of
-    undefined -> [];
-    {ok, NegativeOrZero} when NegativeOrZero =< 0 -> [];
+    undefined -> {MachineState, []};
+    {ok, NegativeOrZero} when NegativeOrZero =< 0 -> {MachineState, []};
 
     {ok, Every} ->
       case RaftIndex rem Every of
-        0 -> [{release_cursor, RaftIndex, MachineState}];
-        _ -> []
+        0 -> {MachineState, [{release_cursor, RaftIndex, snapshot(RaftIndex, MachineState)}]};
+
+        _ -> {MachineState, []}
       end
   end.
+
+
+snapshot(RaftIndex, #app_state{ctx = Ctx} = MachineState) ->
+  Archive = archive_dir(RaftIndex),
+  % a replayed index overwrites the previous archive
+  _ = file:del_dir_r(Archive),
+  {ok, _Files} = my_nif:snapshot_context(Ctx, Archive),
+  prune_archives(),
+  MachineState#app_state{snapshot = {node(), Archive}}.
+
+
+% every replica takes its own archive at the same index, one that was sent the
+% snapshot of another node copies the archive of that node
+fetch_archive(Index, {Node, Source}) ->
+  Archive = archive_dir(Index),
+  case filelib:is_dir(Archive) of
+    true -> ok;
+    false -> write_archive(Archive, erpc:call(Node, ?MODULE, read_archive, [Source]))
+  end,
+  Archive.
+
+
+% ra keeps the latest snapshot, the archive before it stays while a replica may
+% still be copying it
+prune_archives() ->
+  Dir = snapshots_dir(),
+  Names =
+    case file:list_dir(Dir) of
+      {ok, Found} -> Found;
+      {error, _} -> []
+    end,
+  Indexes = lists:reverse(lists:sort([Index || Name <- Names, {Index, []} <- [string:to_integer(Name)]])),
+  lists:foreach(
+    fun (Index) -> file:del_dir_r(archive_dir(Index)) end,
+    lists:nthtail(min(2, length(Indexes)), Indexes)
+  ).
+
+
+snapshots_dir() ->
+  {ok, DataDir} = application:get_env(erlang_project, sql_data_dir),
+  filename:join([DataDir, "snapshots"]).
+
+
+archive_dir(RaftIndex) -> list_to_binary(filename:join([snapshots_dir(), integer_to_list(RaftIndex)])).
+
+
+% [{path relative to the archive, contents}]
+read_archive(Archive) ->
+  Prefix = byte_size(Archive) + 1,
+  filelib:fold_files(
+    binary_to_list(Archive),
+    "",
+    true,
+    fun
+      (Path, Acc) ->
+        {ok, Bin} = file:read_file(Path),
+        <<_:Prefix/binary, Relative/binary>> = list_to_binary(Path),
+        [{Relative, Bin} | Acc]
+    end,
+    []
+  ).
+
+
+write_archive(Archive, Contents) ->
+  _ = file:del_dir_r(Archive),
+  lists:foreach(
+    fun
+      ({Relative, Bin}) ->
+        Path = filename:join(Archive, Relative),
+        ok = filelib:ensure_dir(Path),
+        ok = file:write_file(Path, Bin)
+    end,
+    Contents
+  ).
@@ -0,0 +1,37 @@
+package com.erldb
+
+import org.json.JSONObject
+
+/**
+ * What the server knows about a column of a statement's result.
+ * The origin ([database], [table], [column]) and the flags are null when the column is an expression
+ * or when the server is built without the column_metadata feature.
+ * @property declType the type the column was declared with in its table.
+ */
+data class ColumnMetadata(
+    val name: String,
+    val declType: String?,
+    val database: String?,
+    val table: String?,
+    val column: String?,
+    val notNull: Boolean?,
+    val primaryKey: Boolean?,
+    val autoincrement: Boolean?
+) {
+    companion object {
+        fun fromJSON(json: JSONObject): ColumnMetadata = ColumnMetadata(
+            name = json.getString("name"),
+            declType = json.stringOrNull("decl_type"),
+            database = json.stringOrNull("database"),
+            table = json.stringOrNull("table"),
+            column = json.stringOrNull("column"),
+            notNull = json.booleanOrNull("not_null"),
+            primaryKey = json.booleanOrNull("primary_key"),
+            autoincrement = json.booleanOrNull("autoincrement")
+        )
+
+        private fun JSONObject.stringOrNull(key: String): String? = if (has(key)) getString(key) else null
+
+        private fun JSONObject.booleanOrNull(key: String): Boolean? = if (has(key)) getBoolean(key) else null
+    }
+}
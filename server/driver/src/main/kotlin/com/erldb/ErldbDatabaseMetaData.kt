@@ -442,7 +442,7 @@ class ErldbDatabaseMetaData : DatabaseMetaData {
     }
 
     override fun getTables(catalog: String?, p1: String?, p2: String?, p3: Array<out String>?): ResultSet? {
-        val sql = "SELECT name FROM sqlite_master WHERE type='table' ORDER BY name;"
+        val sql = "SELECT name FROM sqlite_master WHERE type='table' AND name <> '_erldb_meta' ORDER BY name;"
         val stmt: Statement? = connection.createStatement()
         return stmt?.executeQuery(sql)
     }
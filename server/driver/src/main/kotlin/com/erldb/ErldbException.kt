@@ -1,6 +1,76 @@
 package com.erldb
 
-class ErldbException : Exception {
-    constructor(message: String) : super(message)
-    constructor(message: String, cause: Throwable) : super(message, cause)
-}
\ No newline at end of file
+import org.json.JSONObject
+import java.sql.SQLException
+
+/**
+ * An error returned by the cluster.
+ *
+ * @property kind The kind of the error, the name of the primary SQLite result code (busy, constraint, ...)
+ * or one of parameter, column, conversion, misuse, internal, io, interrupted, budget_exceeded, timeout, expired, lease_expired, worker_crashed.
+ * @property code The primary SQLite result code, if the error comes from SQLite.
+ * @property extendedCode The extended SQLite result code, also the vendor code of the exception.
+ * @property offset The byte offset in the query of the token the error is about.
+ * @property parameter The index of the parameter that could not be bound.
+ * @property statement The index of the statement of a script that failed.
+ * @property statementOffset The byte offset in the script of the statement that failed.
+ * @property row The index of the parameter set of a batch that failed.
+ */
+class ErldbException(
+    message: String,
+    val kind: String? = null,
+    val code: Int? = null,
+    val extendedCode: Int? = null,
+    val offset: Int? = null,
+    val parameter: Int? = null,
+    val statement: Int? = null,
+    val statementOffset: Int? = null,
+    val row: Int? = null,
+    cause: Throwable? = null
+) : SQLException(message, sqlState(kind), extendedCode ?: 0, cause) {
+    constructor(message: String, cause: Throwable) : this(message, cause = cause as Throwable?)
+
+    companion object {
+        /**
+         * Maps the kind of an error to a SQLState.
+         */
+        fun sqlState(kind: String?): String = when (kind) {
+            "constraint" -> "23000"
+            "busy", "locked" -> "40001"
+            "abort", "lease_expired" -> "40000"
+            "error", "schema" -> "42000"
+            "perm", "auth" -> "28000"
+            "readonly" -> "25006"
+            "range", "parameter" -> "07009"
+            "mismatch", "conversion", "column" -> "22000"
+            "toobig" -> "22001"
+            "misuse" -> "HY010"
+            "interrupted", "budget_exceeded", "timeout" -> "57014"
+            "expired" -> "08003"
+            "worker_crashed" -> "58000"
+            else -> "HY000"
+        }
+
+        /**
+         * Creates an exception from the error of a response, either a map with kind and message or a string.
+         */
+        fun fromJson(error: Any?): ErldbException = when (error) {
+            is JSONObject -> ErldbException(
+                error.optString("message", "unknown error"),
+                kind = error.optString("kind").ifEmpty { null },
+                code = error.intOrNull("code"),
+                extendedCode = error.intOrNull("extended_code"),
+                offset = error.intOrNull("offset"),
+                parameter = error.intOrNull("parameter"),
+                statement = error.intOrNull("statement"),
+                statementOffset = error.intOrNull("statement_offset"),
+                row = error.intOrNull("row")
+            )
+
+            is String -> ErldbException(error)
+            else -> ErldbException("unexpected error")
+        }
+
+        private fun JSONObject.intOrNull(key: String): Int? = if (has(key)) getInt(key) else null
+    }
+}
@@ -36,14 +36,13 @@ class ErldbPreparedStatement : AbstractErldbPreparedStatement {
     }
 
     override fun executeQuery(): ResultSet? = withGuard().execute {
+        val params = boundParams()
         try {
             rs?.close()
             statementAccess.reset()
             exhaustedResults = false
 
-            getParams().iterator().withIndex().forEach { (i, v) ->
-                statementAccess.bind(OneColumnIndex(i + 1), v)
-            }
+            bindParams(params)
 
             val stepResult = step()
             if (stepResult == null) {
@@ -79,18 +78,30 @@ class ErldbPreparedStatement : AbstractErldbPreparedStatement {
         }
     }
 
+    // every parameter must have been set
+    private fun boundParams(): Array<SQLiteValue> = Array(paramCount) {
+        parameters[it + 1] ?: throw SQLException("Parameter ${it + 1} was not set")
+    }
+
+    // one request for all the parameters
+    private fun bindParams(params: Array<SQLiteValue> = boundParams()) {
+        if (paramCount == 0) {
+            return
+        }
+        statementAccess.bindAll(params).getOrThrow()
+    }
+
     override fun executeLargeUpdate(): Long = withGuard().execute {
         if (columnNames.isNotEmpty()) {
             throw SQLException("Query returns results")
         }
+        val params = boundParams()
         try {
             rs?.close()
             statementAccess.reset().getOrThrow()
             exhaustedResults = false
 
-            getParams().forEachIndexed { i, v ->
-                statementAccess.bind(OneColumnIndex(i + 1), v)
-            }
+            bindParams(params)
             val db = statementAccess.db()
             synchronized(db) {
                 val stepResult = step()
@@ -110,9 +121,7 @@ class ErldbPreparedStatement : AbstractErldbPreparedStatement {
         rs?.close()
         statementAccess.reset()
 
-        getParams().forEachIndexed { i, v ->
-            statementAccess.bind(OneColumnIndex(i + 1), v)
-        }
+        bindParams()
 
         val stepResult = step()
         if (stepResult == null) {
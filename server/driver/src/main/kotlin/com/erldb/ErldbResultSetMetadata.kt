@@ -1,6 +1,7 @@
 package com.erldb
 
 import java.sql.ResultSetMetaData
+import java.sql.SQLException
 
 class ErldbResultSetMetaData : ResultSetMetaData {
 
@@ -9,6 +10,14 @@ class ErldbResultSetMetaData : ResultSetMetaData {
         this.resultSet = resultSet
     }
 
+    private val columns: Array<ColumnMetadata> by lazy {
+        resultSet.stmt.statementAccess.columnMetadata().getOrThrow()
+    }
+
+    private fun column(oneColumnIndex: Int): ColumnMetadata =
+        columns.getOrNull(oneColumnIndex - 1)
+            ?: throw SQLException("column $oneColumnIndex out of bounds [1,${columns.size}]")
+
     override fun <T : Any?> unwrap(p0: Class<T>?): T {
         TODO("Not yet implemented")
     }
@@ -21,9 +30,8 @@ class ErldbResultSetMetaData : ResultSetMetaData {
         resultSet.cols.size
 
 
-    override fun isAutoIncrement(p0: Int): Boolean {
-        TODO("Not yet implemented")
-    }
+    override fun isAutoIncrement(oneColumnIndex: Int): Boolean =
+        column(oneColumnIndex).autoincrement ?: false
 
     override fun isCaseSensitive(p0: Int): Boolean {
         TODO("Not yet implemented")
@@ -37,9 +45,12 @@ class ErldbResultSetMetaData : ResultSetMetaData {
         TODO("Not yet implemented")
     }
 
-    override fun isNullable(p0: Int): Int {
-        TODO("Not yet implemented")
-    }
+    override fun isNullable(oneColumnIndex: Int): Int =
+        when (column(oneColumnIndex).notNull) {
+            true -> ResultSetMetaData.columnNoNulls
+            false -> ResultSetMetaData.columnNullable
+            null -> ResultSetMetaData.columnNullableUnknown
+        }
 
     override fun isSigned(p0: Int): Boolean {
         TODO("Not yet implemented")
@@ -49,17 +60,13 @@ class ErldbResultSetMetaData : ResultSetMetaData {
         TODO("Not yet implemented")
     }
 
-    override fun getColumnLabel(p0: Int): String {
-        TODO("Not yet implemented")
-    }
+    override fun getColumnLabel(oneColumnIndex: Int): String = getColumnName(oneColumnIndex)
 
     override fun getColumnName(oneColumnIndex: Int): String {
         return resultSet.cols[oneColumnIndex - 1]
     }
 
-    override fun getSchemaName(p0: Int): String {
-        TODO("Not yet implemented")
-    }
+    override fun getSchemaName(oneColumnIndex: Int): String = column(oneColumnIndex).database ?: ""
 
     override fun getPrecision(p0: Int): Int {
         TODO("Not yet implemented")
@@ -69,9 +76,7 @@ class ErldbResultSetMetaData : ResultSetMetaData {
         TODO("Not yet implemented")
     }
 
-    override fun getTableName(p0: Int): String {
-        TODO("Not yet implemented")
-    }
+    override fun getTableName(oneColumnIndex: Int): String = column(oneColumnIndex).table ?: ""
 
     override fun getCatalogName(p0: Int): String {
         TODO("Not yet implemented")
@@ -81,9 +86,7 @@ class ErldbResultSetMetaData : ResultSetMetaData {
         TODO("Not yet implemented")
     }
 
-    override fun getColumnTypeName(p0: Int): String {
-        TODO("Not yet implemented")
-    }
+    override fun getColumnTypeName(oneColumnIndex: Int): String = column(oneColumnIndex).declType ?: ""
 
     override fun isReadOnly(p0: Int): Boolean {
         TODO("Not yet implemented")
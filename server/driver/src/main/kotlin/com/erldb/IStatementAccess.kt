@@ -81,11 +81,22 @@ interface IStatementAccess {
          */
         fun bind(pos: OneColumnIndex, v: Any?): Result<Unit> = bind(pos, SQLiteValue.from(v))
 
+        /**
+         * Binds all the parameters of the statement in one call, either all of them are bound or none.
+         * @param values one value per parameter, in order.
+         */
+        fun bindAll(values: Array<SQLiteValue>): Result<Unit>
+
         /**
          * Returns the names of the columns in the statement.
          */
         fun columnNames(): Result<Array<String>>
 
+        /**
+         * Returns the declared type, origin and constraints of the columns in the statement.
+         */
+        fun columnMetadata(): Result<Array<ColumnMetadata>>
+
         /**
          * Returns the database of the statement.
          */
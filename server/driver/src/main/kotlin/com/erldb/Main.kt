@@ -136,7 +136,7 @@ fun getLibVersion(): String {
 
 fun getTables(): List<String> {
     connect().let { conn ->
-        val sql = "SELECT name FROM sqlite_master WHERE type='table' ORDER BY name;"
+        val sql = "SELECT name FROM sqlite_master WHERE type='table' AND name <> '_erldb_meta' ORDER BY name;"
         val stmt: Statement? = conn.createStatement()
         val rs: ResultSet? = stmt?.executeQuery(sql)
         val result = mutableListOf<String>()
@@ -249,7 +249,7 @@ fun pippo() {
 // list tables
 fun listTables() {
     val connection = ErldbConnection("jdbc:erldb://localhost:8080/public/sample.db", null)
-    val sql = "SELECT name FROM sqlite_master WHERE type='table' ORDER BY name;"
+    val sql = "SELECT name FROM sqlite_master WHERE type='table' AND name <> '_erldb_meta' ORDER BY name;"
     connection.use { conn ->
         val stmt: Statement? = conn.createStatement()
         val rs: ResultSet? = stmt?.executeQuery(sql)
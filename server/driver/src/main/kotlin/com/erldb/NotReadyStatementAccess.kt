@@ -41,10 +41,18 @@ class NotReadyStatementAccess : IStatementAccess {
         throw notReady()
     }
 
+    override fun bindAll(values: Array<SQLiteValue>): Result<Unit> {
+        throw notReady()
+    }
+
     override fun columnNames(): Result<Array<String>> {
         throw notReady()
     }
 
+    override fun columnMetadata(): Result<Array<ColumnMetadata>> {
+        throw notReady()
+    }
+
     override fun db(): IDatabaseAccess {
         throw notReady()
     }
@@ -46,6 +46,17 @@ class StatementAccess(
         doRequest("bind", params)//.getString("ok") == "success"
     }
 
+    /**
+     * Binds the given [values] to all the parameters of the statement at once.
+     * @param values One value per parameter, in order.
+     */
+    override fun bindAll(values: Array<SQLiteValue>): Result<Unit> = checkOpen().let {
+        val params = mapOf(
+            "Conn" to connectionId, "Stmt" to statementId, "Params" to JSONArray(values.map { it.toJSON() })
+        )
+        doRequest("bind_all", params)
+    }
+
     /**
      * Returns an array of column names for the current result set.
      * @return Array of column names.
@@ -58,6 +69,18 @@ class StatementAccess(
         ).map { it.iterator().asSequence().map { it.toString() }.toList().toTypedArray() }
     }
 
+    /**
+     * Returns the metadata of the columns of the current result set, one entry per column.
+     * @return Array of column metadata.
+     */
+    override fun columnMetadata(): Result<Array<ColumnMetadata>> = checkOpen().let {
+        doRequest<JSONArray>(
+            "column_metadata", mapOf(
+                "Conn" to connectionId, "Stmt" to statementId
+            )
+        ).map { json -> Array(json.length()) { ColumnMetadata.fromJSON(json.getJSONObject(it)) } }
+    }
+
     /**
      * Executes the statement `n` times and returns the resulting rows as a list of arrays of [SQLiteValue] objects.
      *
@@ -88,12 +88,7 @@ open class HttpAccess(val nodes: List<Pair<String, Int>>) {
             }
             return Result.success(rv)
         } catch (e: Exception) {
-            try {
-                val error = ErldbException(postResult.getString("error"))
-                return Result.failure(error)
-            } catch (e: Exception) {
-                return Result.failure(ErldbException("unexpected error"))
-            }
+            return Result.failure(ErldbException.fromJson(postResult.opt("error")))
         }
     }
 